@@ -0,0 +1,522 @@
+// `#[derive(ProtoMessage)]` and `#[derive(ProtoOneof)]` for the
+// protobuf_parsing crate.
+//
+// Every field of the struct needs a `#[proto(field = N)]` attribute giving its
+// field number. The generated `add_field` and `ProtoEncode` impls dispatch on
+// the field's type through `protobuf_parsing::ProtoField`, so scalars, `&str`,
+// `&[u8]`, nested messages and `Vec`s of any of these all work the same way.
+// Integer fields can add `fixed` or `zigzag` to pick the `fixed32`/`sfixed64`
+// or `sint32`/`sint64` encodings instead of plain VARINTs. `HashMap` and
+// `BTreeMap` fields add `map`, or `map(key = zigzag, value = fixed)` to pick
+// the encodings of their keys and values.
+//
+// An `Option` of an enum deriving `ProtoOneof` is marked `#[proto(oneof)]`
+// and takes no field number: each of the enum's variants has its own, with
+// the same attributes as a struct field. The macro cannot see those numbers,
+// so a clash with the struct's fields or another oneof fails to compile
+// through a const assertion instead.
+//
+// Fields with numbers the struct does not declare are skipped, unless one
+// `Vec<Field<'a>>` is marked `#[proto(unknown_fields)]`: they are then kept
+// there and written back out after the known fields.
+//
+// Both derives also implement `IntoOwned`, converting field by field, unless
+// something borrows in a way that cannot be made owned: a `&'a str` or
+// `&'a [u8]`, or unknown fields. Borrowed types should use `Cow` instead.
+// Types without a lifetime are already owned and are returned as they are.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+	Attribute, Data, DeriveInput, Fields, GenericArgument, GenericParam, Generics, Ident, Lifetime,
+	LifetimeParam, LitInt, PathArguments, Type, parse_macro_input, spanned::Spanned,
+};
+
+#[proc_macro_derive(ProtoMessage, attributes(proto))]
+pub fn derive_proto_message(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	expand(&input)
+		.unwrap_or_else(syn::Error::into_compile_error)
+		.into()
+}
+
+#[proc_macro_derive(ProtoOneof, attributes(proto))]
+pub fn derive_proto_oneof(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	expand_oneof(&input)
+		.unwrap_or_else(syn::Error::into_compile_error)
+		.into()
+}
+
+/// A struct field, the field number it is stored under and the
+/// `protobuf_parsing` encoding marker to store it with.
+struct MessageField<'a> {
+	ident: &'a Ident,
+	field_num: u64,
+	encoding: proc_macro2::TokenStream,
+}
+
+/// A `#[proto(oneof)]` struct field and the enum in its `Option`.
+struct OneofField<'a> {
+	ident: &'a Ident,
+	ty: &'a Type,
+}
+
+/// `ProtoMessage<'a>` and `ProtoOneof<'a>` borrow from the input, so reuse
+/// the type's own lifetime if it has one, or introduce one just for the impl.
+fn proto_lifetime(generics: &Generics) -> (Generics, Lifetime) {
+	let mut generics = generics.clone();
+	let lifetime = match generics.lifetimes().next() {
+		Some(param) => param.lifetime.clone(),
+		None => {
+			let lifetime = Lifetime::new("'proto", Span::call_site());
+			generics.params.insert(
+				0,
+				GenericParam::Lifetime(LifetimeParam::new(lifetime.clone())),
+			);
+			lifetime
+		}
+	};
+	(generics, lifetime)
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+	let Data::Struct(data) = &input.data else {
+		return Err(syn::Error::new(
+			input.span(),
+			"ProtoMessage can only be derived for structs",
+		));
+	};
+	let Fields::Named(named) = &data.fields else {
+		return Err(syn::Error::new(
+			data.fields.span(),
+			"ProtoMessage requires named fields",
+		));
+	};
+
+	let mut fields: Vec<MessageField> = Vec::new();
+	let mut oneofs: Vec<OneofField> = Vec::new();
+	let mut unknown_fields: Option<&Ident> = None;
+	for field in &named.named {
+		let ident = field.ident.as_ref().unwrap();
+		let (field_num, encoding) = match proto_attr(&field.attrs, field.span())? {
+			ProtoAttr::Field(field_num, encoding) => (field_num, encoding),
+			ProtoAttr::UnknownFields => {
+				if unknown_fields.replace(ident).is_some() {
+					return Err(syn::Error::new(
+						field.span(),
+						"only one field can hold unknown fields",
+					));
+				}
+				continue;
+			}
+			ProtoAttr::Oneof => {
+				oneofs.push(OneofField {
+					ident,
+					ty: option_type(&field.ty)?,
+				});
+				continue;
+			}
+		};
+		if let Some(other) = fields.iter().find(|f| f.field_num == field_num) {
+			return Err(syn::Error::new(
+				field.span(),
+				format!(
+					"field number {field_num} is already used by `{}`",
+					other.ident
+				),
+			));
+		}
+		fields.push(MessageField {
+			ident,
+			field_num,
+			encoding,
+		});
+	}
+
+	let (generics, lifetime) = proto_lifetime(&input.generics);
+	let (impl_generics, _, _) = generics.split_for_impl();
+	let (encode_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+	let name = &input.ident;
+
+	let idents: Vec<_> = fields.iter().map(|f| f.ident).collect();
+	let nums: Vec<_> = fields.iter().map(|f| f.field_num).collect();
+	let encodings: Vec<_> = fields.iter().map(|f| &f.encoding).collect();
+	let oneof_idents: Vec<_> = oneofs.iter().map(|o| o.ident).collect();
+	let oneof_types: Vec<_> = oneofs.iter().map(|o| o.ty).collect();
+	let unknown_arm = match unknown_fields {
+		Some(ident) => quote! {
+			_ => {
+				self.#ident.push(field);
+				::core::result::Result::Ok(())
+			}
+		},
+		None => quote! { _ => ::core::result::Result::Ok(()), },
+	};
+	let into_owned = match unknown_fields {
+		Some(_) => None,
+		None => into_owned_impl(input, |owned| {
+			let idents = named.named.iter().map(|field| &field.ident);
+			quote! {
+				#owned { #(#idents: ::protobuf_parsing::IntoOwned::into_owned(self.#idents),)* }
+			}
+		}),
+	};
+	let unknown_idents: Vec<_> = unknown_fields.into_iter().collect();
+	let distinct_check = (!oneofs.is_empty()).then(|| {
+		let message = format!(
+			"field numbers in `{}` and its oneofs must be distinct",
+			input.ident
+		);
+		quote! {
+			const {
+				::core::assert!(
+					::protobuf_parsing::distinct_fields(
+						&[#(#nums),*],
+						&[#(<#oneof_types as ::protobuf_parsing::ProtoOneof<#lifetime>>::FIELDS),*],
+					),
+					#message
+				)
+			};
+		}
+	});
+
+	Ok(quote! {
+		impl #impl_generics ::protobuf_parsing::ProtoMessage<#lifetime> for #name #ty_generics #where_clause {
+			fn add_field(
+				&mut self,
+				field: ::protobuf_parsing::Field<#lifetime>,
+			) -> ::core::result::Result<(), ::protobuf_parsing::DecodeError> {
+				#distinct_check
+				match field.field_num {
+					#(#nums => ::protobuf_parsing::ProtoField::<#lifetime, #encodings>::merge_value(&mut self.#idents, field.value),)*
+					#(
+						num if <#oneof_types as ::protobuf_parsing::ProtoOneof<#lifetime>>::FIELDS.contains(&num) => {
+							<#oneof_types as ::protobuf_parsing::ProtoOneof<#lifetime>>::merge_field(&mut self.#oneof_idents, field)
+						}
+					)*
+					#unknown_arm
+				}
+			}
+		}
+
+		impl #encode_generics ::protobuf_parsing::ProtoEncode for #name #ty_generics #where_clause {
+			fn encode_to(&self, buf: &mut ::std::vec::Vec<u8>) {
+				#(
+					::protobuf_parsing::ProtoField::<'_, #encodings>::encode_singular(&self.#idents, #nums, buf);
+				)*
+				#(
+					if let ::core::option::Option::Some(oneof) = &self.#oneof_idents {
+						::protobuf_parsing::ProtoOneof::encode_to(oneof, buf);
+					}
+				)*
+				#(
+					for field in &self.#unknown_idents {
+						field.encode_to(buf);
+					}
+				)*
+			}
+
+			fn encoded_len(&self) -> usize {
+				let mut len = 0;
+				#(
+					len += ::protobuf_parsing::ProtoField::<'_, #encodings>::encoded_singular_len(&self.#idents, #nums);
+				)*
+				#(
+					if let ::core::option::Option::Some(oneof) = &self.#oneof_idents {
+						len += ::protobuf_parsing::ProtoOneof::encoded_len(oneof);
+					}
+				)*
+				#(
+					len += self.#unknown_idents.iter().map(|field| field.encoded_len()).sum::<usize>();
+				)*
+				len
+			}
+		}
+
+		#into_owned
+	})
+}
+
+fn expand_oneof(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+	let Data::Enum(data) = &input.data else {
+		return Err(syn::Error::new(
+			input.span(),
+			"ProtoOneof can only be derived for enums",
+		));
+	};
+
+	let mut variants = Vec::new();
+	let mut nums: Vec<u64> = Vec::new();
+	let mut encodings = Vec::new();
+	for variant in &data.variants {
+		let Fields::Unnamed(fields) = &variant.fields else {
+			return Err(syn::Error::new(
+				variant.span(),
+				"oneof members must hold exactly one value",
+			));
+		};
+		if fields.unnamed.len() != 1 {
+			return Err(syn::Error::new(
+				variant.span(),
+				"oneof members must hold exactly one value",
+			));
+		}
+		let ProtoAttr::Field(field_num, encoding) = proto_attr(&variant.attrs, variant.span())?
+		else {
+			return Err(syn::Error::new(
+				variant.span(),
+				"oneof members need a #[proto(field = N)] attribute",
+			));
+		};
+		if nums.contains(&field_num) {
+			return Err(syn::Error::new(
+				variant.span(),
+				format!("field number {field_num} is already used"),
+			));
+		}
+		variants.push(&variant.ident);
+		nums.push(field_num);
+		encodings.push(encoding);
+	}
+
+	let (generics, lifetime) = proto_lifetime(&input.generics);
+	let (impl_generics, _, _) = generics.split_for_impl();
+	let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+	let name = &input.ident;
+	let into_owned = into_owned_impl(input, |owned| {
+		quote! {
+			match self {
+				#(Self::#variants(value) => #owned::#variants(::protobuf_parsing::IntoOwned::into_owned(value)),)*
+			}
+		}
+	});
+
+	Ok(quote! {
+		impl #impl_generics ::protobuf_parsing::ProtoOneof<#lifetime> for #name #ty_generics #where_clause {
+			const FIELDS: &'static [u64] = &[#(#nums),*];
+
+			fn merge_field(
+				oneof: &mut ::core::option::Option<Self>,
+				field: ::protobuf_parsing::Field<#lifetime>,
+			) -> ::core::result::Result<(), ::protobuf_parsing::DecodeError> {
+				match field.field_num {
+					#(
+						#nums => {
+							// A different member replaces the current one.
+							if !::core::matches!(oneof, ::core::option::Option::Some(Self::#variants(_))) {
+								*oneof = ::core::option::Option::Some(Self::#variants(::core::default::Default::default()));
+							}
+							let ::core::option::Option::Some(Self::#variants(value)) = oneof else {
+								::core::unreachable!()
+							};
+							::protobuf_parsing::ProtoField::<#lifetime, #encodings>::merge_value(value, field.value)
+						}
+					)*
+					_ => ::core::result::Result::Ok(()),
+				}
+			}
+
+			fn encode_to(&self, buf: &mut ::std::vec::Vec<u8>) {
+				match self {
+					#(Self::#variants(value) => ::protobuf_parsing::ProtoField::<'_, #encodings>::encode_field(value, #nums, buf),)*
+				}
+			}
+
+			fn encoded_len(&self) -> usize {
+				match self {
+					#(Self::#variants(value) => ::protobuf_parsing::ProtoField::<'_, #encodings>::encoded_field_len(value, #nums),)*
+				}
+			}
+		}
+
+		#into_owned
+	})
+}
+
+/// The `IntoOwned` impl for `input`, if it can have one, given how to build
+/// the owned value from `self` with the path to its type.
+///
+/// Only types whose generics are all lifetimes qualify, as the owned type is
+/// the same type with each of them `'static`.
+fn into_owned_impl(
+	input: &DeriveInput,
+	convert: impl FnOnce(&proc_macro2::TokenStream) -> proc_macro2::TokenStream,
+) -> Option<proc_macro2::TokenStream> {
+	let name = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+	if input.generics.params.is_empty() {
+		return Some(quote! {
+			impl ::protobuf_parsing::IntoOwned for #name #where_clause {
+				type Owned = Self;
+
+				fn into_owned(self) -> Self {
+					self
+				}
+			}
+		});
+	}
+	if input.generics.type_params().next().is_some()
+		|| input.generics.const_params().next().is_some()
+		|| field_types(&input.data).any(has_reference)
+	{
+		return None;
+	}
+	let statics = input.generics.lifetimes().map(|_| quote!('static));
+	let owned = quote!(#name::<#(#statics),*>);
+	let body = convert(&owned);
+	Some(quote! {
+		impl #impl_generics ::protobuf_parsing::IntoOwned for #name #ty_generics #where_clause {
+			type Owned = #owned;
+
+			fn into_owned(self) -> Self::Owned {
+				#body
+			}
+		}
+	})
+}
+
+/// The types of a struct's fields or of an enum's variants' fields.
+fn field_types(data: &Data) -> Box<dyn Iterator<Item = &Type> + '_> {
+	match data {
+		Data::Struct(data) => Box::new(data.fields.iter().map(|field| &field.ty)),
+		Data::Enum(data) => Box::new(
+			data.variants
+				.iter()
+				.flat_map(|variant| variant.fields.iter().map(|field| &field.ty)),
+		),
+		Data::Union(_) => Box::new(std::iter::empty()),
+	}
+}
+
+/// Whether `ty` is or contains a reference, like `&'a str` or
+/// `Vec<&'a [u8]>`.
+fn has_reference(ty: &Type) -> bool {
+	match ty {
+		Type::Reference(_) => true,
+		Type::Path(path) => path.path.segments.iter().any(|segment| {
+			let PathArguments::AngleBracketed(args) = &segment.arguments else {
+				return false;
+			};
+			args.args.iter().any(|arg| match arg {
+				GenericArgument::Type(ty) => has_reference(ty),
+				_ => false,
+			})
+		}),
+		Type::Array(array) => has_reference(&array.elem),
+		Type::Slice(slice) => has_reference(&slice.elem),
+		Type::Tuple(tuple) => tuple.elems.iter().any(has_reference),
+		Type::Paren(paren) => has_reference(&paren.elem),
+		Type::Group(group) => has_reference(&group.elem),
+		_ => false,
+	}
+}
+
+/// The `T` in `Option<T>`.
+fn option_type(ty: &Type) -> syn::Result<&Type> {
+	if let Type::Path(path) = ty
+		&& let Some(segment) = path.path.segments.last()
+		&& segment.ident == "Option"
+		&& let PathArguments::AngleBracketed(args) = &segment.arguments
+		&& let Some(GenericArgument::Type(inner)) = args.args.first()
+	{
+		return Ok(inner);
+	}
+	Err(syn::Error::new(
+		ty.span(),
+		"#[proto(oneof)] fields must be an `Option` of a ProtoOneof enum",
+	))
+}
+
+/// What a struct field's or oneof member's `#[proto(...)]` attribute says it
+/// holds.
+enum ProtoAttr {
+	/// `#[proto(field = N)]`, with the encoding marker to use.
+	Field(u64, proc_macro2::TokenStream),
+	/// `#[proto(unknown_fields)]`
+	UnknownFields,
+	/// `#[proto(oneof)]`
+	Oneof,
+}
+
+/// The encoding marker named by `fixed` or `zigzag`, if `path` is one.
+fn encoding_marker(path: &syn::Path) -> Option<Ident> {
+	let marker = if path.is_ident("fixed") {
+		"Fixed"
+	} else if path.is_ident("zigzag") {
+		"ZigZag"
+	} else {
+		return None;
+	};
+	Some(Ident::new(marker, path.span()))
+}
+
+fn proto_attr(attrs: &[Attribute], span: Span) -> syn::Result<ProtoAttr> {
+	let mut field_num = None;
+	let mut unknown_fields = false;
+	let mut oneof = false;
+	let mut encoding = quote!(::protobuf_parsing::Natural);
+	for attr in attrs.iter().filter(|a| a.path().is_ident("proto")) {
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("field") {
+				let lit: LitInt = meta.value()?.parse()?;
+				let num: u64 = lit.base10_parse()?;
+				// Field numbers are 29 bits wide, and 0 is reserved.
+				if !(1..1 << 29).contains(&num) {
+					return Err(meta.error("field number must be between 1 and 2^29 - 1"));
+				}
+				field_num = Some(num);
+				Ok(())
+			} else if let Some(marker) = encoding_marker(&meta.path) {
+				encoding = quote!(::protobuf_parsing::#marker);
+				Ok(())
+			} else if meta.path.is_ident("map") {
+				let mut key = Ident::new("Natural", meta.path.span());
+				let mut value = key.clone();
+				if meta.input.peek(syn::token::Paren) {
+					meta.parse_nested_meta(|inner| {
+						let target = if inner.path.is_ident("key") {
+							&mut key
+						} else if inner.path.is_ident("value") {
+							&mut value
+						} else {
+							return Err(inner.error("expected `key` or `value`"));
+						};
+						let path: syn::Path = inner.value()?.parse()?;
+						*target = encoding_marker(&path)
+							.ok_or_else(|| syn::Error::new(path.span(), "expected `fixed` or `zigzag`"))?;
+						Ok(())
+					})?;
+				}
+				encoding = quote!(::protobuf_parsing::Map<::protobuf_parsing::#key, ::protobuf_parsing::#value>);
+				Ok(())
+			} else if meta.path.is_ident("unknown_fields") {
+				unknown_fields = true;
+				Ok(())
+			} else if meta.path.is_ident("oneof") {
+				oneof = true;
+				Ok(())
+			} else {
+				Err(meta.error("unsupported proto attribute"))
+			}
+		})?;
+	}
+	match (field_num, unknown_fields, oneof) {
+		(Some(field_num), false, false) => Ok(ProtoAttr::Field(field_num, encoding)),
+		(None, true, false) => Ok(ProtoAttr::UnknownFields),
+		(None, false, true) => Ok(ProtoAttr::Oneof),
+		(Some(_), _, _) => Err(syn::Error::new(
+			span,
+			"unknown_fields and oneof fields cannot have a field number",
+		)),
+		(None, true, true) => Err(syn::Error::new(
+			span,
+			"a field cannot be both unknown_fields and oneof",
+		)),
+		(None, false, false) => Err(syn::Error::new(
+			span,
+			"missing #[proto(field = N)] attribute",
+		)),
+	}
+}
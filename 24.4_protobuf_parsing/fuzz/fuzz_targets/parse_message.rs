@@ -0,0 +1,81 @@
+#![no_main]
+
+use std::collections::BTreeMap;
+
+use libfuzzer_sys::fuzz_target;
+use protobuf_parsing::stream::MessageReader;
+use protobuf_parsing::{Field, ProtoEncode, ProtoMessage, ProtoOneof, parse_message, raw};
+
+/// A message using every kind of field, so that any input exercises as much
+/// of the decoder as it can.
+#[derive(ProtoMessage, Debug, Default)]
+struct Everything<'a> {
+	#[proto(field = 1)]
+	uint: u64,
+	#[proto(field = 2)]
+	int: i32,
+	#[proto(field = 3, zigzag)]
+	sint: i64,
+	#[proto(field = 4, fixed)]
+	fixed: u32,
+	#[proto(field = 5)]
+	double: f64,
+	#[proto(field = 6)]
+	flag: bool,
+	#[proto(field = 7)]
+	text: &'a str,
+	#[proto(field = 8)]
+	data: &'a [u8],
+	#[proto(field = 9)]
+	packed: Vec<i64>,
+	#[proto(field = 10)]
+	strings: Vec<&'a str>,
+	#[proto(field = 11)]
+	child: Child<'a>,
+	#[proto(field = 12)]
+	children: Vec<Child<'a>>,
+	// A `BTreeMap`, so that encoding the same map twice gives the same bytes.
+	#[proto(field = 13, map(key = zigzag))]
+	counts: BTreeMap<i32, u32>,
+	#[proto(oneof)]
+	choice: Option<Choice<'a>>,
+	// Recursive, so that deeply nested input reaches the depth limit.
+	#[proto(field = 16)]
+	nested: Vec<Everything<'a>>,
+	#[proto(unknown_fields)]
+	unknown: Vec<Field<'a>>,
+}
+
+#[derive(ProtoMessage, Debug, Default)]
+struct Child<'a> {
+	#[proto(field = 1)]
+	name: &'a str,
+	#[proto(field = 2, fixed)]
+	ids: Vec<u64>,
+}
+
+#[derive(ProtoOneof, Debug)]
+enum Choice<'a> {
+	#[proto(field = 14)]
+	Name(&'a str),
+	#[proto(field = 15)]
+	Child(Child<'a>),
+}
+
+fuzz_target!(|data: &[u8]| {
+	// Malformed input must be an error, never a panic.
+	let _ = raw::format_raw(data);
+	let mut reader = MessageReader::new(data);
+	while let Ok(Some(_)) = reader.next_record() {}
+
+	let Ok(message) = parse_message::<Everything>(data) else {
+		return;
+	};
+	// Whatever decodes must encode to bytes that decode to the same message,
+	// which then encodes to the same bytes. Floats rule out comparing the
+	// messages themselves, as NaN != NaN.
+	let encoded = message.encode();
+	assert_eq!(encoded.len(), message.encoded_len());
+	let decoded: Everything = parse_message(&encoded).expect("an encoded message decodes");
+	assert_eq!(decoded.encode(), encoded);
+});
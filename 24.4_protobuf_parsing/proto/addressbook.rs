@@ -0,0 +1,108 @@
+#[derive(::protobuf_parsing::ProtoMessage, PartialEq, Debug, Default)]
+pub struct PersonAddress<'a> {
+	#[proto(field = 1)]
+	pub street: ::std::borrow::Cow<'a, str>,
+	#[proto(field = 2, fixed)]
+	pub zip: u32,
+}
+
+#[derive(::protobuf_parsing::ProtoMessage, PartialEq, Debug, Default)]
+pub struct Person<'a> {
+	#[proto(field = 1)]
+	pub name: ::std::borrow::Cow<'a, str>,
+	#[proto(field = 2)]
+	pub id: u64,
+	#[proto(field = 3)]
+	pub phone: Vec<PhoneNumber<'a>>,
+	#[proto(field = 4, zigzag)]
+	pub balance: i64,
+	#[proto(field = 5)]
+	pub height: f64,
+	#[proto(field = 6)]
+	pub active: bool,
+	#[proto(field = 7)]
+	pub tags: Vec<::std::borrow::Cow<'a, str>>,
+	#[proto(field = 8)]
+	pub address: PersonAddress<'a>,
+	#[proto(field = 9, map)]
+	pub attributes: ::std::collections::HashMap<::std::borrow::Cow<'a, str>, ::std::borrow::Cow<'a, str>>,
+	#[proto(field = 12)]
+	pub referrer: Option<Box<Person<'a>>>,
+	#[proto(oneof)]
+	pub contact: Option<PersonContact<'a>>,
+}
+
+#[derive(::protobuf_parsing::ProtoOneof, PartialEq, Debug)]
+pub enum PersonContact<'a> {
+	#[proto(field = 10)]
+	Email(::std::borrow::Cow<'a, str>),
+	#[proto(field = 11)]
+	WorkPhone(PhoneNumber<'a>),
+	#[proto(field = 13)]
+	Assistant(Box<Person<'a>>),
+}
+
+#[derive(::protobuf_parsing::ProtoMessage, PartialEq, Debug, Default)]
+pub struct PhoneNumber<'a> {
+	#[proto(field = 1)]
+	pub number: ::std::borrow::Cow<'a, str>,
+	#[proto(field = 2)]
+	pub type_: ::std::borrow::Cow<'a, str>,
+	/// A [`PhoneNumberPhoneType`] value.
+	#[proto(field = 3)]
+	pub kind: i32,
+}
+
+#[derive(::protobuf_parsing::ProtoMessage, PartialEq, Debug, Default)]
+pub struct Count {
+	#[proto(field = 1, zigzag)]
+	pub value: i32,
+	#[proto(field = 2)]
+	pub total: u32,
+	#[proto(field = 3)]
+	pub delta: i32,
+	#[proto(field = 4)]
+	pub offset: i64,
+	#[proto(field = 5, fixed)]
+	pub checksum: i64,
+	#[proto(field = 6, fixed)]
+	pub mask: u64,
+	#[proto(field = 7, fixed)]
+	pub low: i32,
+	#[proto(field = 8)]
+	pub ratio: f32,
+	#[proto(field = 12, map(key = zigzag, value = fixed))]
+	pub histogram: ::std::collections::HashMap<i32, u64>,
+}
+
+#[derive(::protobuf_parsing::ProtoMessage, PartialEq, Debug, Default)]
+pub struct AddressBook<'a> {
+	#[proto(field = 1)]
+	pub people: Vec<Person<'a>>,
+	#[proto(field = 2)]
+	pub counts: Vec<Count>,
+	#[proto(field = 3)]
+	pub signature: ::std::borrow::Cow<'a, [u8]>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[repr(i32)]
+pub enum PhoneNumberPhoneType {
+	#[default]
+	Unspecified = 0,
+	Mobile = 1,
+	Home = 2,
+}
+
+impl TryFrom<i32> for PhoneNumberPhoneType {
+	type Error = i32;
+
+	fn try_from(value: i32) -> Result<Self, i32> {
+		match value {
+			0 => Ok(PhoneNumberPhoneType::Unspecified),
+			1 => Ok(PhoneNumberPhoneType::Mobile),
+			2 => Ok(PhoneNumberPhoneType::Home),
+			_ => Err(value),
+		}
+	}
+}
@@ -0,0 +1,16 @@
+// Print the fields of a protobuf message without its schema, like
+// `protoc --decode_raw`: decode_raw [FILE], reading stdin without a FILE.
+
+use std::io::Read;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+	let mut data = Vec::new();
+	match std::env::args().nth(1) {
+		Some(path) => data = std::fs::read(path)?,
+		None => {
+			std::io::stdin().read_to_end(&mut data)?;
+		}
+	}
+	print!("{}", protobuf_parsing::raw::format_raw(&data)?);
+	Ok(())
+}
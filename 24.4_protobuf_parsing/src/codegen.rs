@@ -0,0 +1,781 @@
+//! Generate `#[derive(ProtoMessage)]` structs from proto3 `.proto` schemas.
+//!
+//! Messages become zero-copy structs like `Person` and `PhoneNumber`, with
+//! `string` and `bytes` fields as `Cow`s borrowed from the input, which
+//! `IntoOwned` copies out when the message has to outlive it. Nested messages and
+//! enums are flattened into their parent's name, so `Person.PhoneNumber`
+//! becomes `PersonPhoneNumber`. Enum fields are stored as their `i32` value,
+//! since proto3 enums are open, and each enum gets a `TryFrom<i32>` impl.
+//! Map fields become `HashMap`s, and each `oneof` becomes an `Option` of a
+//! generated `ProtoOneof` enum named after the message and the oneof.
+//! A singular field through which a message contains itself becomes an
+//! `Option<Box<T>>`, or a `Box<T>` in a oneof, as the struct would otherwise
+//! be infinitely large.
+//!
+//! From a build script:
+//!
+//! ```ignore
+//! // build.rs
+//! let out_dir = std::env::var("OUT_DIR").unwrap();
+//! protobuf_parsing::codegen::compile_protos(&["proto/addressbook.proto"], out_dir).unwrap();
+//!
+//! // src/lib.rs
+//! include!(concat!(env!("OUT_DIR"), "/addressbook.rs"));
+//! ```
+
+mod parser;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use parser::{Enum, FieldDef, Message, Schema};
+
+#[derive(Error, Debug)]
+pub enum SchemaError {
+	#[error("line {line}: {message}")]
+	Syntax { line: usize, message: String },
+	#[error("line {line}: {message}")]
+	Invalid { line: usize, message: String },
+	#[error("line {line}: {what} are not supported")]
+	Unsupported { line: usize, what: &'static str },
+	#[error("line {line}: unknown type `{name}`")]
+	UnknownType { line: usize, name: String },
+	#[error("{}: {source}", path.display())]
+	Io {
+		path: PathBuf,
+		source: std::io::Error,
+	},
+}
+
+/// Generate Rust code for each `.proto` file in `protos`, written to
+/// `<out_dir>/<file stem>.rs`.
+pub fn compile_protos(
+	protos: &[impl AsRef<Path>],
+	out_dir: impl AsRef<Path>,
+) -> Result<(), SchemaError> {
+	for proto in protos {
+		let proto = proto.as_ref();
+		println!("cargo:rerun-if-changed={}", proto.display());
+		let source = std::fs::read_to_string(proto).map_err(|source| SchemaError::Io {
+			path: proto.to_owned(),
+			source,
+		})?;
+		let code = format!(
+			"// @generated by protobuf_parsing::codegen from {}\n\n{}",
+			proto.display(),
+			generate(&source)?
+		);
+		let out = out_dir
+			.as_ref()
+			.join(proto.file_stem().unwrap_or_default())
+			.with_extension("rs");
+		std::fs::write(&out, code).map_err(|source| SchemaError::Io { path: out, source })?;
+	}
+	Ok(())
+}
+
+/// Generate Rust code for the messages and enums in proto3 `source`.
+pub fn generate(source: &str) -> Result<String, SchemaError> {
+	let schema = parser::parse(source)?;
+	Generator::new(&schema).generate()
+}
+
+/// A field's Rust type, and the `#[proto(...)]` encoding flag it needs.
+struct RustType {
+	name: String,
+	encoding: Option<String>,
+	/// For enum fields, the generated enum the `i32` holds values of.
+	enum_name: Option<String>,
+}
+
+fn scalar_type(type_name: &str) -> Option<(&'static str, Option<&'static str>)> {
+	Some(match type_name {
+		"double" => ("f64", None),
+		"float" => ("f32", None),
+		"int64" => ("i64", None),
+		"uint64" => ("u64", None),
+		"int32" => ("i32", None),
+		"uint32" => ("u32", None),
+		"bool" => ("bool", None),
+		"string" => ("::std::borrow::Cow<'a, str>", None),
+		"bytes" => ("::std::borrow::Cow<'a, [u8]>", None),
+		"fixed64" => ("u64", Some("fixed")),
+		"fixed32" => ("u32", Some("fixed")),
+		"sfixed64" => ("i64", Some("fixed")),
+		"sfixed32" => ("i32", Some("fixed")),
+		"sint64" => ("i64", Some("zigzag")),
+		"sint32" => ("i32", Some("zigzag")),
+		_ => return None,
+	})
+}
+
+/// `Person.PhoneNumber` -> `PersonPhoneNumber`.
+fn rust_type_name(full_name: &str) -> String {
+	full_name.replace('.', "")
+}
+
+/// The enum generated for `oneof` in message `message`: `Person.contact` ->
+/// `PersonContact`.
+fn rust_oneof_name(message: &str, oneof: &str) -> String {
+	format!("{}{}", rust_type_name(message), camel_case(oneof))
+}
+
+/// Field names are kept, with keywords suffixed by `_` like `type_`.
+fn rust_field_name(name: &str) -> String {
+	const KEYWORDS: &[&str] = &[
+		"as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else",
+		"enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+		"move", "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait",
+		"true", "try", "type", "unsafe", "use", "where", "while", "yield",
+	];
+	if KEYWORDS.contains(&name) {
+		format!("{name}_")
+	} else {
+		name.to_string()
+	}
+}
+
+/// `work_phone` or `WORK_PHONE` -> `WorkPhone`.
+fn camel_case(name: &str) -> String {
+	name.split('_')
+		.filter(|word| !word.is_empty())
+		.map(|word| {
+			let mut chars = word.chars();
+			let first = chars.next().unwrap().to_ascii_uppercase();
+			std::iter::once(first)
+				.chain(chars.map(|c| c.to_ascii_lowercase()))
+				.collect::<String>()
+		})
+		.collect()
+}
+
+/// `PHONE_TYPE_MOBILE` in enum `PhoneType` -> `Mobile`.
+fn rust_variant_name(enum_name: &str, value_name: &str) -> String {
+	let short_name = enum_name.rsplit('.').next().unwrap_or(enum_name);
+	let mut prefix = String::new();
+	for (i, c) in short_name.chars().enumerate() {
+		if c.is_uppercase() && i > 0 {
+			prefix.push('_');
+		}
+		prefix.push(c.to_ascii_uppercase());
+	}
+	prefix.push('_');
+	let name = match value_name.strip_prefix(&prefix) {
+		Some(rest) if rest.starts_with(|c: char| c.is_ascii_alphabetic()) => rest,
+		_ => value_name,
+	};
+	camel_case(name)
+}
+
+struct Generator<'s> {
+	schema: &'s Schema,
+	messages: HashMap<&'s str, &'s Message>,
+	enums: HashSet<&'s str>,
+	/// Messages whose struct needs a lifetime, because they contain a
+	/// `string`, `bytes` or a message that does.
+	borrowing: HashSet<&'s str>,
+	/// The singular fields through which a message contains itself, by
+	/// message and field number. They are boxed.
+	recursive: HashSet<(&'s str, u64)>,
+}
+
+impl<'s> Generator<'s> {
+	fn new(schema: &'s Schema) -> Self {
+		Generator {
+			schema,
+			messages: schema
+				.messages
+				.iter()
+				.map(|m| (m.name.as_str(), m))
+				.collect(),
+			enums: schema.enums.iter().map(|e| e.name.as_str()).collect(),
+			borrowing: HashSet::new(),
+			recursive: HashSet::new(),
+		}
+	}
+
+	/// Find the message or enum that `type_name`, written inside `scope`,
+	/// refers to: the innermost scope wins, as in protobuf.
+	fn resolve(&self, scope: &str, type_name: &str) -> Option<&'s str> {
+		let mut name = type_name.strip_prefix('.').unwrap_or(type_name);
+		if let Some(package) = &self.schema.package {
+			name = name
+				.strip_prefix(package.as_str())
+				.and_then(|rest| rest.strip_prefix('.'))
+				.unwrap_or(name);
+		}
+		let mut scope = if type_name.starts_with('.') {
+			""
+		} else {
+			scope
+		};
+		loop {
+			let candidate = if scope.is_empty() {
+				name.to_string()
+			} else {
+				format!("{scope}.{name}")
+			};
+			if let Some((&found, _)) = self.messages.get_key_value(candidate.as_str()) {
+				return Some(found);
+			}
+			if let Some(&found) = self.enums.get(candidate.as_str()) {
+				return Some(found);
+			}
+			if scope.is_empty() {
+				return None;
+			}
+			scope = scope.rsplit_once('.').map_or("", |(outer, _)| outer);
+		}
+	}
+
+	fn rust_type(&self, message: &Message, field: &FieldDef) -> Result<RustType, SchemaError> {
+		let mut value = self.value_type(message, field)?;
+		let Some(key) = &field.map_key else {
+			if self
+				.recursive
+				.contains(&(message.name.as_str(), field.number))
+			{
+				value.name = if field.oneof.is_some() {
+					format!("Box<{}>", value.name)
+				} else {
+					format!("Option<Box<{}>>", value.name)
+				};
+			}
+			return Ok(value);
+		};
+		// Keys can be any integral or string type.
+		let (key_name, key_encoding) = match scalar_type(key) {
+			Some(scalar) if !matches!(key.as_str(), "double" | "float" | "bytes") => scalar,
+			_ => {
+				return Err(SchemaError::Invalid {
+					line: field.line,
+					message: format!("invalid map key type `{key}`"),
+				});
+			}
+		};
+		let encodings: Vec<_> = [("key", key_encoding), ("value", value.encoding.as_deref())]
+			.into_iter()
+			.filter_map(|(part, encoding)| Some(format!("{part} = {}", encoding?)))
+			.collect();
+		let encoding = if encodings.is_empty() {
+			"map".to_string()
+		} else {
+			format!("map({})", encodings.join(", "))
+		};
+		Ok(RustType {
+			name: format!("::std::collections::HashMap<{key_name}, {}>", value.name),
+			encoding: Some(encoding),
+			enum_name: value.enum_name,
+		})
+	}
+
+	/// The type of a field's values, which for maps is the type of the map's
+	/// values.
+	fn value_type(&self, message: &Message, field: &FieldDef) -> Result<RustType, SchemaError> {
+		if let Some((name, encoding)) = scalar_type(&field.type_name) {
+			return Ok(RustType {
+				name: name.to_string(),
+				encoding: encoding.map(str::to_string),
+				enum_name: None,
+			});
+		}
+		let resolved = self
+			.resolve(&message.name, &field.type_name)
+			.ok_or_else(|| SchemaError::UnknownType {
+				line: field.line,
+				name: field.type_name.clone(),
+			})?;
+		if self.enums.contains(resolved) {
+			return Ok(RustType {
+				name: "i32".to_string(),
+				encoding: None,
+				enum_name: Some(rust_type_name(resolved)),
+			});
+		}
+		let mut name = rust_type_name(resolved);
+		if self.borrowing.contains(resolved) {
+			name.push_str("<'a>");
+		}
+		Ok(RustType {
+			name,
+			encoding: None,
+			enum_name: None,
+		})
+	}
+
+	/// The messages that `message`'s singular fields hold directly, with the
+	/// fields. Repeated and map fields hold theirs on the heap.
+	fn contained_messages<'m>(
+		&'m self,
+		message: &'m Message,
+	) -> impl Iterator<Item = (&'m FieldDef, &'s str)> + 'm {
+		message
+			.fields
+			.iter()
+			.filter(|field| !field.repeated && field.map_key.is_none())
+			.filter_map(|field| {
+				let resolved = self.resolve(&message.name, &field.type_name)?;
+				self.messages
+					.contains_key(resolved)
+					.then_some((field, resolved))
+			})
+	}
+
+	/// Reject schemas where flattening gives two types the same Rust name,
+	/// like `Person.PhoneNumber` and `PersonPhoneNumber`, or a oneof and a
+	/// nested message. The later of the two is reported.
+	fn check_names(&self) -> Result<(), SchemaError> {
+		// (line, what the schema calls it, Rust name)
+		let mut types = Vec::new();
+		for message in &self.schema.messages {
+			let name = &message.name;
+			types.push((message.line, format!("`{name}`"), rust_type_name(name)));
+			let mut oneofs = HashSet::new();
+			for field in &message.fields {
+				if let Some(oneof) = &field.oneof
+					&& oneofs.insert(oneof)
+				{
+					let what = format!("oneof `{name}.{oneof}`");
+					types.push((field.line, what, rust_oneof_name(name, oneof)));
+				}
+			}
+		}
+		for enumeration in &self.schema.enums {
+			let name = &enumeration.name;
+			types.push((enumeration.line, format!("`{name}`"), rust_type_name(name)));
+		}
+		types.sort();
+		let mut seen: HashMap<String, String> = HashMap::new();
+		for (line, what, rust_name) in types {
+			if let Some(other) = seen.get(&rust_name) {
+				return Err(SchemaError::Invalid {
+					line,
+					message: format!("{what} and {other} would both be named `{rust_name}`"),
+				});
+			}
+			seen.insert(rust_name, what);
+		}
+		Ok(())
+	}
+
+	/// Find the singular fields through which a message contains itself,
+	/// which would make its struct infinitely large unboxed.
+	fn find_recursive(&mut self) {
+		let schema = self.schema;
+		let recursive = schema
+			.messages
+			.iter()
+			.flat_map(|message| {
+				self.contained_messages(message)
+					.filter(|&(_, first)| self.contains(first, &message.name))
+					.map(|(field, _)| (message.name.as_str(), field.number))
+			})
+			.collect();
+		self.recursive = recursive;
+	}
+
+	/// Whether message `outer` is or holds a `target` through singular
+	/// fields.
+	fn contains(&self, outer: &'s str, target: &str) -> bool {
+		let mut seen = HashSet::new();
+		let mut stack = vec![outer];
+		while let Some(name) = stack.pop() {
+			if name == target {
+				return true;
+			}
+			if seen.insert(name) {
+				let contained = self.contained_messages(self.messages[name]);
+				stack.extend(contained.map(|(_, name)| name));
+			}
+		}
+		false
+	}
+
+	fn find_borrowing(&mut self) -> Result<(), SchemaError> {
+		let schema = self.schema;
+		loop {
+			let mut changed = false;
+			for message in &schema.messages {
+				if self.borrowing.contains(message.name.as_str()) {
+					continue;
+				}
+				for field in &message.fields {
+					if self.rust_type(message, field)?.name.contains("'a") {
+						self.borrowing.insert(&message.name);
+						changed = true;
+						break;
+					}
+				}
+			}
+			if !changed {
+				return Ok(());
+			}
+		}
+	}
+
+	fn generate(mut self) -> Result<String, SchemaError> {
+		self.check_names()?;
+		self.find_recursive();
+		self.find_borrowing()?;
+		let mut out = String::new();
+		for message in &self.schema.messages {
+			self.write_message(&mut out, message)?;
+		}
+		for enumeration in &self.schema.enums {
+			write_enum(&mut out, enumeration);
+		}
+		Ok(out)
+	}
+
+	fn write_message(&self, out: &mut String, message: &Message) -> Result<(), SchemaError> {
+		let name = rust_type_name(&message.name);
+		let lifetime = if self.borrowing.contains(message.name.as_str()) {
+			"<'a>"
+		} else {
+			""
+		};
+		if !out.is_empty() {
+			out.push('\n');
+		}
+		writeln!(
+			out,
+			"#[derive(::protobuf_parsing::ProtoMessage, PartialEq, Debug, Default)]"
+		)
+		.unwrap();
+		writeln!(out, "pub struct {name}{lifetime} {{").unwrap();
+		let mut oneofs: Vec<&str> = Vec::new();
+		for field in &message.fields {
+			if let Some(oneof) = &field.oneof {
+				if !oneofs.contains(&oneof.as_str()) {
+					oneofs.push(oneof);
+				}
+				continue;
+			}
+			let rust_type = self.rust_type(message, field)?;
+			if let Some(enum_name) = &rust_type.enum_name {
+				let doc = if field.map_key.is_some() {
+					format!("Values are [`{enum_name}`] values.")
+				} else {
+					format!("A [`{enum_name}`] value.")
+				};
+				writeln!(out, "\t/// {doc}").unwrap();
+			}
+			let encoding = rust_type
+				.encoding
+				.map_or(String::new(), |encoding| format!(", {encoding}"));
+			writeln!(out, "\t#[proto(field = {}{encoding})]", field.number).unwrap();
+			let ty = if field.repeated {
+				format!("Vec<{}>", rust_type.name)
+			} else {
+				rust_type.name
+			};
+			writeln!(out, "\tpub {}: {ty},", rust_field_name(&field.name)).unwrap();
+		}
+		let mut enums = String::new();
+		for oneof in oneofs {
+			let enum_name = rust_oneof_name(&message.name, oneof);
+			let enum_lifetime = self.write_oneof(&mut enums, message, oneof, &enum_name)?;
+			writeln!(out, "\t#[proto(oneof)]").unwrap();
+			writeln!(
+				out,
+				"\tpub {}: Option<{enum_name}{enum_lifetime}>,",
+				rust_field_name(oneof)
+			)
+			.unwrap();
+		}
+		writeln!(out, "}}").unwrap();
+		out.push_str(&enums);
+		Ok(())
+	}
+
+	/// Write the enum for `oneof` in `message`, returning its lifetime
+	/// parameters: members are borrowed like any other field.
+	fn write_oneof(
+		&self,
+		out: &mut String,
+		message: &Message,
+		oneof: &str,
+		enum_name: &str,
+	) -> Result<&'static str, SchemaError> {
+		let mut variants = String::new();
+		let mut borrowing = false;
+		for field in &message.fields {
+			if field.oneof.as_deref() != Some(oneof) {
+				continue;
+			}
+			let rust_type = self.rust_type(message, field)?;
+			borrowing |= rust_type.name.contains("'a");
+			if let Some(enum_name) = &rust_type.enum_name {
+				writeln!(variants, "\t/// A [`{enum_name}`] value.").unwrap();
+			}
+			let encoding = rust_type
+				.encoding
+				.map_or(String::new(), |encoding| format!(", {encoding}"));
+			writeln!(variants, "\t#[proto(field = {}{encoding})]", field.number).unwrap();
+			writeln!(
+				variants,
+				"\t{}({}),",
+				camel_case(&field.name),
+				rust_type.name
+			)
+			.unwrap();
+		}
+		let lifetime = if borrowing { "<'a>" } else { "" };
+		writeln!(out).unwrap();
+		writeln!(
+			out,
+			"#[derive(::protobuf_parsing::ProtoOneof, PartialEq, Debug)]"
+		)
+		.unwrap();
+		writeln!(out, "pub enum {enum_name}{lifetime} {{").unwrap();
+		out.push_str(&variants);
+		writeln!(out, "}}").unwrap();
+		Ok(lifetime)
+	}
+}
+
+fn write_enum(out: &mut String, enumeration: &Enum) {
+	let name = rust_type_name(&enumeration.name);
+	// Aliases share a value; only the first name gets a variant.
+	let mut seen = HashSet::new();
+	let variants: Vec<_> = enumeration
+		.values
+		.iter()
+		.filter(|(_, value)| seen.insert(*value))
+		.map(|(value_name, value)| (rust_variant_name(&enumeration.name, value_name), *value))
+		.collect();
+
+	writeln!(out).unwrap();
+	writeln!(out, "#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]").unwrap();
+	writeln!(out, "#[repr(i32)]").unwrap();
+	writeln!(out, "pub enum {name} {{").unwrap();
+	for (i, (variant, value)) in variants.iter().enumerate() {
+		if i == 0 {
+			writeln!(out, "\t#[default]").unwrap();
+		}
+		writeln!(out, "\t{variant} = {value},").unwrap();
+	}
+	writeln!(out, "}}").unwrap();
+	writeln!(out).unwrap();
+	writeln!(out, "impl TryFrom<i32> for {name} {{").unwrap();
+	writeln!(out, "\ttype Error = i32;").unwrap();
+	writeln!(out).unwrap();
+	writeln!(out, "\tfn try_from(value: i32) -> Result<Self, i32> {{").unwrap();
+	writeln!(out, "\t\tmatch value {{").unwrap();
+	for (variant, value) in &variants {
+		writeln!(out, "\t\t\t{value} => Ok({name}::{variant}),").unwrap();
+	}
+	writeln!(out, "\t\t\t_ => Err(value),").unwrap();
+	writeln!(out, "\t\t}}").unwrap();
+	writeln!(out, "\t}}").unwrap();
+	writeln!(out, "}}").unwrap();
+}
+
+#[cfg(test)]
+mod test {
+	use std::borrow::Cow;
+
+	use super::*;
+	use crate::{FULL_PERSON, IntoOwned, ProtoEncode, parse_message};
+
+	mod addressbook {
+		include!("../proto/addressbook.rs");
+	}
+
+	#[test]
+	fn test_generate_addressbook() {
+		// Regenerate proto/addressbook.rs when the generator changes.
+		let code = generate(include_str!("../proto/addressbook.proto")).unwrap();
+		assert_eq!(code, include_str!("../proto/addressbook.rs"));
+	}
+
+	#[test]
+	fn test_generated_person() {
+		use addressbook::{Person, PhoneNumber};
+
+		let person: Person = parse_message(FULL_PERSON).unwrap();
+		assert_eq!(person.name, "maxwell");
+		assert_eq!(person.id, 42);
+		assert_eq!(
+			person.phone,
+			[
+				PhoneNumber {
+					number: "+1202-555-1212".into(),
+					type_: "home".into(),
+					kind: 0,
+				},
+				PhoneNumber {
+					number: "+1800-867-5308".into(),
+					type_: "mobile".into(),
+					kind: 0,
+				},
+			]
+		);
+		assert_eq!(person.encode(), FULL_PERSON);
+	}
+
+	#[test]
+	fn test_generated_types() {
+		use addressbook::*;
+
+		let book = AddressBook {
+			people: vec![Person {
+				balance: -3,
+				height: 1.75,
+				active: true,
+				tags: vec!["a".into(), "b".into()],
+				address: PersonAddress {
+					street: "Main St".into(),
+					zip: 12345,
+				},
+				attributes: HashMap::from([("team".into(), "search".into())]),
+				contact: Some(PersonContact::WorkPhone(PhoneNumber {
+					number: "555".into(),
+					..Default::default()
+				})),
+				referrer: Some(Box::new(Person {
+					contact: Some(PersonContact::Assistant(Box::default())),
+					..Default::default()
+				})),
+				phone: vec![PhoneNumber {
+					kind: PhoneNumberPhoneType::Home as i32,
+					..Default::default()
+				}],
+				..Default::default()
+			}],
+			counts: vec![Count {
+				value: -1,
+				total: u32::MAX,
+				delta: i32::MIN,
+				offset: -2,
+				checksum: -3,
+				mask: u64::MAX,
+				low: -4,
+				ratio: 0.5,
+				histogram: HashMap::from([(-1, 3)]),
+			}],
+			signature: Cow::Borrowed(&[0xde, 0xad]),
+		};
+		let bytes = book.encode();
+		let decoded: AddressBook = parse_message(&bytes).unwrap();
+		assert_eq!(
+			PhoneNumberPhoneType::try_from(decoded.people[0].phone[0].kind),
+			Ok(PhoneNumberPhoneType::Home)
+		);
+		assert_eq!(PhoneNumberPhoneType::try_from(7), Err(7));
+		assert_eq!(decoded, book);
+		// Decoded strings borrow from the buffer until made owned.
+		assert!(matches!(decoded.people[0].tags[0], Cow::Borrowed(_)));
+		let owned: AddressBook<'static> = decoded.into_owned();
+		drop(bytes);
+		assert_eq!(owned, book);
+	}
+
+	#[test]
+	fn test_generated_oneof() {
+		use addressbook::*;
+
+		// email: "a@b.c", then work_phone { number: "555" }
+		let data = [
+			0x52, 0x05, b'a', b'@', b'b', b'.', b'c', //
+			0x5a, 0x05, 0x0a, 0x03, b'5', b'5', b'5',
+		];
+		let person: Person = parse_message(&data).unwrap();
+		assert_eq!(
+			person.contact,
+			Some(PersonContact::WorkPhone(PhoneNumber {
+				number: "555".into(),
+				..Default::default()
+			}))
+		);
+		assert_eq!(person.encode(), &data[7..]);
+	}
+
+	#[test]
+	fn test_resolve_scopes() {
+		let code = generate(
+			"syntax = \"proto3\";
+			package pkg.v1;
+			message A { message B { string s = 1; } B b = 1; }
+			message C { A.B b = 1; .pkg.v1.A a = 2; repeated C children = 3; }",
+		)
+		.unwrap();
+		assert!(code.contains("pub b: AB<'a>,"), "{code}");
+		assert!(code.contains("pub a: A<'a>,"), "{code}");
+		assert!(code.contains("pub children: Vec<C<'a>>,"), "{code}");
+	}
+
+	#[test]
+	fn test_recursive_fields() {
+		let code = generate(
+			"syntax = \"proto3\";
+			message A { A next = 1; repeated A children = 2; B b = 3; }
+			message B { oneof o { A a = 1; C c = 2; } }
+			message C { string s = 1; }",
+		)
+		.unwrap();
+		assert!(code.contains("pub next: Option<Box<A<'a>>>,"), "{code}");
+		assert!(code.contains("pub children: Vec<A<'a>>,"), "{code}");
+		assert!(code.contains("pub b: Option<Box<B<'a>>>,"), "{code}");
+		assert!(code.contains("\tA(Box<A<'a>>),"), "{code}");
+		assert!(code.contains("\tC(C<'a>),"), "{code}");
+	}
+
+	#[test]
+	fn test_enum_names() {
+		assert_eq!(
+			rust_variant_name("PhoneType", "PHONE_TYPE_MOBILE"),
+			"Mobile"
+		);
+		assert_eq!(rust_variant_name("Person.Kind", "KIND_2D"), "Kind2d");
+		assert_eq!(rust_variant_name("Color", "RED"), "Red");
+	}
+
+	#[test]
+	fn test_schema_errors() {
+		let err = |source| generate(source).unwrap_err().to_string();
+		assert_eq!(
+			err("syntax = \"proto2\";"),
+			"line 1: syntaxes other than proto3 are not supported"
+		);
+		assert_eq!(
+			err("message A {\n  Missing m = 1;\n}"),
+			"line 2: unknown type `Missing`"
+		);
+		assert_eq!(
+			err("message A {\n  string a = 1;\n  string b = 1;\n}"),
+			"line 3: `b` = 1 clashes with `a`"
+		);
+		assert_eq!(
+			err("message A {\n  map<double, string> m = 1;\n}"),
+			"line 2: invalid map key type `double`"
+		);
+		assert_eq!(
+			err("message A {\n  oneof o {\n    repeated string s = 1;\n  }\n}"),
+			"line 3: oneof members cannot be `repeated`"
+		);
+		assert_eq!(
+			err("message A {\n  string s = 1;\n  oneof o { string t = 1; }\n}"),
+			"line 3: `t` = 1 clashes with `s`"
+		);
+		assert_eq!(
+			err("message A {\n  string a = 1\n}"),
+			"line 3: expected `;`"
+		);
+		assert_eq!(
+			err("message PersonPhone {}\nmessage Person {\n  message Phone {}\n}"),
+			"line 3: `Person.Phone` and `PersonPhone` would both be named `PersonPhone`"
+		);
+		assert_eq!(
+			err("message A {\n  message B {}\n  oneof b { string s = 1; }\n}"),
+			"line 3: oneof `A.b` and `A.B` would both be named `AB`"
+		);
+		assert_eq!(
+			err("enum E { E_ONE = 1; }"),
+			"line 1: the first value of enum `E` must be 0"
+		);
+	}
+}
@@ -0,0 +1,429 @@
+use super::SchemaError;
+
+/// The parts of a `.proto` file that code is generated from. Names are fully
+/// qualified within the file, e.g. `Person.PhoneNumber`, without the package.
+#[derive(Debug, Default)]
+pub(super) struct Schema {
+	pub package: Option<String>,
+	pub messages: Vec<Message>,
+	pub enums: Vec<Enum>,
+}
+
+#[derive(Debug)]
+pub(super) struct Message {
+	pub name: String,
+	pub fields: Vec<FieldDef>,
+	pub line: usize,
+}
+
+#[derive(Debug)]
+pub(super) struct FieldDef {
+	pub name: String,
+	/// The type as written in the schema, resolved later. For maps, the
+	/// type of the values.
+	pub type_name: String,
+	pub repeated: bool,
+	/// For maps, the type of the keys.
+	pub map_key: Option<String>,
+	/// The `oneof` the field is a member of.
+	pub oneof: Option<String>,
+	pub number: u64,
+	pub line: usize,
+}
+
+#[derive(Debug)]
+pub(super) struct Enum {
+	pub name: String,
+	pub values: Vec<(String, i32)>,
+	pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	/// An identifier, possibly dotted: `Person.PhoneNumber`, `.pkg.Person`.
+	Ident(String),
+	Int(u64),
+	Str(String),
+	Symbol(char),
+}
+
+/// Split `source` into tokens, each with its line number.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, SchemaError> {
+	let mut tokens = Vec::new();
+	let mut line = 1;
+	let mut chars = source.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\n' => line += 1,
+			c if c.is_whitespace() => {}
+			'/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+			'/' if chars.peek() == Some(&'*') => {
+				chars.next();
+				let start = line;
+				let mut prev = ' ';
+				loop {
+					match chars.next() {
+						Some('/') if prev == '*' => break,
+						Some(c) => {
+							if c == '\n' {
+								line += 1;
+							}
+							prev = c;
+						}
+						None => return Err(syntax(start, "unterminated comment")),
+					}
+				}
+			}
+			'"' | '\'' => {
+				let mut value = String::new();
+				loop {
+					match chars.next() {
+						Some(q) if q == c => break,
+						Some('\\') => value.extend(chars.next()),
+						Some('\n') | None => return Err(syntax(line, "unterminated string")),
+						Some(c) => value.push(c),
+					}
+				}
+				tokens.push((Token::Str(value), line));
+			}
+			c if c.is_ascii_digit() => {
+				let mut text = String::from(c);
+				while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric()) {
+					text.push(c);
+				}
+				let value = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+					Some(hex) => u64::from_str_radix(hex, 16),
+					None => text.parse(),
+				}
+				.map_err(|_| syntax(line, &format!("invalid number `{text}`")))?;
+				tokens.push((Token::Int(value), line));
+			}
+			c if c.is_alphabetic() || c == '_' || c == '.' => {
+				let mut text = String::from(c);
+				while let Some(c) = chars.next_if(|&c| c.is_alphanumeric() || c == '_' || c == '.')
+				{
+					text.push(c);
+				}
+				tokens.push((Token::Ident(text), line));
+			}
+			c => tokens.push((Token::Symbol(c), line)),
+		}
+	}
+	Ok(tokens)
+}
+
+fn syntax(line: usize, message: &str) -> SchemaError {
+	SchemaError::Syntax {
+		line,
+		message: message.to_string(),
+	}
+}
+
+struct Parser {
+	tokens: Vec<(Token, usize)>,
+	pos: usize,
+	schema: Schema,
+}
+
+/// Parse proto3 `source` into a `Schema`.
+pub(super) fn parse(source: &str) -> Result<Schema, SchemaError> {
+	let mut parser = Parser {
+		tokens: tokenize(source)?,
+		pos: 0,
+		schema: Schema::default(),
+	};
+	while parser.peek().is_some() {
+		parser.parse_top_level()?;
+	}
+	Ok(parser.schema)
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos).map(|(token, _)| token)
+	}
+
+	/// The line of the next token, or of the last one at the end of input.
+	fn line(&self) -> usize {
+		self.tokens
+			.get(self.pos)
+			.or(self.tokens.last())
+			.map_or(1, |(_, line)| *line)
+	}
+
+	fn next(&mut self) -> Result<Token, SchemaError> {
+		let line = self.line();
+		let token = self
+			.peek()
+			.cloned()
+			.ok_or_else(|| syntax(line, "unexpected end of file"))?;
+		self.pos += 1;
+		Ok(token)
+	}
+
+	fn eat_symbol(&mut self, symbol: char) -> bool {
+		let found = self.peek() == Some(&Token::Symbol(symbol));
+		if found {
+			self.pos += 1;
+		}
+		found
+	}
+
+	fn expect_symbol(&mut self, symbol: char) -> Result<(), SchemaError> {
+		if self.eat_symbol(symbol) {
+			Ok(())
+		} else {
+			Err(syntax(self.line(), &format!("expected `{symbol}`")))
+		}
+	}
+
+	fn expect_ident(&mut self) -> Result<String, SchemaError> {
+		let line = self.line();
+		match self.next()? {
+			Token::Ident(ident) => Ok(ident),
+			_ => Err(syntax(line, "expected an identifier")),
+		}
+	}
+
+	fn expect_int(&mut self) -> Result<u64, SchemaError> {
+		let line = self.line();
+		match self.next()? {
+			Token::Int(value) => Ok(value),
+			_ => Err(syntax(line, "expected a number")),
+		}
+	}
+
+	/// Skip an `option`/`reserved` statement through its `;`.
+	fn skip_statement(&mut self) -> Result<(), SchemaError> {
+		while self.next()? != Token::Symbol(';') {}
+		Ok(())
+	}
+
+	fn unsupported(&self, what: &'static str) -> SchemaError {
+		SchemaError::Unsupported {
+			line: self.line(),
+			what,
+		}
+	}
+
+	fn parse_top_level(&mut self) -> Result<(), SchemaError> {
+		let line = self.line();
+		if self.eat_symbol(';') {
+			return Ok(());
+		}
+		match self.expect_ident()?.as_str() {
+			"syntax" => {
+				self.expect_symbol('=')?;
+				if self.next()? != Token::Str("proto3".to_string()) {
+					return Err(SchemaError::Unsupported {
+						line,
+						what: "syntaxes other than proto3",
+					});
+				}
+				self.expect_symbol(';')
+			}
+			"package" => {
+				self.schema.package = Some(self.expect_ident()?);
+				self.expect_symbol(';')
+			}
+			"option" => self.skip_statement(),
+			"message" => self.parse_message(""),
+			"enum" => self.parse_enum(""),
+			"import" => Err(SchemaError::Unsupported {
+				line,
+				what: "imports",
+			}),
+			"service" => Err(SchemaError::Unsupported {
+				line,
+				what: "services",
+			}),
+			_ => Err(syntax(line, "expected `message` or `enum`")),
+		}
+	}
+
+	/// Parse a message after its `message` keyword, along with any messages
+	/// and enums nested in it.
+	fn parse_message(&mut self, scope: &str) -> Result<(), SchemaError> {
+		let line = self.line();
+		let name = scoped(scope, &self.expect_ident()?);
+		self.expect_symbol('{')?;
+		let mut fields: Vec<FieldDef> = Vec::new();
+		while !self.eat_symbol('}') {
+			if self.eat_symbol(';') {
+				continue;
+			}
+			let line = self.line();
+			let mut word = self.expect_ident()?;
+			match word.as_str() {
+				"message" => {
+					self.parse_message(&name)?;
+					continue;
+				}
+				"enum" => {
+					self.parse_enum(&name)?;
+					continue;
+				}
+				"option" | "reserved" => {
+					self.skip_statement()?;
+					continue;
+				}
+				"oneof" => {
+					self.parse_oneof(&mut fields)?;
+					continue;
+				}
+				"extensions" | "extend" => return Err(self.unsupported("extensions")),
+				"required" => return Err(self.unsupported("required fields")),
+				_ => {}
+			}
+			let repeated = word == "repeated";
+			if repeated || word == "optional" {
+				word = self.expect_ident()?;
+			}
+			let mut map_key = None;
+			if word == "map" && self.eat_symbol('<') {
+				if repeated {
+					return Err(SchemaError::Invalid {
+						line,
+						message: "map fields cannot be repeated".to_string(),
+					});
+				}
+				map_key = Some(self.expect_ident()?);
+				self.expect_symbol(',')?;
+				word = self.expect_ident()?;
+				self.expect_symbol('>')?;
+			}
+			let mut field = self.parse_field(word, line)?;
+			field.repeated = repeated;
+			field.map_key = map_key;
+			add_field(&mut fields, field)?;
+		}
+		self.schema.messages.push(Message { name, fields, line });
+		Ok(())
+	}
+
+	/// Parse the rest of a field after its type: `name = 1 [options];`.
+	fn parse_field(&mut self, type_name: String, line: usize) -> Result<FieldDef, SchemaError> {
+		let name = self.expect_ident()?;
+		self.expect_symbol('=')?;
+		let number = self.expect_int()?;
+		if !(1..1 << 29).contains(&number) {
+			return Err(SchemaError::Invalid {
+				line,
+				message: format!("field number {number} is out of range"),
+			});
+		}
+		if self.eat_symbol('[') {
+			while !self.eat_symbol(']') {
+				self.next()?;
+			}
+		}
+		self.expect_symbol(';')?;
+		Ok(FieldDef {
+			name,
+			type_name,
+			repeated: false,
+			map_key: None,
+			oneof: None,
+			number,
+			line,
+		})
+	}
+
+	/// Parse a oneof after its `oneof` keyword, adding its members to
+	/// `fields`.
+	fn parse_oneof(&mut self, fields: &mut Vec<FieldDef>) -> Result<(), SchemaError> {
+		let oneof = self.expect_ident()?;
+		self.expect_symbol('{')?;
+		while !self.eat_symbol('}') {
+			if self.eat_symbol(';') {
+				continue;
+			}
+			let line = self.line();
+			let word = self.expect_ident()?;
+			if word == "option" {
+				self.skip_statement()?;
+				continue;
+			}
+			if matches!(word.as_str(), "repeated" | "optional")
+				|| word == "map" && self.peek() == Some(&Token::Symbol('<'))
+			{
+				return Err(SchemaError::Invalid {
+					line,
+					message: format!("oneof members cannot be `{word}`"),
+				});
+			}
+			let mut field = self.parse_field(word, line)?;
+			field.oneof = Some(oneof.clone());
+			add_field(fields, field)?;
+		}
+		Ok(())
+	}
+
+	/// Parse an enum after its `enum` keyword.
+	fn parse_enum(&mut self, scope: &str) -> Result<(), SchemaError> {
+		let line = self.line();
+		let name = scoped(scope, &self.expect_ident()?);
+		self.expect_symbol('{')?;
+		let mut values = Vec::new();
+		while !self.eat_symbol('}') {
+			if self.eat_symbol(';') {
+				continue;
+			}
+			let line = self.line();
+			let value_name = self.expect_ident()?;
+			if value_name == "option" || value_name == "reserved" {
+				self.skip_statement()?;
+				continue;
+			}
+			self.expect_symbol('=')?;
+			let negative = self.eat_symbol('-');
+			let magnitude = self.expect_int()?;
+			let value = i64::try_from(magnitude)
+				.ok()
+				.map(|v| if negative { -v } else { v })
+				.and_then(|v| i32::try_from(v).ok())
+				.ok_or_else(|| syntax(line, "enum value out of range for int32"))?;
+			if self.eat_symbol('[') {
+				while !self.eat_symbol(']') {
+					self.next()?;
+				}
+			}
+			self.expect_symbol(';')?;
+			values.push((value_name, value));
+		}
+		if values.first().is_none_or(|(_, value)| *value != 0) {
+			return Err(SchemaError::Invalid {
+				line: self.line(),
+				message: format!("the first value of enum `{name}` must be 0"),
+			});
+		}
+		self.schema.enums.push(Enum { name, values, line });
+		Ok(())
+	}
+}
+
+/// Add `field` to a message's `fields`, unless its name or number is taken.
+fn add_field(fields: &mut Vec<FieldDef>, field: FieldDef) -> Result<(), SchemaError> {
+	if let Some(other) = fields
+		.iter()
+		.find(|f| f.number == field.number || f.name == field.name)
+	{
+		return Err(SchemaError::Invalid {
+			line: field.line,
+			message: format!(
+				"`{}` = {} clashes with `{}`",
+				field.name, field.number, other.name
+			),
+		});
+	}
+	fields.push(field);
+	Ok(())
+}
+
+fn scoped(scope: &str, name: &str) -> String {
+	if scope.is_empty() {
+		name.to_string()
+	} else {
+		format!("{scope}.{name}")
+	}
+}
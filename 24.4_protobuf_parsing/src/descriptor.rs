@@ -0,0 +1,208 @@
+use crate::{DecodeError, FieldValue, Level, WireType, parse_field, value_start};
+
+/// The type of a field as declared in the `.proto` file, which the wire
+/// format alone does not tell apart: `int64` and `sint64` are both VARINTs.
+#[derive(Debug, Clone, Copy)]
+pub enum FieldType {
+	Double,
+	Float,
+	Int64,
+	Uint64,
+	Int32,
+	Uint32,
+	Sint64,
+	Sint32,
+	Fixed64,
+	Fixed32,
+	Sfixed64,
+	Sfixed32,
+	Bool,
+	String,
+	Bytes,
+	/// An enum with these named values. Others are shown as numbers.
+	Enum(&'static [(&'static str, i32)]),
+	Message(&'static [FieldDesc]),
+}
+
+/// One field of a message, for the formats that go by field name: the
+/// `json` mapping and the `text` format.
+#[derive(Debug)]
+pub struct FieldDesc {
+	/// The name as written in the `.proto` file.
+	pub name: &'static str,
+	pub number: u64,
+	pub field_type: FieldType,
+	pub repeated: bool,
+}
+
+/// A message whose fields are described for `json` and `text`.
+pub trait ProtoDescribe {
+	const FIELDS: &'static [FieldDesc];
+}
+
+impl FieldType {
+	/// The wire type of each value when a repeated field of this type is
+	/// packed, or `None` if it cannot be.
+	fn packed_wire_type(self) -> Option<WireType> {
+		match self {
+			FieldType::Double | FieldType::Fixed64 | FieldType::Sfixed64 => Some(WireType::I64),
+			FieldType::Float | FieldType::Fixed32 | FieldType::Sfixed32 => Some(WireType::I32),
+			FieldType::String | FieldType::Bytes | FieldType::Message(_) => None,
+			_ => Some(WireType::Varint),
+		}
+	}
+}
+
+impl FieldDesc {
+	/// Decode one of the field's values, found at byte `offset` of the
+	/// buffer being decoded.
+	fn value<'a>(&self, value: &FieldValue<'a>, offset: usize) -> Result<Value<'a>, DecodeError> {
+		let value = match self.field_type {
+			FieldType::Double => value.as_f64().map(Value::Double),
+			FieldType::Float => value.as_f32().map(Value::Float),
+			FieldType::Int64 => value.as_int64().map(Value::Int64),
+			FieldType::Uint64 => value.as_u64().map(Value::Uint64),
+			FieldType::Int32 => value.as_int32().map(Value::Int32),
+			FieldType::Uint32 => value.as_uint32().map(Value::Uint32),
+			FieldType::Sint64 => value.as_sint64().map(Value::Int64),
+			FieldType::Sint32 => value.as_sint32().map(Value::Int32),
+			FieldType::Fixed64 => value.as_fixed64().map(Value::Uint64),
+			FieldType::Fixed32 => value.as_fixed32().map(Value::Uint32),
+			FieldType::Sfixed64 => value.as_sfixed64().map(Value::Int64),
+			FieldType::Sfixed32 => value.as_sfixed32().map(Value::Int32),
+			FieldType::Bool => value.as_bool().map(Value::Bool),
+			FieldType::String => value.as_str().map(Value::String),
+			FieldType::Bytes => value.as_bytes().map(Value::Bytes),
+			FieldType::Enum(names) => value.as_int32().map(|value| Value::Enum(value, names)),
+			FieldType::Message(fields) => {
+				let chunk = value
+					.as_bytes()
+					.map_err(|err| err.in_field(self.number, offset))?;
+				// already at offsets in the whole buffer
+				return Ok(Value::Message(decode(&[(offset, chunk)], fields)?));
+			}
+		};
+		value.map_err(|err| err.in_field(self.number, offset))
+	}
+}
+
+/// A field value decoded according to its `FieldType`.
+pub(crate) enum Value<'a> {
+	Double(f64),
+	Float(f32),
+	Int64(i64),
+	Uint64(u64),
+	Int32(i32),
+	Uint32(u32),
+	Bool(bool),
+	String(&'a str),
+	Bytes(&'a [u8]),
+	Enum(i32, &'static [(&'static str, i32)]),
+	Message(Vec<DecodedField<'a>>),
+}
+
+impl Value<'_> {
+	/// Whether proto3 would leave this value off the wire. Messages are never
+	/// default, as they are only present when set.
+	fn is_default(&self) -> bool {
+		match self {
+			Value::Double(v) => v.to_bits() == 0,
+			Value::Float(v) => v.to_bits() == 0,
+			Value::Int64(v) => *v == 0,
+			Value::Uint64(v) => *v == 0,
+			Value::Int32(v) | Value::Enum(v, _) => *v == 0,
+			Value::Uint32(v) => *v == 0,
+			Value::Bool(v) => !v,
+			Value::String(v) => v.is_empty(),
+			Value::Bytes(v) => v.is_empty(),
+			Value::Message(_) => false,
+		}
+	}
+}
+
+/// The values of a field present in a message. Singular fields have exactly
+/// one: the last on the wire.
+pub(crate) struct DecodedField<'a> {
+	pub desc: &'static FieldDesc,
+	pub values: Vec<Value<'a>>,
+}
+
+/// Decode the concatenation of `chunks` as a message with `fields`, in the
+/// order they are described. Singular fields holding their default value
+/// and fields that are not described are left out.
+///
+/// Each chunk comes with its offset in the buffer being decoded, which error
+/// offsets are relative to.
+pub(crate) fn decode<'a>(
+	chunks: &[(usize, &'a [u8])],
+	fields: &'static [FieldDesc],
+) -> Result<Vec<DecodedField<'a>>, DecodeError> {
+	let start = chunks.first().map_or(0, |&(offset, _)| offset);
+	let _level = Level::enter().map_err(|err| err.offset_by(start))?;
+	// each value with the offset it starts at
+	let mut found: Vec<Vec<(usize, FieldValue<'a>)>> = fields.iter().map(|_| Vec::new()).collect();
+	for &(base, chunk) in chunks {
+		let mut data = chunk;
+		while !data.is_empty() {
+			let field_start = base + chunk.len() - data.len();
+			let (field, remainder) = parse_field(data).map_err(|err| err.offset_by(field_start))?;
+			let value_offset = field_start + value_start(data, &field.value, remainder);
+			data = remainder;
+			let Some(i) = fields.iter().position(|f| f.number == field.field_num) else {
+				continue;
+			};
+			let desc = &fields[i];
+			match desc.field_type.packed_wire_type() {
+				Some(wire_type) if desc.repeated && field.value.wire_type() == WireType::Len => {
+					let mut packed = field.value.packed(wire_type)?;
+					loop {
+						let offset = value_offset + packed.position();
+						let Some(value) = packed.next() else {
+							break;
+						};
+						let value = value.map_err(|err| err.in_field(desc.number, value_offset))?;
+						found[i].push((offset, value));
+					}
+				}
+				_ => found[i].push((value_offset, field.value)),
+			}
+		}
+	}
+
+	let mut decoded = Vec::new();
+	for (desc, found) in fields.iter().zip(found) {
+		let Some((last_offset, last)) = found.last() else {
+			continue;
+		};
+		let values = match desc.field_type {
+			// A singular message sent more than once is merged, which is the
+			// same as decoding the occurrences back to back.
+			FieldType::Message(nested) if !desc.repeated => {
+				let chunks = found
+					.iter()
+					.map(|(offset, value)| {
+						let chunk = value.as_bytes();
+						Ok((
+							*offset,
+							chunk.map_err(|err| err.in_field(desc.number, *offset))?,
+						))
+					})
+					.collect::<Result<Vec<_>, _>>()?;
+				vec![Value::Message(decode(&chunks, nested)?)]
+			}
+			_ if desc.repeated => found
+				.iter()
+				.map(|(offset, value)| desc.value(value, *offset))
+				.collect::<Result<_, _>>()?,
+			_ => {
+				let value = desc.value(last, *last_offset)?;
+				if value.is_default() {
+					continue;
+				}
+				vec![value]
+			}
+		};
+		decoded.push(DecodedField { desc, values });
+	}
+	Ok(decoded)
+}
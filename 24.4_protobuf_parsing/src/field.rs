@@ -0,0 +1,374 @@
+use std::borrow::Cow;
+
+use crate::{
+	DecodeError, Field, FieldValue, ProtoEncode, ProtoMessage, WireType, encode_message_field,
+	encode_tag, encode_varint, message_field_len, tag_len, varint_len,
+};
+
+/// Encoding marker for a type's usual wire format: VARINTs for integers and
+/// `bool`, fixed-width for floating point, `Len` for everything else.
+pub struct Natural;
+
+/// Encoding marker for the fixed-width integer types: `fixed32`, `fixed64`,
+/// `sfixed32` and `sfixed64`.
+pub struct Fixed;
+
+/// Encoding marker for the ZigZag-encoded `sint32` and `sint64` types.
+pub struct ZigZag;
+
+/// A type that `#[derive(ProtoMessage)]` can store a field in, when encoded
+/// as `E`.
+///
+/// Singular fields are only written when `is_default` is false, as proto3
+/// leaves default values off the wire; `Vec`s write every element, packed
+/// into a single `Len` field if the elements are scalars.
+pub trait ProtoField<'a, E = Natural> {
+	/// Merge one occurrence of the field on the wire into `self`.
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError>;
+
+	fn is_default(&self) -> bool;
+
+	/// Append the field with its tag, whether or not it is the default.
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>);
+
+	/// The number of bytes `encode_field` appends.
+	fn encoded_field_len(&self, field_num: u64) -> usize;
+
+	/// Append the field as a singular field, unless it is the default.
+	fn encode_singular(&self, field_num: u64, buf: &mut Vec<u8>) {
+		if !self.is_default() {
+			self.encode_field(field_num, buf);
+		}
+	}
+
+	/// The number of bytes `encode_singular` appends.
+	fn encoded_singular_len(&self, field_num: u64) -> usize {
+		if self.is_default() {
+			0
+		} else {
+			self.encoded_field_len(field_num)
+		}
+	}
+
+	/// For scalar types, the value as it goes on the wire, so that repeated
+	/// fields of them can be packed. `None` for `Len` types and messages.
+	fn packed_value(&self) -> Option<FieldValue<'static>> {
+		None
+	}
+}
+
+/// Implement `ProtoField<'a, $encoding>` for a scalar type, given how to read
+/// it from a `FieldValue` and how to turn it back into one.
+macro_rules! scalar_field {
+	($encoding:ty, $ty:ty, $decode:expr, $encode:expr) => {
+		impl<'a> ProtoField<'a, $encoding> for $ty {
+			fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+				*self = $decode(&value)?;
+				Ok(())
+			}
+
+			// Compared on the wire, so that -0.0 is not mistaken for 0.0.
+			fn is_default(&self) -> bool {
+				$encode(*self) == $encode(<$ty>::default())
+			}
+
+			fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+				Field {
+					field_num,
+					value: $encode(*self),
+				}
+				.encode_to(buf);
+			}
+
+			fn encoded_field_len(&self, field_num: u64) -> usize {
+				tag_len(field_num) + $encode(*self).encoded_len()
+			}
+
+			fn packed_value(&self) -> Option<FieldValue<'static>> {
+				Some($encode(*self))
+			}
+		}
+	};
+}
+
+pub(crate) fn uint32_value(v: u32) -> FieldValue<'static> {
+	FieldValue::Varint(v.into())
+}
+
+pub(crate) fn int64_value(v: i64) -> FieldValue<'static> {
+	FieldValue::Varint(v as u64)
+}
+
+pub(crate) fn int32_value(v: i32) -> FieldValue<'static> {
+	int64_value(v.into())
+}
+
+pub(crate) fn bool_value(v: bool) -> FieldValue<'static> {
+	FieldValue::Varint(v.into())
+}
+
+pub(crate) fn double_value(v: f64) -> FieldValue<'static> {
+	FieldValue::I64(v.to_bits() as i64)
+}
+
+pub(crate) fn float_value(v: f32) -> FieldValue<'static> {
+	FieldValue::I32(v.to_bits() as i32)
+}
+
+pub(crate) fn fixed64_value(v: u64) -> FieldValue<'static> {
+	FieldValue::I64(v as i64)
+}
+
+pub(crate) fn fixed32_value(v: u32) -> FieldValue<'static> {
+	FieldValue::I32(v as i32)
+}
+
+pub(crate) fn sint64_value(v: i64) -> FieldValue<'static> {
+	FieldValue::Varint(((v << 1) ^ (v >> 63)) as u64)
+}
+
+pub(crate) fn sint32_value(v: i32) -> FieldValue<'static> {
+	FieldValue::Varint(((v << 1) ^ (v >> 31)) as u32 as u64)
+}
+
+scalar_field!(Natural, u64, FieldValue::as_u64, FieldValue::Varint);
+scalar_field!(Natural, u32, FieldValue::as_uint32, uint32_value);
+scalar_field!(Natural, i64, FieldValue::as_int64, int64_value);
+scalar_field!(Natural, i32, FieldValue::as_int32, int32_value);
+scalar_field!(Natural, bool, FieldValue::as_bool, bool_value);
+scalar_field!(Natural, f64, FieldValue::as_f64, double_value);
+scalar_field!(Natural, f32, FieldValue::as_f32, float_value);
+scalar_field!(Fixed, u64, FieldValue::as_fixed64, fixed64_value);
+scalar_field!(Fixed, i64, FieldValue::as_sfixed64, FieldValue::I64);
+scalar_field!(Fixed, u32, FieldValue::as_fixed32, fixed32_value);
+scalar_field!(Fixed, i32, FieldValue::as_sfixed32, FieldValue::I32);
+scalar_field!(ZigZag, i64, FieldValue::as_sint64, sint64_value);
+scalar_field!(ZigZag, i32, FieldValue::as_sint32, sint32_value);
+
+impl<'a> ProtoField<'a> for &'a [u8] {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		*self = value.as_bytes()?;
+		Ok(())
+	}
+
+	fn is_default(&self) -> bool {
+		self.is_empty()
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		Field {
+			field_num,
+			value: FieldValue::Len(self),
+		}
+		.encode_to(buf);
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		tag_len(field_num) + FieldValue::Len(self).encoded_len()
+	}
+}
+
+impl<'a> ProtoField<'a> for &'a str {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		*self = value.as_str()?;
+		Ok(())
+	}
+
+	fn is_default(&self) -> bool {
+		self.is_empty()
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		self.as_bytes().encode_field(field_num, buf);
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		self.as_bytes().encoded_field_len(field_num)
+	}
+}
+
+/// Strings and bytes that borrow from the input when decoded, and can be
+/// made owned with `IntoOwned`.
+impl<'a> ProtoField<'a> for Cow<'a, str> {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		*self = Cow::Borrowed(value.as_str()?);
+		Ok(())
+	}
+
+	fn is_default(&self) -> bool {
+		self.is_empty()
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		self.as_bytes().encode_field(field_num, buf);
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		self.as_bytes().encoded_field_len(field_num)
+	}
+}
+
+impl<'a> ProtoField<'a> for Cow<'a, [u8]> {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		*self = Cow::Borrowed(value.as_bytes()?);
+		Ok(())
+	}
+
+	fn is_default(&self) -> bool {
+		self.is_empty()
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		(&**self).encode_field(field_num, buf);
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		(&**self).encoded_field_len(field_num)
+	}
+}
+
+/// Embedded messages, stored as `Len` fields. A message seen more than once
+/// is merged field by field rather than replaced.
+///
+/// A message is the default when it encodes to nothing, so singular fields
+/// work out the length once for both checks; otherwise every level of nesting
+/// would double the work.
+impl<'a, M: ProtoMessage<'a> + ProtoEncode> ProtoField<'a> for M {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		self.merge_from(value.as_bytes()?)
+	}
+
+	fn is_default(&self) -> bool {
+		self.encoded_len() == 0
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		encode_message_field(field_num, self, buf);
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		message_field_len(field_num, self)
+	}
+
+	fn encode_singular(&self, field_num: u64, buf: &mut Vec<u8>) {
+		let len = self.encoded_len();
+		if len > 0 {
+			encode_tag(field_num, WireType::Len, buf);
+			encode_varint(len as u64, buf);
+			self.encode_to(buf);
+		}
+	}
+
+	fn encoded_singular_len(&self, field_num: u64) -> usize {
+		match self.encoded_len() {
+			0 => 0,
+			len => tag_len(field_num) + varint_len(len as u64) + len,
+		}
+	}
+}
+
+/// Boxed messages, which is how a message can hold one of its own type.
+impl<'a, M: ProtoMessage<'a>> ProtoMessage<'a> for Box<M> {
+	fn add_field(&mut self, field: Field<'a>) -> Result<(), DecodeError> {
+		(**self).add_field(field)
+	}
+}
+
+impl<M: ProtoEncode + ?Sized> ProtoEncode for Box<M> {
+	fn encode_to(&self, buf: &mut Vec<u8>) {
+		(**self).encode_to(buf);
+	}
+
+	fn encoded_len(&self) -> usize {
+		(**self).encoded_len()
+	}
+}
+
+/// Fields that may be absent, such as the `Option<Box<M>>` of a message that
+/// holds one of its own type. `None` is left off the wire; `Some` is written
+/// even when it holds the default.
+impl<'a, E, T: ProtoField<'a, E> + Default> ProtoField<'a, E> for Option<T> {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		self.get_or_insert_with(T::default).merge_value(value)
+	}
+
+	fn is_default(&self) -> bool {
+		self.is_none()
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		if let Some(value) = self {
+			value.encode_field(field_num, buf);
+		}
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		self.as_ref()
+			.map_or(0, |value| value.encoded_field_len(field_num))
+	}
+}
+
+/// Repeated fields: every occurrence on the wire adds an element, or for
+/// scalars, every element packed into the occurrence.
+impl<'a, E, T: ProtoField<'a, E> + Default> ProtoField<'a, E> for Vec<T> {
+	fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+		// Packed and unpacked occurrences may be mixed for the same field.
+		if let (Some(element), FieldValue::Len(_)) = (T::default().packed_value(), &value) {
+			let mut packed = value.packed(element.wire_type())?;
+			loop {
+				let position = packed.position();
+				let Some(value) = packed.next() else {
+					return Ok(());
+				};
+				let mut element = T::default();
+				element
+					.merge_value(value?)
+					.map_err(|err| err.offset_by(position))?;
+				self.push(element);
+			}
+		}
+		let mut element = T::default();
+		element.merge_value(value)?;
+		self.push(element);
+		Ok(())
+	}
+
+	fn is_default(&self) -> bool {
+		self.is_empty()
+	}
+
+	fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+		match packed_len(self) {
+			Some(len) if !self.is_empty() => {
+				encode_tag(field_num, WireType::Len, buf);
+				encode_varint(len as u64, buf);
+				for value in self.iter().filter_map(|element| element.packed_value()) {
+					value.encode_to(buf);
+				}
+			}
+			_ => {
+				for element in self {
+					element.encode_field(field_num, buf);
+				}
+			}
+		}
+	}
+
+	fn encoded_field_len(&self, field_num: u64) -> usize {
+		match packed_len(self) {
+			Some(len) if !self.is_empty() => tag_len(field_num) + varint_len(len as u64) + len,
+			_ => self
+				.iter()
+				.map(|element| element.encoded_field_len(field_num))
+				.sum(),
+		}
+	}
+}
+
+/// The payload length of `elements` packed together, if they are scalars.
+fn packed_len<'a, E, T: ProtoField<'a, E>>(elements: &[T]) -> Option<usize> {
+	elements
+		.iter()
+		.map(|element| Some(element.packed_value()?.encoded_len()))
+		.sum()
+}
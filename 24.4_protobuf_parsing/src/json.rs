@@ -0,0 +1,249 @@
+//! The canonical proto3 JSON mapping.
+//!
+//! Fields are named in lowerCamelCase and left out when they hold their
+//! default value. 64-bit integers are strings, since JSON numbers are often
+//! read as doubles, and bytes are base64.
+
+use std::fmt::Write;
+
+use crate::descriptor::{DecodedField, Value, decode};
+use crate::{DecodeError, FieldDesc, ProtoDescribe, ProtoEncode};
+
+/// Format `message` as JSON on a single line.
+pub fn to_json<M: ProtoEncode + ProtoDescribe>(message: &M) -> String {
+	format_json(&message.encode(), M::FIELDS).expect("a message decodes as its own fields")
+}
+
+/// Format the message in `data`, which has `fields`, as JSON on a single
+/// line. Fields that are not described are left out.
+pub fn format_json(data: &[u8], fields: &'static [FieldDesc]) -> Result<String, DecodeError> {
+	let mut out = String::new();
+	write_message(&mut out, &decode(&[(0, data)], fields)?);
+	Ok(out)
+}
+
+/// The JSON name of a field: `phone_number` becomes `phoneNumber`.
+fn json_name(name: &str) -> String {
+	let mut out = String::new();
+	let mut upper = false;
+	for c in name.chars() {
+		if c == '_' {
+			upper = true;
+		} else if upper {
+			out.push(c.to_ascii_uppercase());
+			upper = false;
+		} else {
+			out.push(c);
+		}
+	}
+	out
+}
+
+fn write_message(out: &mut String, fields: &[DecodedField]) {
+	out.push('{');
+	for (i, field) in fields.iter().enumerate() {
+		if i > 0 {
+			out.push(',');
+		}
+		write_string(out, &json_name(field.desc.name));
+		out.push(':');
+		if field.desc.repeated {
+			out.push('[');
+			for (i, value) in field.values.iter().enumerate() {
+				if i > 0 {
+					out.push(',');
+				}
+				write_value(out, value);
+			}
+			out.push(']');
+		} else {
+			write_value(out, &field.values[0]);
+		}
+	}
+	out.push('}');
+}
+
+fn write_value(out: &mut String, value: &Value) {
+	// Writing to a String cannot fail.
+	match value {
+		Value::Double(v) => write_float(out, *v, v.to_string()),
+		Value::Float(v) => write_float(out, (*v).into(), v.to_string()),
+		Value::Int64(v) => write!(out, "\"{v}\"").unwrap(),
+		Value::Uint64(v) => write!(out, "\"{v}\"").unwrap(),
+		Value::Int32(v) => write!(out, "{v}").unwrap(),
+		Value::Uint32(v) => write!(out, "{v}").unwrap(),
+		Value::Bool(v) => write!(out, "{v}").unwrap(),
+		Value::String(v) => write_string(out, v),
+		Value::Bytes(v) => {
+			out.push('"');
+			write_base64(out, v);
+			out.push('"');
+		}
+		Value::Enum(v, names) => match names.iter().find(|(_, value)| value == v) {
+			Some((name, _)) => write_string(out, name),
+			None => write!(out, "{v}").unwrap(),
+		},
+		Value::Message(fields) => write_message(out, fields),
+	}
+}
+
+/// Write a float given its shortest decimal form, or as one of the strings
+/// JSON uses for the values it has no numbers for.
+fn write_float(out: &mut String, value: f64, text: String) {
+	if value.is_nan() {
+		out.push_str("\"NaN\"");
+	} else if value == f64::INFINITY {
+		out.push_str("\"Infinity\"");
+	} else if value == f64::NEG_INFINITY {
+		out.push_str("\"-Infinity\"");
+	} else {
+		out.push_str(&text);
+	}
+}
+
+fn write_string(out: &mut String, text: &str) {
+	out.push('"');
+	for c in text.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+/// Standard base64, with padding.
+fn write_base64(out: &mut String, data: &[u8]) {
+	const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	for chunk in data.chunks(3) {
+		let bits = chunk
+			.iter()
+			.enumerate()
+			.fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+		for i in 0..4 {
+			if i <= chunk.len() {
+				out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+			} else {
+				out.push('=');
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::text::parse_text;
+	use crate::{FULL_PERSON, FieldType, Person, parse_message};
+
+	#[test]
+	fn test_person_json() {
+		let person: Person = parse_message(FULL_PERSON).unwrap();
+		assert_eq!(
+			to_json(&person),
+			r#"{"name":"maxwell","id":"42","phone":[{"number":"+1202-555-1212","type":"home"},{"number":"+1800-867-5308","type":"mobile"}]}"#
+		);
+		assert_eq!(to_json(&Person::default()), "{}");
+	}
+
+	const COLOR: &[(&str, i32)] = &[("COLOR_UNSPECIFIED", 0), ("COLOR_RED", 1)];
+
+	const SCALARS: &[FieldDesc] = &[
+		FieldDesc {
+			name: "small_int",
+			number: 1,
+			field_type: FieldType::Sint32,
+			repeated: false,
+		},
+		FieldDesc {
+			name: "big_int",
+			number: 2,
+			field_type: FieldType::Sfixed64,
+			repeated: false,
+		},
+		FieldDesc {
+			name: "ratios",
+			number: 3,
+			field_type: FieldType::Float,
+			repeated: true,
+		},
+		FieldDesc {
+			name: "raw_data",
+			number: 4,
+			field_type: FieldType::Bytes,
+			repeated: true,
+		},
+		FieldDesc {
+			name: "colors",
+			number: 5,
+			field_type: FieldType::Enum(COLOR),
+			repeated: true,
+		},
+		FieldDesc {
+			name: "note",
+			number: 6,
+			field_type: FieldType::String,
+			repeated: false,
+		},
+	];
+
+	#[test]
+	fn test_scalar_json() {
+		let data = parse_text(
+			r#"
+			small_int: -3
+			big_int: -9007199254740993
+			ratios: [0.5, inf, -inf, nan]
+			raw_data: ["", "f", "fo", "foo", "\xff\x00"]
+			colors: [COLOR_RED, 7]
+			note: "tab\there \"quoted\" \x01"
+			"#,
+			SCALARS,
+		)
+		.unwrap();
+		assert_eq!(
+			format_json(&data, SCALARS).unwrap(),
+			concat!(
+				r#"{"smallInt":-3,"bigInt":"-9007199254740993","#,
+				r#""ratios":[0.5,"Infinity","-Infinity","NaN"],"#,
+				r#""rawData":["","Zg==","Zm8=","Zm9v","/wA="],"#,
+				r#""colors":["COLOR_RED",7],"note":"tab\there \"quoted\" \u0001"}"#
+			)
+		);
+	}
+
+	#[test]
+	fn test_json_packed_and_defaults() {
+		// `small_int` set to 0 explicitly, then `colors` packed.
+		let data = [0x08, 0x00, 0x2a, 0x02, 0x00, 0x01];
+		assert_eq!(
+			format_json(&data, SCALARS).unwrap(),
+			r#"{"colors":["COLOR_UNSPECIFIED","COLOR_RED"]}"#
+		);
+	}
+
+	#[test]
+	fn test_json_invalid_value() {
+		assert_eq!(
+			format_json(&[0x32, 0x01, 0xff], SCALARS),
+			Err(DecodeError::InvalidUtf8 {
+				field_num: 6,
+				offset: 2
+			})
+		);
+		// `colors` packed: 1, then 2^31, which is no `int32`
+		assert_eq!(
+			format_json(&[0x2a, 0x06, 0x01, 0x80, 0x80, 0x80, 0x80, 0x08], SCALARS),
+			Err(DecodeError::OutOfRange {
+				field_num: 5,
+				offset: 3,
+				expected: "int32"
+			})
+		);
+	}
+}
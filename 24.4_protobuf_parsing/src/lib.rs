@@ -2,195 +2,758 @@
 
 // exercise: https://google.github.io/comprehensive-rust/lifetimes/exercise.html
 
+use std::borrow::Cow;
+use std::cell::Cell;
+
+use thiserror::Error;
+
+// Lets `#[derive(ProtoMessage)]` output name this crate from inside it too.
+extern crate self as protobuf_parsing;
+
+pub mod codegen;
+mod descriptor;
+mod field;
+pub mod json;
+mod map;
+mod oneof;
+mod owned;
+mod packed;
+#[cfg(test)]
+mod proptests;
+pub mod raw;
+pub mod stream;
+pub mod text;
+
+pub use descriptor::{FieldDesc, FieldType, ProtoDescribe};
+pub use field::{Fixed, Natural, ProtoField, ZigZag};
+pub use map::Map;
+pub use oneof::ProtoOneof;
+#[doc(hidden)]
+pub use oneof::distinct_fields;
+pub use owned::IntoOwned;
+pub use packed::Packed;
+pub use protobuf_parsing_derive::{ProtoMessage, ProtoOneof};
+
 /// A wire type as seen on the wire.
-enum WireType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireType {
 	/// The Varint WireType indicates the value is a single VARINT.
-	Varint,
-	// The I64 WireType indicates that the value is precisely 8 bytes in
-	// little-endian order containing a 64-bit signed integer or double type.
-	//I64,  -- not needed for this exercise
+	Varint = 0,
+	/// The I64 WireType indicates that the value is precisely 8 bytes in
+	/// little-endian order containing a 64-bit signed integer or double type.
+	I64 = 1,
 	/// The Len WireType indicates that the value is a length represented as a
 	/// VARINT followed by exactly that number of bytes.
-	Len,
-	// The I32 WireType indicates that the value is precisely 4 bytes in
-	// little-endian order containing a 32-bit signed integer or float type.
-	//I32,  -- not needed for this exercise
+	Len = 2,
+	/// The I32 WireType indicates that the value is precisely 4 bytes in
+	/// little-endian order containing a 32-bit signed integer or float type.
+	I32 = 5,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 /// A field's value, typed based on the wire type.
-enum FieldValue<'a> {
+pub enum FieldValue<'a> {
 	Varint(u64),
-	//I64(i64),  -- not needed for this exercise
+	I64(i64),
 	Len(&'a [u8]),
-	//I32(i32),  -- not needed for this exercise
+	I32(i32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 /// A field, containing the field number and its value.
-struct Field<'a> {
-	field_num: u64,
-	value: FieldValue<'a>,
+pub struct Field<'a> {
+	pub field_num: u64,
+	pub value: FieldValue<'a>,
+}
+
+/// An error encountered while decoding a message.
+///
+/// Offsets are in bytes from the start of the buffer passed to
+/// `parse_message`, including for errors inside nested messages.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+	#[error("truncated varint at byte {offset}")]
+	TruncatedVarint { offset: usize },
+	#[error("varint at byte {offset} overflows u64")]
+	VarintOverflow { offset: usize },
+	#[error("field {field_num} at byte {offset}: invalid wire type {wire_type}")]
+	InvalidWireType {
+		field_num: u64,
+		offset: usize,
+		wire_type: u64,
+	},
+	#[error("field {field_num} at byte {offset}: {len} bytes expected, input ends")]
+	TruncatedField {
+		field_num: u64,
+		offset: usize,
+		len: u64,
+	},
+	#[error("field {field_num} at byte {offset}: expected a `{expected}` field")]
+	UnexpectedWireType {
+		field_num: u64,
+		offset: usize,
+		expected: &'static str,
+	},
+	#[error("field {field_num} at byte {offset}: invalid UTF-8")]
+	InvalidUtf8 { field_num: u64, offset: usize },
+	#[error("field {field_num} at byte {offset}: value out of range for `{expected}`")]
+	OutOfRange {
+		field_num: u64,
+		offset: usize,
+		expected: &'static str,
+	},
+	#[error("field {field_num} at byte {offset}: unknown enum value {value}")]
+	UnknownEnumValue {
+		field_num: u64,
+		offset: usize,
+		value: i32,
+	},
+	#[error("message at byte {offset} is nested more than {MAX_DEPTH} deep")]
+	TooDeep { offset: usize },
 }
 
-trait ProtoMessage<'a>: Default {
-	fn add_field(&mut self, field: Field<'a>);
+impl DecodeError {
+	/// Move the error's offset from the start of a field's value to the start
+	/// of the enclosing buffer, and attach the field number if it was raised
+	/// without one (field numbers start at 1, so 0 means "not yet known").
+	fn in_field(mut self, field_num: u64, base: usize) -> Self {
+		match &mut self {
+			DecodeError::TruncatedVarint { offset }
+			| DecodeError::VarintOverflow { offset }
+			| DecodeError::TooDeep { offset } => {
+				*offset += base;
+			}
+			DecodeError::InvalidWireType {
+				field_num: num,
+				offset,
+				..
+			}
+			| DecodeError::TruncatedField {
+				field_num: num,
+				offset,
+				..
+			}
+			| DecodeError::UnexpectedWireType {
+				field_num: num,
+				offset,
+				..
+			}
+			| DecodeError::InvalidUtf8 {
+				field_num: num,
+				offset,
+			}
+			| DecodeError::OutOfRange {
+				field_num: num,
+				offset,
+				..
+			}
+			| DecodeError::UnknownEnumValue {
+				field_num: num,
+				offset,
+				..
+			} => {
+				*offset += base;
+				if *num == 0 {
+					*num = field_num;
+				}
+			}
+		}
+		self
+	}
+
+	/// Move the error's offset by `base` bytes.
+	fn offset_by(self, base: usize) -> Self {
+		self.in_field(0, base)
+	}
+}
+
+pub trait ProtoMessage<'a>: Default {
+	/// Merge one field from the wire into `self`. Following the spec,
+	/// singular scalars keep the last value seen, repeated fields append,
+	/// and embedded messages merge field by field.
+	fn add_field(&mut self, field: Field<'a>) -> Result<(), DecodeError>;
+
+	/// Merge the message encoded in `data` into `self`, calling `add_field`
+	/// for each field. This gives the same result as parsing the encoding
+	/// `self` came from with `data` appended to it.
+	///
+	/// The entire input is consumed. Error offsets are relative to `data`.
+	/// Messages nested more than `MAX_DEPTH` deep are an error.
+	fn merge_from(&mut self, data: &'a [u8]) -> Result<(), DecodeError> {
+		let _level = Level::enter()?;
+		let input = data;
+		let mut data = data;
+		while !data.is_empty() {
+			let field_start = input.len() - data.len();
+			let (field, remainder) = parse_field(data).map_err(|err| err.offset_by(field_start))?;
+			// Errors from `add_field` are relative to the field's value.
+			let value_offset = field_start + value_start(data, &field.value, remainder);
+			let field_num = field.field_num;
+			self.add_field(field)
+				.map_err(|err| err.in_field(field_num, value_offset))?;
+			data = remainder;
+		}
+		Ok(())
+	}
+}
+
+/// How deep messages can be nested, counting the outermost one, as in the
+/// upstream protobuf implementations. Deeper input would overflow the stack.
+pub const MAX_DEPTH: usize = 100;
+
+thread_local! {
+	/// How many messages are being decoded on this thread, each inside the
+	/// one before.
+	static DEPTH: Cell<usize> = const { Cell::new(0) };
 }
 
-impl From<u64> for WireType {
-	fn from(value: u64) -> Self {
+/// A message being decoded, counted for as long as it is alive.
+struct Level(());
+
+impl Level {
+	/// Start decoding a message inside the ones being decoded, or fail with
+	/// `TooDeep` at offset 0 if that is too deep.
+	fn enter() -> Result<Level, DecodeError> {
+		let depth = DEPTH.get();
+		if depth >= MAX_DEPTH {
+			return Err(DecodeError::TooDeep { offset: 0 });
+		}
+		DEPTH.set(depth + 1);
+		Ok(Level(()))
+	}
+}
+
+impl Drop for Level {
+	fn drop(&mut self) {
+		DEPTH.set(DEPTH.get() - 1);
+	}
+}
+
+/// The encoding counterpart of `ProtoMessage`.
+pub trait ProtoEncode {
+	/// Append the message's fields to `buf`.
+	fn encode_to(&self, buf: &mut Vec<u8>);
+
+	/// The number of bytes `encode_to` appends.
+	fn encoded_len(&self) -> usize;
+
+	fn encode(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(self.encoded_len());
+		self.encode_to(&mut buf);
+		buf
+	}
+}
+
+impl TryFrom<u64> for WireType {
+	/// The unrecognized wire type.
+	type Error = u64;
+
+	fn try_from(value: u64) -> Result<Self, u64> {
 		match value {
-			0 => WireType::Varint,
-			//1 => WireType::I64,  -- not needed for this exercise
-			2 => WireType::Len,
-			//5 => WireType::I32,  -- not needed for this exercise
-			_ => panic!("Invalid wire type: {value}"),
+			0 => Ok(WireType::Varint),
+			1 => Ok(WireType::I64),
+			2 => Ok(WireType::Len),
+			5 => Ok(WireType::I32),
+			_ => Err(value),
 		}
 	}
 }
 
 impl<'a> FieldValue<'a> {
-	fn as_str(&self) -> &'a str {
-		let FieldValue::Len(data) = self else {
-			panic!("Expected string to be a `Len` field");
-		};
-		std::str::from_utf8(data).expect("Invalid string")
+	/// Error for a value that does not have the `expected` wire type. The
+	/// field number and offset are filled in by `parse_message`.
+	fn unexpected(expected: &'static str) -> DecodeError {
+		DecodeError::UnexpectedWireType {
+			field_num: 0,
+			offset: 0,
+			expected,
+		}
+	}
+
+	/// Error for a value that does not fit the field's type.
+	fn out_of_range(expected: &'static str) -> DecodeError {
+		DecodeError::OutOfRange {
+			field_num: 0,
+			offset: 0,
+			expected,
+		}
 	}
 
-	fn as_bytes(&self) -> &'a [u8] {
+	pub fn wire_type(&self) -> WireType {
+		match self {
+			FieldValue::Varint(_) => WireType::Varint,
+			FieldValue::I64(_) => WireType::I64,
+			FieldValue::Len(_) => WireType::Len,
+			FieldValue::I32(_) => WireType::I32,
+		}
+	}
+
+	/// Append the value as it follows the tag, with a length prefix for `Len`.
+	pub fn encode_to(&self, buf: &mut Vec<u8>) {
+		match self {
+			FieldValue::Varint(value) => encode_varint(*value, buf),
+			FieldValue::I64(value) => buf.extend_from_slice(&value.to_le_bytes()),
+			FieldValue::Len(data) => {
+				encode_varint(data.len() as u64, buf);
+				buf.extend_from_slice(data);
+			}
+			FieldValue::I32(value) => buf.extend_from_slice(&value.to_le_bytes()),
+		}
+	}
+
+	pub fn encoded_len(&self) -> usize {
+		match self {
+			FieldValue::Varint(value) => varint_len(*value),
+			FieldValue::I64(_) => 8,
+			FieldValue::Len(data) => varint_len(data.len() as u64) + data.len(),
+			FieldValue::I32(_) => 4,
+		}
+	}
+
+	pub fn as_str(&self) -> Result<&'a str, DecodeError> {
+		std::str::from_utf8(self.as_bytes()?).map_err(|err| DecodeError::InvalidUtf8 {
+			field_num: 0,
+			offset: err.valid_up_to(),
+		})
+	}
+
+	pub fn as_bytes(&self) -> Result<&'a [u8], DecodeError> {
 		let FieldValue::Len(data) = self else {
-			panic!("Expected bytes to be a `Len` field");
+			return Err(Self::unexpected("Len"));
 		};
-		data
+		Ok(data)
 	}
 
-	fn as_u64(&self) -> u64 {
+	pub fn as_u64(&self) -> Result<u64, DecodeError> {
 		let FieldValue::Varint(value) = self else {
-			panic!("Expected `u64` to be a `Varint` field");
+			return Err(Self::unexpected("Varint"));
+		};
+		Ok(*value)
+	}
+
+	pub fn as_uint32(&self) -> Result<u32, DecodeError> {
+		u32::try_from(self.as_u64()?).map_err(|_| Self::out_of_range("uint32"))
+	}
+
+	/// `int64` values are stored in two's complement.
+	pub fn as_int64(&self) -> Result<i64, DecodeError> {
+		Ok(self.as_u64()? as i64)
+	}
+
+	/// `int32` values are sign-extended to 64 bits on the wire.
+	pub fn as_int32(&self) -> Result<i32, DecodeError> {
+		i32::try_from(self.as_int64()?).map_err(|_| Self::out_of_range("int32"))
+	}
+
+	/// `sint64` values are ZigZag-encoded: 0, -1, 1, -2... become 0, 1, 2, 3...
+	pub fn as_sint64(&self) -> Result<i64, DecodeError> {
+		let n = self.as_u64()?;
+		Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+	}
+
+	pub fn as_sint32(&self) -> Result<i32, DecodeError> {
+		let n = u32::try_from(self.as_u64()?).map_err(|_| Self::out_of_range("sint32"))?;
+		Ok((n >> 1) as i32 ^ -((n & 1) as i32))
+	}
+
+	pub fn as_bool(&self) -> Result<bool, DecodeError> {
+		match self.as_u64()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			_ => Err(Self::out_of_range("bool")),
+		}
+	}
+
+	/// Convert an enum value, which goes on the wire as an `int32`.
+	///
+	/// This is for closed enums, where values without a variant are an
+	/// error; open enums can keep the `as_int32` value instead.
+	pub fn as_enum<E: TryFrom<i32>>(&self) -> Result<E, DecodeError> {
+		let value = self.as_int32()?;
+		E::try_from(value).map_err(|_| DecodeError::UnknownEnumValue {
+			field_num: 0,
+			offset: 0,
+			value,
+		})
+	}
+
+	pub fn as_i64(&self) -> Result<i64, DecodeError> {
+		let FieldValue::I64(value) = self else {
+			return Err(Self::unexpected("I64"));
+		};
+		Ok(*value)
+	}
+
+	pub fn as_i32(&self) -> Result<i32, DecodeError> {
+		let FieldValue::I32(value) = self else {
+			return Err(Self::unexpected("I32"));
 		};
-		*value
+		Ok(*value)
+	}
+
+	/// `double` fields are stored as the IEEE 754 bits of an `I64` field.
+	pub fn as_f64(&self) -> Result<f64, DecodeError> {
+		Ok(f64::from_bits(self.as_i64()? as u64))
+	}
+
+	/// `float` fields are stored as the IEEE 754 bits of an `I32` field.
+	pub fn as_f32(&self) -> Result<f32, DecodeError> {
+		Ok(f32::from_bits(self.as_i32()? as u32))
+	}
+
+	pub fn as_fixed64(&self) -> Result<u64, DecodeError> {
+		Ok(self.as_i64()? as u64)
+	}
+
+	pub fn as_fixed32(&self) -> Result<u32, DecodeError> {
+		Ok(self.as_i32()? as u32)
+	}
+
+	pub fn as_sfixed64(&self) -> Result<i64, DecodeError> {
+		self.as_i64()
+	}
+
+	pub fn as_sfixed32(&self) -> Result<i32, DecodeError> {
+		self.as_i32()
 	}
 }
 
+/// The longest VARINT that fits in a u64: 10 groups of 7 bits.
+const MAX_VARINT_LEN: usize = 10;
+
 /// Parse a VARINT, returning the parsed value and the remaining bytes.
-fn parse_varint(data: &[u8]) -> (u64, &[u8]) {
-	for i in 0..7 {
-		let Some(b) = data.get(i) else {
-			panic!("Not enough bytes for varint");
-		};
+pub fn parse_varint(data: &[u8]) -> Result<(u64, &[u8]), DecodeError> {
+	let mut value = 0u64;
+	for (i, &b) in data.iter().enumerate().take(MAX_VARINT_LEN) {
+		// The tenth byte only has room for the top bit of a u64, and
+		// must not be followed by another byte.
+		if i == MAX_VARINT_LEN - 1 && b > 1 {
+			return Err(DecodeError::VarintOverflow { offset: 0 });
+		}
+		value |= u64::from(b & 0x7f) << (7 * i);
 		if b & 0x80 == 0 {
-			// This is the last byte of the VARINT, so convert it to
-			// a u64 and return it.
-			let mut value = 0u64;
-			for b in data[..=i].iter().rev() {
-				value = (value << 7) | (b & 0x7f) as u64;
-			}
-			return (value, &data[i + 1..]);
+			// This is the last byte of the VARINT.
+			return Ok((value, &data[i + 1..]));
 		}
 	}
 
-	// More than 7 bytes is invalid.
-	panic!("Too many bytes for varint");
+	// The input ran out before the last byte.
+	Err(DecodeError::TruncatedVarint { offset: 0 })
+}
+
+/// Where `value`, parsed from the field at the start of `data` with
+/// `remainder` left over, starts in `data`: for `Len` fields the payload after
+/// its length, for the others the byte after the tag.
+pub(crate) fn value_start(data: &[u8], value: &FieldValue, remainder: &[u8]) -> usize {
+	match value {
+		FieldValue::Len(payload) => data.len() - remainder.len() - payload.len(),
+		// The tag ends at its first byte without the continuation bit.
+		_ => data
+			.iter()
+			.position(|&b| b & 0x80 == 0)
+			.map_or(0, |i| i + 1),
+	}
+}
+
+/// Append `value` as a VARINT, least significant group first.
+pub fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+	while value >= 0x80 {
+		buf.push((value as u8) | 0x80);
+		value >>= 7;
+	}
+	buf.push(value as u8);
+}
+
+/// The number of bytes `encode_varint` appends for `value`.
+pub fn varint_len(value: u64) -> usize {
+	(64 - value.leading_zeros() as usize).div_ceil(7).max(1)
+}
+
+/// Split `len` bytes off the front of `data`, or fail with `TruncatedField`.
+fn split_value(data: &[u8], len: u64) -> Result<(&[u8], &[u8]), DecodeError> {
+	match usize::try_from(len) {
+		Ok(n) if n <= data.len() => Ok(data.split_at(n)),
+		_ => Err(DecodeError::TruncatedField {
+			field_num: 0,
+			offset: 0,
+			len,
+		}),
+	}
+}
+
+/// Split `N` little-endian bytes off the front of `data`.
+fn parse_fixed<const N: usize>(data: &[u8]) -> Result<([u8; N], &[u8]), DecodeError> {
+	let (bytes, remainder) = split_value(data, N as u64)?;
+	Ok((bytes.try_into().unwrap(), remainder))
 }
 
 /// Convert a tag into a field number and a WireType.
-fn unpack_tag(tag: u64) -> (u64, WireType) {
+fn unpack_tag(tag: u64) -> Result<(u64, WireType), DecodeError> {
 	let field_num = tag >> 3;
-	let wire_type = WireType::from(tag & 0x7);
-	(field_num, wire_type)
+	let wire_type =
+		WireType::try_from(tag & 0x7).map_err(|wire_type| DecodeError::InvalidWireType {
+			field_num,
+			offset: 0,
+			wire_type,
+		})?;
+	Ok((field_num, wire_type))
+}
+
+/// Append the tag for a field: its number and WireType packed into a VARINT.
+pub fn encode_tag(field_num: u64, wire_type: WireType, buf: &mut Vec<u8>) {
+	encode_varint(field_num << 3 | wire_type as u64, buf);
+}
+
+pub fn tag_len(field_num: u64) -> usize {
+	varint_len(field_num << 3)
+}
+
+impl Field<'_> {
+	pub fn encode_to(&self, buf: &mut Vec<u8>) {
+		encode_tag(self.field_num, self.value.wire_type(), buf);
+		self.value.encode_to(buf);
+	}
+
+	pub fn encoded_len(&self) -> usize {
+		tag_len(self.field_num) + self.value.encoded_len()
+	}
+}
+
+/// Append `message` as an embedded `Len` field.
+pub fn encode_message_field<M: ProtoEncode>(field_num: u64, message: &M, buf: &mut Vec<u8>) {
+	encode_tag(field_num, WireType::Len, buf);
+	encode_varint(message.encoded_len() as u64, buf);
+	message.encode_to(buf);
+}
+
+pub fn message_field_len<M: ProtoEncode>(field_num: u64, message: &M) -> usize {
+	let len = message.encoded_len();
+	tag_len(field_num) + varint_len(len as u64) + len
 }
 
 /// Parse a field, returning the remaining bytes
-fn parse_field(data: &[u8]) -> (Field, &[u8]) {
-	let (tag, remainder) = parse_varint(data);
-	let (field_num, wire_type) = unpack_tag(tag);
+pub fn parse_field(data: &[u8]) -> Result<(Field<'_>, &[u8]), DecodeError> {
+	let (tag, remainder) = parse_varint(data)?;
+	let (field_num, wire_type) = unpack_tag(tag)?;
+	// Errors past the tag are reported at the start of the value.
+	let value_offset = data.len() - remainder.len();
+	let in_value = |err: DecodeError| err.in_field(field_num, value_offset);
 	// Based on the wire type, build a Field, consuming as many bytes as necessary.
 	let (fieldvalue, remainder) = match wire_type {
 		WireType::Varint => {
-			let (value, remainder) = parse_varint(remainder);
+			let (value, remainder) = parse_varint(remainder).map_err(in_value)?;
 			(FieldValue::Varint(value), remainder)
 		}
+		WireType::I64 => {
+			let (bytes, remainder) = parse_fixed(remainder).map_err(in_value)?;
+			(FieldValue::I64(i64::from_le_bytes(bytes)), remainder)
+		}
+		WireType::I32 => {
+			let (bytes, remainder) = parse_fixed(remainder).map_err(in_value)?;
+			(FieldValue::I32(i32::from_le_bytes(bytes)), remainder)
+		}
 		WireType::Len => {
 			// adapted from solution
-			let (len_value, remainder) = parse_varint(remainder);
-			let len: usize = len_value.try_into().expect("requires valid usize");
-			assert!(
-				len <= remainder.len(),
-				"unexpected EOF: error handling in prod"
-			);
+			let (len, remainder) = parse_varint(remainder).map_err(in_value)?;
 			// split bytefield at index
-			let (val, remainder) = remainder.split_at(len);
+			let (val, remainder) = split_value(remainder, len).map_err(in_value)?;
 			(FieldValue::Len(val), remainder)
 		}
 	};
-	(
+	Ok((
 		Field {
 			field_num,
 			value: fieldvalue,
 		},
 		remainder,
-	)
+	))
 }
 
 /// Parse a message in the given data, calling `T::add_field` for each field in
 /// the message.
 ///
 /// The entire input is consumed.
-fn parse_message<'a, T: ProtoMessage<'a>>(mut data: &'a [u8]) -> T {
+pub fn parse_message<'a, T: ProtoMessage<'a>>(input: &'a [u8]) -> Result<T, DecodeError> {
 	let mut result = T::default();
-	while !data.is_empty() {
-		let parsed = parse_field(data);
-		result.add_field(parsed.0);
-		data = parsed.1;
-	}
-	result
+	result.merge_from(input)?;
+	Ok(result)
 }
 
-#[derive(PartialEq, Debug, Default)]
+// Strings borrow from the input when decoded, and `into_owned` copies them
+// out so the message can outlive the buffer.
+#[derive(PartialEq, Debug, Default, Clone)]
 struct PhoneNumber<'a> {
-	number: &'a str,
-	type_: &'a str,
+	number: Cow<'a, str>,
+	type_: Cow<'a, str>,
 }
 
-#[derive(PartialEq, Debug, Default)]
+#[derive(PartialEq, Debug, Default, Clone)]
 struct Person<'a> {
-	name: &'a str,
+	name: Cow<'a, str>,
 	id: u64,
 	phone: Vec<PhoneNumber<'a>>,
 }
 
+impl ProtoDescribe for PhoneNumber<'_> {
+	const FIELDS: &'static [FieldDesc] = &[
+		FieldDesc {
+			name: "number",
+			number: 1,
+			field_type: FieldType::String,
+			repeated: false,
+		},
+		FieldDesc {
+			name: "type",
+			number: 2,
+			field_type: FieldType::String,
+			repeated: false,
+		},
+	];
+}
+
+impl ProtoDescribe for Person<'_> {
+	const FIELDS: &'static [FieldDesc] = &[
+		FieldDesc {
+			name: "name",
+			number: 1,
+			field_type: FieldType::String,
+			repeated: false,
+		},
+		FieldDesc {
+			name: "id",
+			number: 2,
+			field_type: FieldType::Uint64,
+			repeated: false,
+		},
+		FieldDesc {
+			name: "phone",
+			number: 3,
+			field_type: FieldType::Message(PhoneNumber::FIELDS),
+			repeated: true,
+		},
+	];
+}
+
+impl IntoOwned for PhoneNumber<'_> {
+	type Owned = PhoneNumber<'static>;
+
+	fn into_owned(self) -> PhoneNumber<'static> {
+		PhoneNumber {
+			number: Cow::Owned(self.number.into_owned()),
+			type_: Cow::Owned(self.type_.into_owned()),
+		}
+	}
+}
+
+impl IntoOwned for Person<'_> {
+	type Owned = Person<'static>;
+
+	fn into_owned(self) -> Person<'static> {
+		Person {
+			name: Cow::Owned(self.name.into_owned()),
+			id: self.id,
+			phone: self.phone.into_owned(),
+		}
+	}
+}
+
 // TODO: Implement ProtoMessage for Person and PhoneNumber.
 
-// tuple-style indexing on Person
+// tuple-style indexing on Person. Assigning singular fields keeps the last
+// value seen, as the spec requires.
 impl<'a> ProtoMessage<'a> for Person<'a> {
-	fn add_field(&mut self, field: Field<'a>) {
+	fn add_field(&mut self, field: Field<'a>) -> Result<(), DecodeError> {
 		match field.field_num {
-			1 => self.name = field.value.as_str(),
-			2 => self.id = field.value.as_u64(),
+			1 => self.name = field.value.as_str()?.into(),
+			2 => self.id = field.value.as_u64()?,
 			3 => {
-				self.phone.push(parse_message(field.value.as_bytes()));
+				self.phone.push(parse_message(field.value.as_bytes()?)?);
 			}
-			_ => todo!("idk"),
+			// Fields from a newer schema are skipped.
+			_ => {}
 		}
+		Ok(())
 	}
 }
 impl<'a> ProtoMessage<'a> for PhoneNumber<'a> {
-	fn add_field(&mut self, field: Field<'a>) {
+	fn add_field(&mut self, field: Field<'a>) -> Result<(), DecodeError> {
 		match field.field_num {
-			1 => self.number = field.value.as_str(),
-			2 => self.type_ = field.value.as_str(),
-			_ => todo!("idk"),
+			1 => self.number = field.value.as_str()?.into(),
+			2 => self.type_ = field.value.as_str()?.into(),
+			// Fields from a newer schema are skipped.
+			_ => {}
+		}
+		Ok(())
+	}
+}
+
+// proto3 leaves scalar fields holding their default value off the wire.
+impl ProtoEncode for Person<'_> {
+	fn encode_to(&self, buf: &mut Vec<u8>) {
+		if !self.name.is_empty() {
+			Field {
+				field_num: 1,
+				value: FieldValue::Len(self.name.as_bytes()),
+			}
+			.encode_to(buf);
+		}
+		if self.id != 0 {
+			Field {
+				field_num: 2,
+				value: FieldValue::Varint(self.id),
+			}
+			.encode_to(buf);
+		}
+		for phone in &self.phone {
+			encode_message_field(3, phone, buf);
+		}
+	}
+
+	fn encoded_len(&self) -> usize {
+		let mut len = 0;
+		if !self.name.is_empty() {
+			len += tag_len(1) + FieldValue::Len(self.name.as_bytes()).encoded_len();
 		}
+		if self.id != 0 {
+			len += tag_len(2) + varint_len(self.id);
+		}
+		for phone in &self.phone {
+			len += message_field_len(3, phone);
+		}
+		len
+	}
+}
+impl ProtoEncode for PhoneNumber<'_> {
+	fn encode_to(&self, buf: &mut Vec<u8>) {
+		for (field_num, value) in [(1, &self.number), (2, &self.type_)] {
+			if !value.is_empty() {
+				Field {
+					field_num,
+					value: FieldValue::Len(value.as_bytes()),
+				}
+				.encode_to(buf);
+			}
+		}
+	}
+
+	fn encoded_len(&self) -> usize {
+		[(1, &self.number), (2, &self.type_)]
+			.into_iter()
+			.filter(|(_, value)| !value.is_empty())
+			.map(|(field_num, value)| {
+				tag_len(field_num) + FieldValue::Len(value.as_bytes()).encoded_len()
+			})
+			.sum()
 	}
 }
 
 #[test]
 fn test_id() {
-	let person_id: Person = parse_message(&[0x10, 0x2a]);
+	let person_id: Person = parse_message(&[0x10, 0x2a]).unwrap();
 	assert_eq!(
 		person_id,
 		Person {
-			name: "",
+			name: "".into(),
 			id: 42,
 			phone: vec![]
 		}
@@ -202,11 +765,12 @@ fn test_name() {
 	let person_name: Person = parse_message(&[
 		0x0a, 0x0e, 0x62, 0x65, 0x61, 0x75, 0x74, 0x69, 0x66, 0x75, 0x6c, 0x20, 0x6e, 0x61, 0x6d,
 		0x65,
-	]);
+	])
+	.unwrap();
 	assert_eq!(
 		person_name,
 		Person {
-			name: "beautiful name",
+			name: "beautiful name".into(),
 			id: 0,
 			phone: vec![]
 		}
@@ -215,11 +779,12 @@ fn test_name() {
 
 #[test]
 fn test_just_person() {
-	let person_name_id: Person = parse_message(&[0x0a, 0x04, 0x45, 0x76, 0x61, 0x6e, 0x10, 0x16]);
+	let person_name_id: Person =
+		parse_message(&[0x0a, 0x04, 0x45, 0x76, 0x61, 0x6e, 0x10, 0x16]).unwrap();
 	assert_eq!(
 		person_name_id,
 		Person {
-			name: "Evan",
+			name: "Evan".into(),
 			id: 22,
 			phone: vec![]
 		}
@@ -231,45 +796,880 @@ fn test_phone() {
 	let phone: Person = parse_message(&[
 		0x0a, 0x00, 0x10, 0x00, 0x1a, 0x16, 0x0a, 0x0e, 0x2b, 0x31, 0x32, 0x33, 0x34, 0x2d, 0x37,
 		0x37, 0x37, 0x2d, 0x39, 0x30, 0x39, 0x30, 0x12, 0x04, 0x68, 0x6f, 0x6d, 0x65,
-	]);
+	])
+	.unwrap();
 	assert_eq!(
 		phone,
 		Person {
-			name: "",
+			name: "".into(),
 			id: 0,
 			phone: vec![PhoneNumber {
-				number: "+1234-777-9090",
-				type_: "home"
+				number: "+1234-777-9090".into(),
+				type_: "home".into()
 			},],
 		}
 	);
 }
 
+#[cfg(test)]
+const FULL_PERSON: &[u8] = &[
+	0x0a, 0x07, 0x6d, 0x61, 0x78, 0x77, 0x65, 0x6c, 0x6c, 0x10, 0x2a, 0x1a, 0x16, 0x0a, 0x0e, 0x2b,
+	0x31, 0x32, 0x30, 0x32, 0x2d, 0x35, 0x35, 0x35, 0x2d, 0x31, 0x32, 0x31, 0x32, 0x12, 0x04, 0x68,
+	0x6f, 0x6d, 0x65, 0x1a, 0x18, 0x0a, 0x0e, 0x2b, 0x31, 0x38, 0x30, 0x30, 0x2d, 0x38, 0x36, 0x37,
+	0x2d, 0x35, 0x33, 0x30, 0x38, 0x12, 0x06, 0x6d, 0x6f, 0x62, 0x69, 0x6c, 0x65,
+];
+
 // Put that all together into a single parse.
 #[test]
 fn test_full_person() {
-	let person: Person = parse_message(&[
-		0x0a, 0x07, 0x6d, 0x61, 0x78, 0x77, 0x65, 0x6c, 0x6c, 0x10, 0x2a, 0x1a, 0x16, 0x0a, 0x0e,
-		0x2b, 0x31, 0x32, 0x30, 0x32, 0x2d, 0x35, 0x35, 0x35, 0x2d, 0x31, 0x32, 0x31, 0x32, 0x12,
-		0x04, 0x68, 0x6f, 0x6d, 0x65, 0x1a, 0x18, 0x0a, 0x0e, 0x2b, 0x31, 0x38, 0x30, 0x30, 0x2d,
-		0x38, 0x36, 0x37, 0x2d, 0x35, 0x33, 0x30, 0x38, 0x12, 0x06, 0x6d, 0x6f, 0x62, 0x69, 0x6c,
-		0x65,
-	]);
+	let person: Person = parse_message(FULL_PERSON).unwrap();
 	assert_eq!(
 		person,
 		Person {
-			name: "maxwell",
+			name: "maxwell".into(),
 			id: 42,
 			phone: vec![
 				PhoneNumber {
-					number: "+1202-555-1212",
-					type_: "home"
+					number: "+1202-555-1212".into(),
+					type_: "home".into()
 				},
 				PhoneNumber {
-					number: "+1800-867-5308",
-					type_: "mobile"
+					number: "+1800-867-5308".into(),
+					type_: "mobile".into()
 				},
 			]
 		}
 	);
 }
+
+#[test]
+fn test_fixed64_fields() {
+	// field 1, wire type I64: the `double` 1.5
+	let (field, remainder) = parse_field(&[0x09, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f]).unwrap();
+	assert!(remainder.is_empty());
+	assert_eq!(field.field_num, 1);
+	assert_eq!(field.value.as_f64(), Ok(1.5));
+
+	// field 2, wire type I64: the `sfixed64` -2
+	let (field, _) = parse_field(&[0x11, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap();
+	assert_eq!(field.field_num, 2);
+	assert_eq!(field.value.as_sfixed64(), Ok(-2));
+	assert_eq!(field.value.as_fixed64(), Ok(u64::MAX - 1));
+}
+
+#[test]
+fn test_fixed32_fields() {
+	// field 1, wire type I32: the `float` -0.25, followed by another field
+	let (field, remainder) = parse_field(&[0x0d, 0x00, 0x00, 0x80, 0xbe, 0x10, 0x2a]).unwrap();
+	assert_eq!(remainder, &[0x10, 0x2a]);
+	assert_eq!(field.field_num, 1);
+	assert_eq!(field.value.as_f32(), Ok(-0.25));
+
+	// field 3, wire type I32: the `fixed32` 0xdeadbeef
+	let (field, _) = parse_field(&[0x1d, 0xef, 0xbe, 0xad, 0xde]).unwrap();
+	assert_eq!(field.field_num, 3);
+	assert_eq!(field.value.as_fixed32(), Ok(0xdead_beef));
+	assert_eq!(field.value.as_sfixed32(), Ok(0xdead_beef_u32 as i32));
+}
+
+#[test]
+fn test_truncated_fixed64() {
+	assert_eq!(
+		parse_field(&[0x09, 0, 0, 0]).unwrap_err(),
+		DecodeError::TruncatedField {
+			field_num: 1,
+			offset: 1,
+			len: 8
+		}
+	);
+}
+
+#[test]
+fn test_truncated_varint() {
+	// the `id` field's value is cut off after a continuation byte
+	let err = parse_message::<Person>(&[0x10, 0x2a, 0x10, 0x80]).unwrap_err();
+	assert_eq!(err, DecodeError::TruncatedVarint { offset: 3 });
+	// so is the tag of the second field
+	let err = parse_message::<Person>(&[0x10, 0x2a, 0x80]).unwrap_err();
+	assert_eq!(err, DecodeError::TruncatedVarint { offset: 2 });
+}
+
+#[test]
+fn test_invalid_wire_type() {
+	// field 2 with wire type 7
+	let err = parse_message::<Person>(&[0x10, 0x2a, 0x17]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::InvalidWireType {
+			field_num: 2,
+			offset: 2,
+			wire_type: 7
+		}
+	);
+}
+
+#[test]
+fn test_truncated_len() {
+	// `name` claims 14 bytes, but only 4 follow
+	let err = parse_message::<Person>(&[0x0a, 0x0e, 0x62, 0x65, 0x61, 0x75]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::TruncatedField {
+			field_num: 1,
+			offset: 1,
+			len: 14
+		}
+	);
+	// a length that does not fit in memory is no different
+	let err =
+		parse_message::<Person>(&[0x0a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]).unwrap_err();
+	assert!(matches!(
+		err,
+		DecodeError::TruncatedField { field_num: 1, .. }
+	));
+}
+
+#[test]
+fn test_unexpected_wire_type() {
+	// `id` sent as a `Len` field
+	let err = parse_message::<Person>(&[0x0a, 0x00, 0x12, 0x01, 0x2a]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::UnexpectedWireType {
+			field_num: 2,
+			offset: 4,
+			expected: "Varint"
+		}
+	);
+}
+
+#[test]
+fn test_invalid_utf8_in_nested_message() {
+	// phone number "+1\xff" inside the first `phone` entry
+	let err = parse_message::<Person>(&[0x10, 0x01, 0x1a, 0x05, 0x0a, 0x03, 0x2b, 0x31, 0xff])
+		.unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::InvalidUtf8 {
+			field_num: 1,
+			offset: 8
+		}
+	);
+	assert_eq!(err.to_string(), "field 1 at byte 8: invalid UTF-8");
+}
+
+#[test]
+fn test_varint_round_trip() {
+	// Both sides of every 7-bit group boundary, plus the extremes.
+	let mut values = vec![0, 1, u64::MAX, u64::MAX - 1, 1 << 63];
+	for bits in (7..64).step_by(7) {
+		values.extend([(1 << bits) - 1, 1 << bits, (1 << bits) + 1]);
+	}
+	for value in values {
+		let mut buf = Vec::new();
+		encode_varint(value, &mut buf);
+		let expected_len = (64 - value.leading_zeros() as usize).div_ceil(7).max(1);
+		assert_eq!(buf.len(), expected_len, "length of {value:#x}");
+		assert_eq!(varint_len(value), expected_len, "length of {value:#x}");
+		buf.push(0xaa);
+		assert_eq!(parse_varint(&buf), Ok((value, &[0xaa][..])), "{value:#x}");
+	}
+}
+
+#[test]
+fn test_varint_negative_int64() {
+	// Negative `int64` values are sign-extended to 10 bytes.
+	let mut buf = Vec::new();
+	encode_varint(-1i64 as u64, &mut buf);
+	assert_eq!(
+		buf,
+		[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+	);
+	let (value, _) = parse_varint(&buf).unwrap();
+	assert_eq!(value as i64, -1);
+
+	let (value, _) =
+		parse_varint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).unwrap();
+	assert_eq!(value as i64, i64::MIN);
+}
+
+#[test]
+fn test_varint_overflow() {
+	// A tenth byte above 1 sets bits past the 64th.
+	let err = parse_varint(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]);
+	assert_eq!(err, Err(DecodeError::VarintOverflow { offset: 0 }));
+	// So does an eleventh byte.
+	let err = parse_varint(&[
+		0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00,
+	]);
+	assert_eq!(err, Err(DecodeError::VarintOverflow { offset: 0 }));
+	// Reported relative to the message being parsed.
+	let err = parse_message::<Person>(&[
+		0x10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
+	]);
+	assert_eq!(err, Err(DecodeError::VarintOverflow { offset: 1 }));
+}
+
+#[test]
+fn test_large_id() {
+	let person: Person = parse_message(&[
+		0x10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+	])
+	.unwrap();
+	assert_eq!(person.id, u64::MAX);
+}
+
+#[test]
+fn test_encode_full_person() {
+	let person: Person = parse_message(FULL_PERSON).unwrap();
+	assert_eq!(person.encoded_len(), FULL_PERSON.len());
+	assert_eq!(person.encode(), FULL_PERSON);
+	assert_eq!(parse_message::<Person>(&person.encode()), Ok(person));
+}
+
+#[test]
+fn test_encode_round_trip() {
+	let people = [
+		Person::default(),
+		Person {
+			name: "beautiful name".into(),
+			id: u64::MAX,
+			phone: vec![],
+		},
+		Person {
+			name: "".into(),
+			id: 0,
+			phone: vec![
+				PhoneNumber::default(),
+				PhoneNumber {
+					number: "+1234-777-9090".into(),
+					type_: "".into(),
+				},
+			],
+		},
+	];
+	for person in people {
+		let bytes = person.encode();
+		assert_eq!(bytes.len(), person.encoded_len());
+		assert_eq!(parse_message::<Person>(&bytes), Ok(person));
+	}
+}
+
+#[test]
+fn test_encode_omits_defaults() {
+	// `test_phone` spells out the empty name and zero id; the encoder does not.
+	let person = Person {
+		name: "".into(),
+		id: 0,
+		phone: vec![PhoneNumber {
+			number: "+1234-777-9090".into(),
+			type_: "home".into(),
+		}],
+	};
+	assert_eq!(
+		person.encode(),
+		[
+			0x1a, 0x16, 0x0a, 0x0e, 0x2b, 0x31, 0x32, 0x33, 0x34, 0x2d, 0x37, 0x37, 0x37, 0x2d,
+			0x39, 0x30, 0x39, 0x30, 0x12, 0x04, 0x68, 0x6f, 0x6d, 0x65,
+		]
+	);
+}
+
+#[test]
+fn test_encode_fixed_fields() {
+	for value in [FieldValue::I64(-2), FieldValue::I32(0x3e80_0000)] {
+		let field = Field {
+			field_num: 300,
+			value,
+		};
+		let mut buf = Vec::new();
+		field.encode_to(&mut buf);
+		assert_eq!(buf.len(), field.encoded_len());
+		let (parsed, remainder) = parse_field(&buf).unwrap();
+		assert!(remainder.is_empty());
+		assert_eq!(parsed.field_num, 300);
+		assert_eq!(
+			parsed.value.as_fixed64().ok(),
+			field.value.as_fixed64().ok()
+		);
+		assert_eq!(
+			parsed.value.as_fixed32().ok(),
+			field.value.as_fixed32().ok()
+		);
+	}
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Attachment<'a> {
+	#[proto(field = 1)]
+	file_name: &'a str,
+	#[proto(field = 2)]
+	data: &'a [u8],
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Note<'a> {
+	#[proto(field = 1)]
+	author: Person<'a>,
+	#[proto(field = 2)]
+	tags: Vec<&'a str>,
+	#[proto(field = 3)]
+	attachments: Vec<Attachment<'a>>,
+	#[proto(field = 4)]
+	score: f64,
+	#[proto(field = 5)]
+	revision: u64,
+	#[proto(field = 15)]
+	weight: f32,
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Counters {
+	#[proto(field = 1)]
+	values: Vec<u64>,
+}
+
+#[test]
+fn test_derive_decode() {
+	// `author` is the `test_full_person` fixture
+	let mut bytes = vec![0x0a, FULL_PERSON.len() as u8];
+	bytes.extend_from_slice(FULL_PERSON);
+	bytes.extend_from_slice(&[
+		0x12, 0x03, 0x66, 0x6f, 0x6f, // tags: "foo"
+		0x12, 0x00, // tags: ""
+		0x1a, 0x07, 0x0a, 0x01, 0x61, 0x12, 0x02, 0x00, 0xff, // attachments
+		0x28, 0x07, // revision: 7
+		0x7d, 0x00, 0x00, 0x20, 0x40, // weight: 2.5
+	]);
+	let note: Note = parse_message(&bytes).unwrap();
+	assert_eq!(note.author, parse_message(FULL_PERSON).unwrap());
+	assert_eq!(note.tags, ["foo", ""]);
+	assert_eq!(
+		note.attachments,
+		[Attachment {
+			file_name: "a",
+			data: &[0x00, 0xff]
+		}]
+	);
+	assert_eq!(note.score, 0.0);
+	assert_eq!(note.revision, 7);
+	assert_eq!(note.weight, 2.5);
+	assert_eq!(note.encode(), bytes);
+}
+
+#[test]
+fn test_derive_round_trip() {
+	let note = Note {
+		author: Person {
+			name: "Evan".into(),
+			id: 22,
+			phone: vec![PhoneNumber::default()],
+		},
+		tags: vec!["", "b"],
+		attachments: vec![Attachment::default()],
+		score: -0.0,
+		revision: u64::MAX,
+		weight: 0.0,
+	};
+	let bytes = note.encode();
+	assert_eq!(bytes.len(), note.encoded_len());
+	assert_eq!(parse_message::<Note>(&bytes), Ok(note));
+
+	let counters = Counters {
+		values: vec![0, 1, 1 << 40],
+	};
+	assert_eq!(parse_message::<Counters>(&counters.encode()), Ok(counters));
+}
+
+#[test]
+fn test_derive_errors() {
+	// `revision` sent as a `Len` field
+	let err = parse_message::<Note>(&[0x2a, 0x00]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::UnexpectedWireType {
+			field_num: 5,
+			offset: 2,
+			expected: "Varint"
+		}
+	);
+	// fields the struct does not declare are skipped
+	assert_eq!(
+		parse_message::<Counters>(&[0x10, 0x01, 0x08, 0x02]),
+		Ok(Counters { values: vec![2] })
+	);
+}
+
+#[test]
+fn test_skip_unknown_fields() {
+	// `FULL_PERSON` with fields 4 (Varint), 5 (I64), 6 (Len) and 7 (I32) added
+	// by a newer schema, including one inside the first phone number.
+	let mut bytes = vec![0x20, 0x96, 0x01, 0x29, 1, 2, 3, 4, 5, 6, 7, 8];
+	bytes.extend_from_slice(FULL_PERSON);
+	bytes.extend_from_slice(&[0x32, 0x02, 0xff, 0xff, 0x3d, 1, 2, 3, 4]);
+	bytes.extend_from_slice(&[0x1a, 0x05, 0x0a, 0x01, 0x31, 0x18, 0x01]);
+	let person: Person = parse_message(&bytes).unwrap();
+	let mut expected: Person = parse_message(FULL_PERSON).unwrap();
+	expected.phone.push(PhoneNumber {
+		number: "1".into(),
+		type_: "".into(),
+	});
+	assert_eq!(person, expected);
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Envelope<'a> {
+	#[proto(field = 2)]
+	id: u64,
+	#[proto(unknown_fields)]
+	unknown: Vec<Field<'a>>,
+}
+
+#[test]
+fn test_keep_unknown_fields() {
+	let bytes = [
+		0x0a, 0x02, 0x68, 0x69, // 1: "hi"
+		0x10, 0x2a, // 2: 42
+		0x19, 1, 2, 3, 4, 5, 6, 7, 8, // 3: fixed64
+		0x25, 1, 2, 3, 4, // 4: fixed32
+	];
+	let envelope: Envelope = parse_message(&bytes).unwrap();
+	assert_eq!(envelope.id, 42);
+	assert_eq!(
+		envelope.unknown,
+		[
+			Field {
+				field_num: 1,
+				value: FieldValue::Len(b"hi"),
+			},
+			Field {
+				field_num: 3,
+				value: FieldValue::I64(0x0807_0605_0403_0201),
+			},
+			Field {
+				field_num: 4,
+				value: FieldValue::I32(0x0403_0201),
+			},
+		]
+	);
+	// Known fields come first when re-encoding; unknown ones keep their order.
+	let encoded = envelope.encode();
+	assert_eq!(encoded.len(), envelope.encoded_len());
+	assert_eq!(encoded[..2], [0x10, 0x2a]);
+	assert_eq!(encoded[2..6], bytes[..4]);
+	assert_eq!(encoded[6..], bytes[6..]);
+	assert_eq!(parse_message::<Envelope>(&encoded), Ok(envelope));
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Samples {
+	#[proto(field = 1, zigzag)]
+	deltas: Vec<i32>,
+	#[proto(field = 2)]
+	readings: Vec<f64>,
+}
+
+#[test]
+fn test_packed_repeated_fields() {
+	// `Counters.values` as written by proto3: packed into one `Len` field.
+	let packed = [0x0a, 0x04, 0x03, 0x8e, 0x02, 0x00];
+	let counters: Counters = parse_message(&packed).unwrap();
+	assert_eq!(counters.values, [3, 270, 0]);
+	assert_eq!(counters.encode(), packed);
+
+	// Unpacked and packed occurrences of one field append to each other.
+	let mixed = [0x08, 0x01, 0x0a, 0x02, 0x02, 0x03, 0x08, 0x04, 0x0a, 0x00];
+	let counters: Counters = parse_message(&mixed).unwrap();
+	assert_eq!(counters.values, [1, 2, 3, 4]);
+
+	let samples = Samples {
+		deltas: vec![-1, 1, i32::MIN],
+		readings: vec![0.5, -0.0],
+	};
+	let bytes = samples.encode();
+	assert_eq!(bytes.len(), samples.encoded_len());
+	assert_eq!(bytes[..2], [0x0a, 0x07]);
+	assert_eq!(bytes[9..11], [0x12, 0x10]);
+	assert_eq!(parse_message::<Samples>(&bytes), Ok(samples));
+}
+
+#[test]
+fn test_packed_repeated_errors() {
+	// The second packed double is cut short.
+	let err = parse_message::<Samples>(&[0x12, 0x0c, 0, 0, 0, 0, 0, 0, 0xe0, 0x3f, 1, 2, 3, 4])
+		.unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::TruncatedField {
+			field_num: 2,
+			offset: 10,
+			len: 8
+		}
+	);
+	// 2^32 is not a valid `sint32`, packed or not.
+	let err =
+		parse_message::<Samples>(&[0x0a, 0x06, 0x01, 0x80, 0x80, 0x80, 0x80, 0x10]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::OutOfRange {
+			field_num: 1,
+			offset: 3,
+			expected: "sint32"
+		}
+	);
+	let err =
+		parse_message::<Samples>(&[0x08, 0x00, 0x08, 0x80, 0x80, 0x80, 0x80, 0x10]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::OutOfRange {
+			field_num: 1,
+			offset: 3,
+			expected: "sint32"
+		}
+	);
+	// Errors for fixed-width values are at the value too, after the tag.
+	let err = parse_message::<Samples>(&[0x08, 0x00, 0x15, 0, 0, 0, 0]).unwrap_err();
+	assert_eq!(
+		err,
+		DecodeError::UnexpectedWireType {
+			field_num: 2,
+			offset: 3,
+			expected: "I64"
+		}
+	);
+}
+
+#[test]
+fn test_signed_accessors() {
+	let varint = |bytes: &[u8]| {
+		parse_varint(bytes)
+			.map(|(v, _)| FieldValue::Varint(v))
+			.unwrap()
+	};
+	let minus_one = varint(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+	assert_eq!(minus_one.as_int64(), Ok(-1));
+	assert_eq!(minus_one.as_int32(), Ok(-1));
+	assert!(minus_one.as_uint32().is_err());
+
+	let big = FieldValue::Varint(1 << 31);
+	assert_eq!(big.as_int64(), Ok(1 << 31));
+	assert_eq!(big.as_uint32(), Ok(1 << 31));
+	assert_eq!(
+		big.as_int32(),
+		Err(DecodeError::OutOfRange {
+			field_num: 0,
+			offset: 0,
+			expected: "int32"
+		})
+	);
+	// An int32 must be sign-extended, not just truncated to 32 bits.
+	assert!(FieldValue::Varint(0xffff_ffff).as_int32().is_err());
+	assert!(
+		FieldValue::Varint(u64::from(u32::MAX) + 1)
+			.as_uint32()
+			.is_err()
+	);
+}
+
+#[test]
+fn test_zigzag_accessors() {
+	for (raw, expected) in [(0, 0), (1, -1), (2, 1), (3, -2), (u64::MAX, i64::MIN)] {
+		assert_eq!(FieldValue::Varint(raw).as_sint64(), Ok(expected));
+	}
+	for (raw, expected) in [
+		(0, 0),
+		(1, -1),
+		(0xffff_fffe, i32::MAX),
+		(0xffff_ffff, i32::MIN),
+	] {
+		assert_eq!(FieldValue::Varint(raw).as_sint32(), Ok(expected));
+	}
+	assert!(FieldValue::Varint(1 << 32).as_sint32().is_err());
+	assert!(FieldValue::I32(1).as_sint32().is_err());
+}
+
+#[test]
+fn test_bool_accessor() {
+	assert_eq!(FieldValue::Varint(0).as_bool(), Ok(false));
+	assert_eq!(FieldValue::Varint(1).as_bool(), Ok(true));
+	assert!(FieldValue::Varint(2).as_bool().is_err());
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+enum PhoneType {
+	Mobile,
+	Home,
+}
+
+#[cfg(test)]
+impl TryFrom<i32> for PhoneType {
+	type Error = i32;
+
+	fn try_from(value: i32) -> Result<Self, i32> {
+		match value {
+			0 => Ok(PhoneType::Mobile),
+			1 => Ok(PhoneType::Home),
+			_ => Err(value),
+		}
+	}
+}
+
+#[test]
+fn test_enum_accessor() {
+	assert_eq!(FieldValue::Varint(1).as_enum(), Ok(PhoneType::Home));
+	assert_eq!(
+		FieldValue::Varint(7).as_enum::<PhoneType>(),
+		Err(DecodeError::UnknownEnumValue {
+			field_num: 0,
+			offset: 0,
+			value: 7
+		})
+	);
+	// Open enums keep values without a variant.
+	assert_eq!(FieldValue::Varint(7).as_int32(), Ok(7));
+	assert!(FieldValue::Varint(1 << 40).as_enum::<PhoneType>().is_err());
+}
+
+#[test]
+fn test_merge_concatenated() {
+	// FULL_PERSON followed by `id: 7` and a third phone.
+	let mut data = FULL_PERSON.to_vec();
+	data.extend([0x10, 0x07, 0x1a, 0x05, 0x0a, 0x03, 0x39, 0x31, 0x31]);
+	let person: Person = parse_message(&data).unwrap();
+	assert_eq!(person.name, "maxwell");
+	assert_eq!(person.id, 7);
+	let numbers: Vec<_> = person.phone.iter().map(|p| &*p.number).collect();
+	assert_eq!(numbers, ["+1202-555-1212", "+1800-867-5308", "911"]);
+
+	let mut merged: Person = parse_message(FULL_PERSON).unwrap();
+	merged.merge_from(&data[FULL_PERSON.len()..]).unwrap();
+	assert_eq!(merged, person);
+}
+
+#[test]
+fn test_merge_embedded_message() {
+	let first = Note {
+		author: Person {
+			name: "maxwell".into(),
+			id: 42,
+			phone: vec![],
+		},
+		tags: vec!["draft"],
+		score: 1.5,
+		..Default::default()
+	}
+	.encode();
+	let second = Note {
+		author: Person {
+			name: "".into(),
+			id: 7,
+			phone: vec![PhoneNumber {
+				number: "911".into(),
+				type_: "".into(),
+			}],
+		},
+		tags: vec!["final"],
+		revision: 2,
+		..Default::default()
+	}
+	.encode();
+
+	let expected = Note {
+		author: Person {
+			name: "maxwell".into(),
+			id: 7,
+			phone: vec![PhoneNumber {
+				number: "911".into(),
+				type_: "".into(),
+			}],
+		},
+		tags: vec!["draft", "final"],
+		score: 1.5,
+		revision: 2,
+		..Default::default()
+	};
+	let mut note: Note = parse_message(&first).unwrap();
+	note.merge_from(&second).unwrap();
+	assert_eq!(note, expected);
+	let concatenated = [first, second].concat();
+	assert_eq!(parse_message::<Note>(&concatenated), Ok(expected));
+}
+
+#[test]
+fn test_merge_error_offsets() {
+	let mut person: Person = parse_message(FULL_PERSON).unwrap();
+	assert_eq!(
+		person.merge_from(&[0x10, 0x01, 0x0a, 0x01, 0xff]),
+		Err(DecodeError::InvalidUtf8 {
+			field_num: 1,
+			offset: 4
+		})
+	);
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Node {
+	#[proto(field = 1)]
+	children: Vec<Node>,
+}
+
+/// A `Node` with a single chain of `depth` descendants.
+#[cfg(test)]
+fn nested_nodes(depth: usize) -> Vec<u8> {
+	// the length of each level's payload, from the innermost
+	let mut lens = vec![0];
+	for _ in 0..depth {
+		let len = *lens.last().unwrap();
+		lens.push(tag_len(1) + varint_len(len as u64) + len);
+	}
+	let mut data = Vec::new();
+	for &len in lens.iter().rev().skip(1) {
+		encode_tag(1, WireType::Len, &mut data);
+		encode_varint(len as u64, &mut data);
+	}
+	data
+}
+
+#[test]
+fn test_max_depth() {
+	let node: Node = parse_message(&nested_nodes(MAX_DEPTH - 1)).unwrap();
+	assert_eq!(node.encode(), nested_nodes(MAX_DEPTH - 1));
+	// The innermost node is empty, and starts at the end of the input.
+	let data = nested_nodes(MAX_DEPTH);
+	assert_eq!(
+		parse_message::<Node>(&data),
+		Err(DecodeError::TooDeep { offset: data.len() })
+	);
+	// The depth is back to 0 after an error.
+	assert!(parse_message::<Node>(&nested_nodes(MAX_DEPTH - 1)).is_ok());
+}
+
+/// A leaf message that counts how often its length is asked for.
+#[cfg(test)]
+#[derive(Default)]
+struct Counted {
+	value: u64,
+	len_calls: Cell<usize>,
+}
+
+#[cfg(test)]
+impl ProtoMessage<'_> for Counted {
+	fn add_field(&mut self, field: Field<'_>) -> Result<(), DecodeError> {
+		self.value = field.value.as_u64()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+impl ProtoEncode for Counted {
+	fn encode_to(&self, buf: &mut Vec<u8>) {
+		if self.value != 0 {
+			encode_tag(1, WireType::Varint, buf);
+			encode_varint(self.value, buf);
+		}
+	}
+
+	fn encoded_len(&self) -> usize {
+		self.len_calls.set(self.len_calls.get() + 1);
+		if self.value == 0 {
+			0
+		} else {
+			tag_len(1) + varint_len(self.value)
+		}
+	}
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, Default)]
+struct Inner {
+	#[proto(field = 1)]
+	leaf: Counted,
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, Default)]
+struct Middle {
+	#[proto(field = 1)]
+	inner: Inner,
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, Default)]
+struct Outer {
+	#[proto(field = 1)]
+	middle: Middle,
+}
+
+#[test]
+fn test_nested_encoded_len_once() {
+	let outer = Outer {
+		middle: Middle {
+			inner: Inner {
+				leaf: Counted {
+					value: 7,
+					..Counted::default()
+				},
+			},
+		},
+	};
+	let calls = || outer.middle.inner.leaf.len_calls.take();
+	assert_eq!(outer.encoded_len(), 8);
+	assert_eq!(calls(), 1);
+	// Once to size the buffer, then once more per level written: linear in
+	// the depth, where asking twice per level would double at each one.
+	assert_eq!(
+		outer.encode(),
+		[0x0a, 0x06, 0x0a, 0x04, 0x0a, 0x02, 0x08, 0x07]
+	);
+	assert_eq!(calls(), 4);
+}
+
+#[test]
+fn test_into_owned() {
+	let person = {
+		let buffer = FULL_PERSON.to_vec();
+		let person: Person = parse_message(&buffer).unwrap();
+		assert!(matches!(person.name, Cow::Borrowed(_)));
+		person.into_owned()
+	};
+	// The buffer is gone, and the person can be sent to another thread.
+	let person = std::thread::spawn(move || person).join().unwrap();
+	assert!(matches!(person.phone[0].number, Cow::Owned(_)));
+	assert_eq!(person, parse_message(FULL_PERSON).unwrap());
+}
+
+#[cfg(test)]
+#[derive(ProtoMessage, PartialEq, Debug, Default)]
+struct Blob<'a> {
+	#[proto(field = 1)]
+	name: Cow<'a, str>,
+	#[proto(field = 2)]
+	data: Cow<'a, [u8]>,
+	#[proto(field = 3)]
+	tags: Vec<Cow<'a, str>>,
+}
+
+#[test]
+fn test_derive_cow_fields() {
+	let blob = Blob {
+		name: Cow::Owned("notes.txt".to_string()),
+		data: Cow::Owned(vec![0, 1, 2]),
+		tags: vec![Cow::Borrowed("a"), Cow::Owned("b".to_string())],
+	};
+	let encoded = blob.encode();
+	let decoded: Blob = parse_message(&encoded).unwrap();
+	assert!(matches!(decoded.data, Cow::Borrowed(_)));
+	assert_eq!(decoded, blob);
+	// The derived `IntoOwned` lets it outlive the buffer.
+	let owned: Blob<'static> = decoded.into_owned();
+	drop(encoded);
+	assert!(matches!(owned.tags[0], Cow::Owned(_)));
+	assert_eq!(owned, blob);
+}
@@ -0,0 +1,173 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use crate::{
+	DecodeError, Field, FieldValue, Natural, ProtoField, ProtoMessage, WireType, encode_tag,
+	encode_varint, parse_message, tag_len, varint_len,
+};
+
+/// Encoding marker for map fields, with the markers for the key and value.
+///
+/// On the wire, a map is a repeated message of entries holding the key as
+/// field 1 and the value as field 2.
+pub struct Map<K = Natural, V = Natural>(PhantomData<(K, V)>);
+
+/// One entry of a map field, as decoded from its `Len` value.
+struct Entry<K, V, KE, VE> {
+	key: K,
+	value: V,
+	encoding: PhantomData<(KE, VE)>,
+}
+
+impl<K: Default, V: Default, KE, VE> Default for Entry<K, V, KE, VE> {
+	fn default() -> Self {
+		Entry {
+			key: K::default(),
+			value: V::default(),
+			encoding: PhantomData,
+		}
+	}
+}
+
+impl<'a, K, V, KE, VE> ProtoMessage<'a> for Entry<K, V, KE, VE>
+where
+	K: ProtoField<'a, KE> + Default,
+	V: ProtoField<'a, VE> + Default,
+{
+	fn add_field(&mut self, field: Field<'a>) -> Result<(), DecodeError> {
+		match field.field_num {
+			1 => self.key.merge_value(field.value),
+			2 => self.value.merge_value(field.value),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// The length of an entry's payload. Keys and values are always written,
+/// even when they hold the default value.
+fn entry_len<'a, K: ProtoField<'a, KE>, V: ProtoField<'a, VE>, KE, VE>(
+	key: &K,
+	value: &V,
+) -> usize {
+	key.encoded_field_len(1) + value.encoded_field_len(2)
+}
+
+fn encode_entry<'a, K: ProtoField<'a, KE>, V: ProtoField<'a, VE>, KE, VE>(
+	field_num: u64,
+	key: &K,
+	value: &V,
+	buf: &mut Vec<u8>,
+) {
+	encode_tag(field_num, WireType::Len, buf);
+	encode_varint(entry_len(key, value) as u64, buf);
+	key.encode_field(1, buf);
+	value.encode_field(2, buf);
+}
+
+/// Implement `ProtoField<'a, Map<KE, VE>>` for a map type whose keys need
+/// `$bound`. A key seen again replaces the earlier entry.
+macro_rules! map_field {
+	($map:ident, $($bound:tt)+) => {
+		impl<'a, K, V, KE, VE> ProtoField<'a, Map<KE, VE>> for $map<K, V>
+		where
+			K: ProtoField<'a, KE> + Default + $($bound)+,
+			V: ProtoField<'a, VE> + Default,
+		{
+			fn merge_value(&mut self, value: FieldValue<'a>) -> Result<(), DecodeError> {
+				let entry: Entry<K, V, KE, VE> = parse_message(value.as_bytes()?)?;
+				self.insert(entry.key, entry.value);
+				Ok(())
+			}
+
+			fn is_default(&self) -> bool {
+				self.is_empty()
+			}
+
+			fn encode_field(&self, field_num: u64, buf: &mut Vec<u8>) {
+				for (key, value) in self {
+					encode_entry(field_num, key, value, buf);
+				}
+			}
+
+			fn encoded_field_len(&self, field_num: u64) -> usize {
+				self.iter()
+					.map(|(key, value)| {
+						let len = entry_len(key, value);
+						tag_len(field_num) + varint_len(len as u64) + len
+					})
+					.sum()
+			}
+		}
+	};
+}
+
+map_field!(HashMap, Eq + Hash);
+map_field!(BTreeMap, Ord);
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{ProtoEncode, ProtoMessage};
+
+	#[derive(crate::ProtoMessage, PartialEq, Debug, Default)]
+	struct Labels<'a> {
+		#[proto(field = 1, map)]
+		labels: HashMap<&'a str, &'a str>,
+		#[proto(field = 2, map(key = zigzag, value = fixed))]
+		offsets: BTreeMap<i32, u64>,
+	}
+
+	#[test]
+	fn test_map_entries() {
+		// labels { key: "a" value: "x" }, then offsets { key: -1 value: 2 }
+		let data = [
+			0x0a, 0x06, 0x0a, 0x01, b'a', 0x12, 0x01, b'x', //
+			0x12, 0x0b, 0x08, 0x01, 0x11, 2, 0, 0, 0, 0, 0, 0, 0,
+		];
+		let labels: Labels = parse_message(&data).unwrap();
+		assert_eq!(labels.labels, HashMap::from([("a", "x")]));
+		assert_eq!(labels.offsets, BTreeMap::from([(-1, 2)]));
+		assert_eq!(labels.encode(), data);
+	}
+
+	#[test]
+	fn test_map_missing_and_repeated_keys() {
+		// An entry without a key or value holds the defaults, and a key seen
+		// again replaces the earlier value.
+		let data = [
+			0x0a, 0x00, //
+			0x0a, 0x03, 0x12, 0x01, b'x', //
+			0x0a, 0x05, 0x0a, 0x01, b'a', 0x12, 0x00, //
+			0x0a, 0x06, 0x0a, 0x01, b'a', 0x12, 0x01, b'y',
+		];
+		let labels: Labels = parse_message(&data).unwrap();
+		assert_eq!(labels.labels, HashMap::from([("", "x"), ("a", "y")]));
+	}
+
+	#[test]
+	fn test_map_round_trip() {
+		let labels = Labels {
+			labels: HashMap::from([("team", "search"), ("", "")]),
+			offsets: BTreeMap::from([(i32::MIN, 0), (0, u64::MAX), (7, 1)]),
+		};
+		let encoded = labels.encode();
+		assert_eq!(encoded.len(), labels.encoded_len());
+		assert_eq!(parse_message::<Labels>(&encoded), Ok(labels));
+	}
+
+	#[test]
+	fn test_map_errors() {
+		// The key is a VARINT where a string is expected, reported at the
+		// VARINT.
+		let mut labels = Labels::default();
+		assert_eq!(
+			labels.merge_from(&[0x0a, 0x02, 0x08, 0x01]),
+			Err(DecodeError::UnexpectedWireType {
+				field_num: 1,
+				offset: 3,
+				expected: "Len"
+			})
+		);
+	}
+}
@@ -0,0 +1,160 @@
+use crate::{DecodeError, Field};
+
+/// An enum with one variant per member of a `oneof`, which
+/// `#[derive(ProtoOneof)]` implements.
+///
+/// Messages store it as an `Option` marked `#[proto(oneof)]`. When several
+/// members are on the wire, the last one seen wins; a member seen again is
+/// merged into, as a singular field would be.
+pub trait ProtoOneof<'a>: Sized {
+	/// The field numbers of the members.
+	const FIELDS: &'static [u64];
+
+	/// Merge `field`, one of `FIELDS`, into `oneof`.
+	fn merge_field(oneof: &mut Option<Self>, field: Field<'a>) -> Result<(), DecodeError>;
+
+	/// Append the member's field, even if it holds the default value: being
+	/// set is what a oneof records.
+	fn encode_to(&self, buf: &mut Vec<u8>);
+
+	/// The number of bytes `encode_to` appends.
+	fn encoded_len(&self) -> usize;
+}
+
+/// Whether no two of a message's `fields` and the members of its `oneofs`
+/// share a field number. `#[derive(ProtoMessage)]` cannot see the numbers
+/// of the members, so it checks them with this at compile time:
+///
+/// ```compile_fail
+/// #[derive(protobuf_parsing::ProtoOneof)]
+/// enum Contact<'a> {
+///     #[proto(field = 1)]
+///     Email(&'a str),
+/// }
+///
+/// #[derive(protobuf_parsing::ProtoMessage, Default)]
+/// struct Card<'a> {
+///     #[proto(field = 1)]
+///     name: &'a str,
+///     #[proto(oneof)]
+///     contact: Option<Contact<'a>>,
+/// }
+///
+/// let _ = protobuf_parsing::parse_message::<Card>(&[]);
+/// ```
+#[doc(hidden)]
+pub const fn distinct_fields(fields: &[u64], oneofs: &[&[u64]]) -> bool {
+	let mut i = 0;
+	while i < oneofs.len() {
+		let mut j = 0;
+		while j < oneofs[i].len() {
+			let num = oneofs[i][j];
+			if contains(fields, num) {
+				return false;
+			}
+			let mut k = 0;
+			while k < i {
+				if contains(oneofs[k], num) {
+					return false;
+				}
+				k += 1;
+			}
+			j += 1;
+		}
+		i += 1;
+	}
+	true
+}
+
+const fn contains(nums: &[u64], num: u64) -> bool {
+	let mut i = 0;
+	while i < nums.len() {
+		if nums[i] == num {
+			return true;
+		}
+		i += 1;
+	}
+	false
+}
+
+#[cfg(test)]
+mod test {
+	use std::borrow::Cow;
+
+	use super::*;
+	use crate::{PhoneNumber, ProtoEncode, ProtoMessage, parse_message};
+
+	#[derive(crate::ProtoOneof, PartialEq, Debug)]
+	enum Contact<'a> {
+		#[proto(field = 2)]
+		Email(&'a str),
+		#[proto(field = 3)]
+		Phone(PhoneNumber<'a>),
+		#[proto(field = 4, zigzag)]
+		Extension(i32),
+	}
+
+	#[derive(crate::ProtoMessage, PartialEq, Debug, Default)]
+	struct Card<'a> {
+		#[proto(field = 1)]
+		name: &'a str,
+		#[proto(oneof)]
+		contact: Option<Contact<'a>>,
+	}
+
+	#[test]
+	fn test_last_member_wins() {
+		// email: "a", then extension: -1
+		let mut card: Card = parse_message(&[0x12, 0x01, b'a', 0x20, 0x01]).unwrap();
+		assert_eq!(card.contact, Some(Contact::Extension(-1)));
+		card.merge_from(&[0x12, 0x01, b'b', 0x0a, 0x01, b'c'])
+			.unwrap();
+		assert_eq!(
+			card,
+			Card {
+				name: "c",
+				contact: Some(Contact::Email("b")),
+			}
+		);
+	}
+
+	#[test]
+	fn test_same_member_merges() {
+		// phone { number: "1" }, then phone { type: "home" }
+		let card: Card = parse_message(&[
+			0x1a, 0x03, 0x0a, 0x01, b'1', //
+			0x1a, 0x06, 0x12, 0x04, b'h', b'o', b'm', b'e',
+		])
+		.unwrap();
+		assert_eq!(
+			card.contact,
+			Some(Contact::Phone(PhoneNumber {
+				number: Cow::Borrowed("1"),
+				type_: Cow::Borrowed("home"),
+			}))
+		);
+	}
+
+	#[test]
+	fn test_encode_set_member() {
+		// A member holding its default value is still written.
+		let card = Card {
+			name: "",
+			contact: Some(Contact::Extension(0)),
+		};
+		assert_eq!(card.encode(), [0x20, 0x00]);
+		assert_eq!(card.encoded_len(), 2);
+		assert_eq!(parse_message::<Card>(&card.encode()), Ok(card));
+		assert!(Card::default().encode().is_empty());
+		assert_eq!(<Contact as ProtoOneof>::FIELDS, [2, 3, 4]);
+	}
+
+	#[test]
+	fn test_distinct_fields() {
+		let contact = <Contact as ProtoOneof>::FIELDS;
+		assert!(distinct_fields(&[1], &[contact]));
+		assert!(distinct_fields(&[1, 5], &[contact, &[6, 7]]));
+		assert!(!distinct_fields(&[1, 3], &[contact]));
+		assert!(!distinct_fields(&[1], &[contact, &[6, 4]]));
+	}
+}
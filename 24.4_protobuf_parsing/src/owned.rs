@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A decoded value that can stop borrowing from the input buffer, so it can
+/// be kept after the buffer is reused or sent to another thread.
+///
+/// Messages that store strings and bytes as `Cow` implement this by converting
+/// each field, turning e.g. a `Person<'a>` into a `Person<'static>`.
+/// `#[derive(ProtoMessage)]` and `#[derive(ProtoOneof)]` write that impl.
+pub trait IntoOwned {
+	type Owned: 'static;
+
+	fn into_owned(self) -> Self::Owned;
+}
+
+// `Cow`'s inherent `into_owned` returns the owned type itself, so it is
+// rewrapped here.
+impl IntoOwned for Cow<'_, str> {
+	type Owned = Cow<'static, str>;
+
+	fn into_owned(self) -> Cow<'static, str> {
+		Cow::Owned(Cow::into_owned(self))
+	}
+}
+
+impl IntoOwned for Cow<'_, [u8]> {
+	type Owned = Cow<'static, [u8]>;
+
+	fn into_owned(self) -> Cow<'static, [u8]> {
+		Cow::Owned(Cow::into_owned(self))
+	}
+}
+
+impl<T: IntoOwned> IntoOwned for Vec<T> {
+	type Owned = Vec<T::Owned>;
+
+	fn into_owned(self) -> Vec<T::Owned> {
+		self.into_iter().map(T::into_owned).collect()
+	}
+}
+
+impl<T: IntoOwned> IntoOwned for Option<T> {
+	type Owned = Option<T::Owned>;
+
+	fn into_owned(self) -> Option<T::Owned> {
+		self.map(T::into_owned)
+	}
+}
+
+impl<T: IntoOwned> IntoOwned for Box<T> {
+	type Owned = Box<T::Owned>;
+
+	fn into_owned(self) -> Box<T::Owned> {
+		Box::new((*self).into_owned())
+	}
+}
+
+impl<K: IntoOwned, V: IntoOwned> IntoOwned for HashMap<K, V>
+where
+	K::Owned: Eq + Hash,
+{
+	type Owned = HashMap<K::Owned, V::Owned>;
+
+	fn into_owned(self) -> HashMap<K::Owned, V::Owned> {
+		self.into_iter()
+			.map(|(key, value)| (key.into_owned(), value.into_owned()))
+			.collect()
+	}
+}
+
+impl<K: IntoOwned, V: IntoOwned> IntoOwned for BTreeMap<K, V>
+where
+	K::Owned: Ord,
+{
+	type Owned = BTreeMap<K::Owned, V::Owned>;
+
+	fn into_owned(self) -> BTreeMap<K::Owned, V::Owned> {
+		self.into_iter()
+			.map(|(key, value)| (key.into_owned(), value.into_owned()))
+			.collect()
+	}
+}
+
+/// Implement `IntoOwned` for types that never borrow.
+macro_rules! already_owned {
+	($($ty:ty),*) => {
+		$(
+			impl IntoOwned for $ty {
+				type Owned = $ty;
+
+				fn into_owned(self) -> $ty {
+					self
+				}
+			}
+		)*
+	};
+}
+
+already_owned!(u64, u32, i64, i32, bool, f64, f32);
@@ -0,0 +1,128 @@
+use crate::{DecodeError, FieldValue, WireType, parse_fixed, parse_varint};
+
+/// The values of a packed repeated field: a `Len` payload holding scalar
+/// values back to back, with no tags in between.
+///
+/// Yields each value as the `FieldValue` it would be if sent unpacked. Stops
+/// after the first error.
+pub struct Packed<'a> {
+	data: &'a [u8],
+	offset: usize,
+	wire_type: WireType,
+}
+
+impl<'a> FieldValue<'a> {
+	/// Iterate over the values of `wire_type` packed into this `Len` value.
+	///
+	/// Panics if `wire_type` is `Len`, which cannot be packed.
+	pub fn packed(&self, wire_type: WireType) -> Result<Packed<'a>, DecodeError> {
+		assert!(wire_type != WireType::Len, "`Len` values cannot be packed");
+		Ok(Packed {
+			data: self.as_bytes()?,
+			offset: 0,
+			wire_type,
+		})
+	}
+}
+
+impl Packed<'_> {
+	/// The offset of the next value from the start of the payload.
+	pub fn position(&self) -> usize {
+		self.offset
+	}
+}
+
+impl<'a> Iterator for Packed<'a> {
+	type Item = Result<FieldValue<'a>, DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.data.is_empty() {
+			return None;
+		}
+		let parsed = match self.wire_type {
+			WireType::Varint => {
+				parse_varint(self.data).map(|(value, rest)| (FieldValue::Varint(value), rest))
+			}
+			WireType::I64 => parse_fixed(self.data)
+				.map(|(bytes, rest)| (FieldValue::I64(i64::from_le_bytes(bytes)), rest)),
+			WireType::I32 => parse_fixed(self.data)
+				.map(|(bytes, rest)| (FieldValue::I32(i32::from_le_bytes(bytes)), rest)),
+			WireType::Len => unreachable!(),
+		};
+		match parsed {
+			Ok((value, rest)) => {
+				self.offset += self.data.len() - rest.len();
+				self.data = rest;
+				Some(Ok(value))
+			}
+			Err(err) => {
+				self.data = &[];
+				Some(Err(err.offset_by(self.offset)))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_packed_varints() {
+		let value = FieldValue::Len(&[0x03, 0x8e, 0x02, 0x9e, 0xa7, 0x05]);
+		let values: Vec<_> = value
+			.packed(WireType::Varint)
+			.unwrap()
+			.map(|v| v.and_then(|v| v.as_u64()))
+			.collect();
+		assert_eq!(values, [Ok(3), Ok(270), Ok(86942)]);
+	}
+
+	#[test]
+	fn test_packed_fixed() {
+		let value = FieldValue::Len(&[0, 0, 0x80, 0x3f, 0, 0, 0, 0xc0]);
+		let values: Vec<_> = value
+			.packed(WireType::I32)
+			.unwrap()
+			.map(|v| v.and_then(|v| v.as_f32()))
+			.collect();
+		assert_eq!(values, [Ok(1.0), Ok(-2.0)]);
+
+		let value = FieldValue::Len(&[0xff; 16]);
+		let values: Vec<_> = value
+			.packed(WireType::I64)
+			.unwrap()
+			.map(|v| v.and_then(|v| v.as_sfixed64()))
+			.collect();
+		assert_eq!(values, [Ok(-1), Ok(-1)]);
+	}
+
+	#[test]
+	fn test_packed_errors() {
+		// A fixed32 value cut short after the first one.
+		let value = FieldValue::Len(&[1, 0, 0, 0, 2, 0]);
+		let mut values = value.packed(WireType::I32).unwrap();
+		assert_eq!(values.next(), Some(Ok(FieldValue::I32(1))));
+		assert_eq!(
+			values.next(),
+			Some(Err(DecodeError::TruncatedField {
+				field_num: 0,
+				offset: 4,
+				len: 4
+			}))
+		);
+		assert_eq!(values.next(), None);
+
+		let value = FieldValue::Len(&[0x01, 0x80]);
+		let values: Vec<_> = value.packed(WireType::Varint).unwrap().collect();
+		assert_eq!(
+			values,
+			[
+				Ok(FieldValue::Varint(1)),
+				Err(DecodeError::TruncatedVarint { offset: 1 })
+			]
+		);
+
+		assert!(FieldValue::Varint(1).packed(WireType::Varint).is_err());
+	}
+}
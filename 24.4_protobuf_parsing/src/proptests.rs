@@ -0,0 +1,211 @@
+//! Property tests for decoding untrusted input: arbitrary bytes, and
+//! structurally valid encodings built from a generated field tree.
+
+use std::borrow::Cow;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::raw::{RawMessage, format_raw};
+use crate::stream::MessageReader;
+use crate::{
+	Counters, DecodeError, Envelope, Field, FieldDesc, FieldType, FieldValue, MAX_DEPTH, Node,
+	Note, Person, PhoneNumber, ProtoDescribe, ProtoEncode, Samples, json, nested_nodes,
+	parse_message, text,
+};
+
+/// A field value as generated, owning what a `FieldValue` borrows.
+#[derive(Debug, Clone)]
+enum Value {
+	Varint(u64),
+	I64(i64),
+	I32(i32),
+	Bytes(Vec<u8>),
+	Message(Vec<(u64, Value)>),
+}
+
+fn value() -> impl Strategy<Value = Value> {
+	let leaf = prop_oneof![
+		any::<u64>().prop_map(Value::Varint),
+		any::<i64>().prop_map(Value::I64),
+		any::<i32>().prop_map(Value::I32),
+		vec(any::<u8>(), 0..16).prop_map(Value::Bytes),
+	];
+	leaf.prop_recursive(3, 32, 6, |inner| {
+		vec((field_num(), inner), 0..6).prop_map(Value::Message)
+	})
+}
+
+fn field_num() -> impl Strategy<Value = u64> {
+	1..1u64 << 29
+}
+
+fn message() -> impl Strategy<Value = Vec<(u64, Value)>> {
+	vec((field_num(), value()), 0..8)
+}
+
+fn encode(fields: &[(u64, Value)]) -> Vec<u8> {
+	let mut buf = Vec::new();
+	for (field_num, value) in fields {
+		let nested;
+		let value = match value {
+			Value::Varint(v) => FieldValue::Varint(*v),
+			Value::I64(v) => FieldValue::I64(*v),
+			Value::I32(v) => FieldValue::I32(*v),
+			Value::Bytes(bytes) => FieldValue::Len(bytes),
+			Value::Message(fields) => {
+				nested = encode(fields);
+				FieldValue::Len(&nested)
+			}
+		};
+		Field {
+			field_num: *field_num,
+			value,
+		}
+		.encode_to(&mut buf);
+	}
+	buf
+}
+
+/// Decode `data` as raw fields and check they are `fields`, descending into
+/// nested messages.
+fn assert_decodes_to(data: &[u8], fields: &[(u64, Value)]) {
+	let RawMessage(parsed) = parse_message(data).unwrap();
+	assert_eq!(parsed.len(), fields.len());
+	for (field, (field_num, value)) in parsed.iter().zip(fields) {
+		assert_eq!(field.field_num, *field_num);
+		match (&field.value, value) {
+			(FieldValue::Varint(a), Value::Varint(b)) => assert_eq!(a, b),
+			(FieldValue::I64(a), Value::I64(b)) => assert_eq!(a, b),
+			(FieldValue::I32(a), Value::I32(b)) => assert_eq!(a, b),
+			(FieldValue::Len(a), Value::Bytes(b)) => assert_eq!(a, b),
+			(FieldValue::Len(a), Value::Message(fields)) => assert_decodes_to(a, fields),
+			(parsed, expected) => panic!("decoded {parsed:?}, expected {expected:?}"),
+		}
+	}
+}
+
+fn offset(err: &DecodeError) -> usize {
+	match *err {
+		DecodeError::TruncatedVarint { offset }
+		| DecodeError::VarintOverflow { offset }
+		| DecodeError::TooDeep { offset }
+		| DecodeError::InvalidWireType { offset, .. }
+		| DecodeError::TruncatedField { offset, .. }
+		| DecodeError::UnexpectedWireType { offset, .. }
+		| DecodeError::InvalidUtf8 { offset, .. }
+		| DecodeError::OutOfRange { offset, .. }
+		| DecodeError::UnknownEnumValue { offset, .. } => offset,
+	}
+}
+
+/// Check that decoding `$message` either fails at an offset inside `$data`,
+/// or gives a message whose encoding decodes and re-encodes to the same
+/// bytes. Encodings are compared rather than messages, as floats may be NaN.
+///
+/// A macro rather than a function, as the re-decoded message borrows from a
+/// local buffer and so has a different lifetime than the first.
+macro_rules! check_decode {
+	($message:ident, $data:expr) => {
+		let data: &[u8] = $data;
+		match parse_message::<$message>(data) {
+			Ok(message) => {
+				let encoded = message.encode();
+				assert_eq!(encoded.len(), message.encoded_len());
+				let decoded: $message = parse_message(&encoded).unwrap_or_else(|err| {
+					panic!("re-encoded {encoded:02x?} fails to decode: {err}")
+				});
+				assert_eq!(decoded.encode(), encoded);
+			}
+			Err(err) => assert!(offset(&err) <= data.len(), "{err} in {} bytes", data.len()),
+		}
+	};
+}
+
+/// Run every decoder over `data`. None may panic, whatever the input.
+fn check_all(data: &[u8]) {
+	if let Err(err) = parse_message::<RawMessage>(data) {
+		assert!(offset(&err) <= data.len(), "{err} in {} bytes", data.len());
+	}
+	check_decode!(Person, data);
+	check_decode!(Note, data);
+	check_decode!(Counters, data);
+	check_decode!(Envelope, data);
+	check_decode!(Samples, data);
+	check_decode!(Node, data);
+	let _ = format_raw(data);
+	let _ = json::format_json(data, Person::FIELDS);
+	let _ = text::format_text(data, Person::FIELDS);
+	let mut reader = MessageReader::new(data);
+	while let Ok(Some(_)) = reader.next_record() {}
+}
+
+fn person() -> impl Strategy<Value = Person<'static>> {
+	let phone = ("\\PC*", "\\PC*").prop_map(|(number, type_)| PhoneNumber {
+		number: Cow::Owned(number),
+		type_: Cow::Owned(type_),
+	});
+	("\\PC*", any::<u64>(), vec(phone, 0..4)).prop_map(|(name, id, phone)| Person {
+		name: Cow::Owned(name),
+		id,
+		phone,
+	})
+}
+
+#[test]
+fn test_deep_nesting() {
+	// Far deeper than the stack allows without a limit.
+	let data = nested_nodes(100_000);
+	assert!(matches!(
+		parse_message::<Node>(&data),
+		Err(DecodeError::TooDeep { .. })
+	));
+	check_all(&data);
+	// Schema-less decoders stop guessing nested messages well before.
+	assert!(format_raw(&data).is_ok());
+	let json = json::format_json(&nested_nodes(MAX_DEPTH), &NODE_FIELDS);
+	assert!(matches!(json, Err(DecodeError::TooDeep { .. })));
+}
+
+/// `Node`'s description, which refers to itself.
+static NODE_FIELDS: [FieldDesc; 1] = [FieldDesc {
+	name: "children",
+	number: 1,
+	field_type: FieldType::Message(&NODE_FIELDS),
+	repeated: true,
+}];
+
+proptest! {
+	#[test]
+	fn test_arbitrary_bytes(data in vec(any::<u8>(), 0..256)) {
+		check_all(&data);
+	}
+
+	#[test]
+	fn test_valid_encodings(fields in message()) {
+		let data = encode(&fields);
+		assert_decodes_to(&data, &fields);
+		let RawMessage(parsed) = parse_message(&data).unwrap();
+		let reencoded: Vec<u8> = parsed.iter().flat_map(|field| {
+			let mut buf = Vec::new();
+			field.encode_to(&mut buf);
+			buf
+		}).collect();
+		prop_assert_eq!(&reencoded, &data);
+		prop_assert!(format_raw(&data).is_ok());
+		check_all(&data);
+	}
+
+	#[test]
+	fn test_truncated_encodings(fields in message(), cut in any::<prop::sample::Index>()) {
+		let data = encode(&fields);
+		check_all(&data[..cut.index(data.len() + 1)]);
+	}
+
+	#[test]
+	fn test_person_round_trip(person in person()) {
+		let encoded = person.encode();
+		prop_assert_eq!(encoded.len(), person.encoded_len());
+		prop_assert_eq!(parse_message::<Person>(&encoded), Ok(person));
+	}
+}
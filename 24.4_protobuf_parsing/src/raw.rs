@@ -0,0 +1,158 @@
+//! Print a message without its schema, like `protoc --decode_raw`.
+//!
+//! `Len` fields are guessed to be a string if they are printable UTF-8,
+//! otherwise a nested message if they parse as one, and bytes if neither.
+
+use std::fmt::Write;
+
+use crate::{DecodeError, Field, FieldValue, ProtoMessage, parse_message};
+
+/// How deep nested messages are decoded before the rest is shown as bytes.
+const MAX_DEPTH: usize = 64;
+
+/// Every field of a message, in wire order.
+#[derive(Default)]
+pub(crate) struct RawMessage<'a>(pub Vec<Field<'a>>);
+
+impl<'a> ProtoMessage<'a> for RawMessage<'a> {
+	fn add_field(&mut self, field: Field<'a>) -> Result<(), DecodeError> {
+		self.0.push(field);
+		Ok(())
+	}
+}
+
+/// What a `Len` payload most likely holds.
+enum Guess<'a> {
+	String(&'a str),
+	Message(Vec<Field<'a>>),
+	Bytes(&'a [u8]),
+}
+
+fn guess(data: &[u8], depth: usize) -> Guess<'_> {
+	if let Ok(text) = std::str::from_utf8(data)
+		&& !text
+			.chars()
+			.any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+	{
+		return Guess::String(text);
+	}
+	// Field number 0 is invalid, so bytes that decode to it are not a message.
+	if depth < MAX_DEPTH
+		&& let Ok(RawMessage(fields)) = parse_message(data)
+		&& fields.iter().all(|field| field.field_num != 0)
+	{
+		return Guess::Message(fields);
+	}
+	Guess::Bytes(data)
+}
+
+/// Format every field in `data` as a tree, one field per line.
+pub fn format_raw(data: &[u8]) -> Result<String, DecodeError> {
+	let RawMessage(fields) = parse_message(data)?;
+	let mut out = String::new();
+	write_fields(&mut out, &fields, 0);
+	Ok(out)
+}
+
+fn write_fields(out: &mut String, fields: &[Field], depth: usize) {
+	let indent = "  ".repeat(depth);
+	for field in fields {
+		let num = field.field_num;
+		// Writing to a String cannot fail.
+		match &field.value {
+			FieldValue::Varint(value) => writeln!(out, "{indent}{num}: {value}"),
+			FieldValue::I64(value) => writeln!(out, "{indent}{num}: 0x{value:016x}"),
+			FieldValue::I32(value) => writeln!(out, "{indent}{num}: 0x{value:08x}"),
+			FieldValue::Len(data) => match guess(data, depth + 1) {
+				Guess::String(text) => writeln!(out, "{indent}{num}: {text:?}"),
+				Guess::Bytes(bytes) => writeln!(out, "{indent}{num}: \"{}\"", bytes.escape_ascii()),
+				Guess::Message(fields) => {
+					writeln!(out, "{indent}{num} {{").unwrap();
+					write_fields(out, &fields, depth + 1);
+					writeln!(out, "{indent}}}")
+				}
+			},
+		}
+		.unwrap();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::FULL_PERSON;
+
+	#[test]
+	fn test_format_person() {
+		assert_eq!(
+			format_raw(FULL_PERSON).unwrap(),
+			r#"1: "maxwell"
+2: 42
+3 {
+  1: "+1202-555-1212"
+  2: "home"
+}
+3 {
+  1: "+1800-867-5308"
+  2: "mobile"
+}
+"#
+		);
+	}
+
+	#[test]
+	fn test_format_scalars() {
+		let data = [
+			0x09, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f, // 1: double 1.5
+			0x15, 0xef, 0xbe, 0xad, 0xde, // 2: fixed32
+			0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, // 3: -1
+			0x22, 0x00, // 4: empty
+		];
+		assert_eq!(
+			format_raw(&data).unwrap(),
+			"1: 0x3ff8000000000000\n2: 0xdeadbeef\n3: 18446744073709551615\n4: \"\"\n"
+		);
+	}
+
+	#[test]
+	fn test_guess_bytes() {
+		// Not UTF-8, and 0xff is a varint that never ends.
+		assert_eq!(
+			format_raw(&[0x0a, 0x02, 0x00, 0xff]).unwrap(),
+			"1: \"\\x00\\xff\"\n"
+		);
+		// Parses as a message, but with field number 0.
+		assert_eq!(
+			format_raw(&[0x0a, 0x02, 0x00, 0x01]).unwrap(),
+			"1: \"\\x00\\x01\"\n"
+		);
+	}
+
+	#[test]
+	fn test_deep_nesting() {
+		// 100 levels of field 1 wrapping the next, around a varint.
+		let mut data = vec![0x08, 0x07];
+		for _ in 0..100 {
+			let len = data.len();
+			let mut wrapped = vec![0x0a];
+			crate::encode_varint(len as u64, &mut wrapped);
+			wrapped.extend(data);
+			data = wrapped;
+		}
+		let out = format_raw(&data).unwrap();
+		assert_eq!(out.matches('{').count(), MAX_DEPTH - 1);
+		assert!(out.contains(&format!("{}1: \"\\n", "  ".repeat(MAX_DEPTH - 1))));
+	}
+
+	#[test]
+	fn test_malformed() {
+		assert_eq!(
+			format_raw(&[0x08, 0x01, 0x0a, 0x05, 0x00]),
+			Err(DecodeError::TruncatedField {
+				field_num: 1,
+				offset: 3,
+				len: 5
+			})
+		);
+	}
+}
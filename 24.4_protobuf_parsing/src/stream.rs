@@ -0,0 +1,254 @@
+//! Streams of messages stored back to back, each prefixed with its length as
+//! a VARINT: the format of `writeDelimitedTo`/`parseDelimitedFrom`.
+
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::{
+	DecodeError, MAX_VARINT_LEN, ProtoEncode, ProtoMessage, encode_varint, parse_message,
+	parse_varint,
+};
+
+/// The largest record `MessageReader` accepts unless told otherwise.
+pub const DEFAULT_MAX_LEN: usize = 64 << 20;
+
+/// An error reading a message stream. `record` counts from 0.
+#[derive(Error, Debug)]
+pub enum StreamError {
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+	#[error("record {record}: stream ends inside the length prefix")]
+	TruncatedPrefix { record: usize },
+	#[error("record {record}: invalid length prefix")]
+	InvalidPrefix { record: usize },
+	#[error("record {record}: length {len} is over the limit of {max_len} bytes")]
+	TooLarge {
+		record: usize,
+		len: u64,
+		max_len: usize,
+	},
+	#[error("record {record}: stream ends after {read} of {len} bytes")]
+	Truncated {
+		record: usize,
+		len: u64,
+		read: usize,
+	},
+	#[error("record {record}: {source}")]
+	Decode { record: usize, source: DecodeError },
+}
+
+/// Reads length-delimited records one at a time, reusing a single buffer
+/// that grows to the largest record seen, up to `max_len`.
+///
+/// Reads the length prefix a byte at a time, so `reader` should be buffered.
+pub struct MessageReader<R> {
+	reader: R,
+	buf: Vec<u8>,
+	max_len: usize,
+	record: usize,
+}
+
+impl<R: Read> MessageReader<R> {
+	pub fn new(reader: R) -> Self {
+		MessageReader {
+			reader,
+			buf: Vec::new(),
+			max_len: DEFAULT_MAX_LEN,
+			record: 0,
+		}
+	}
+
+	/// Reject records longer than `max_len` bytes without reading them.
+	pub fn with_max_len(mut self, max_len: usize) -> Self {
+		self.max_len = max_len;
+		self
+	}
+
+	/// Read the next record's length prefix, or `None` at the end of the
+	/// stream.
+	fn read_len(&mut self) -> Result<Option<u64>, StreamError> {
+		let record = self.record;
+		let mut prefix = [0; MAX_VARINT_LEN];
+		for i in 0..MAX_VARINT_LEN {
+			match self.reader.read_exact(&mut prefix[i..=i]) {
+				Ok(()) => {}
+				Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+					return if i == 0 {
+						Ok(None)
+					} else {
+						Err(StreamError::TruncatedPrefix { record })
+					};
+				}
+				Err(err) => return Err(err.into()),
+			}
+			if prefix[i] & 0x80 == 0 {
+				return match parse_varint(&prefix[..=i]) {
+					Ok((len, _)) => Ok(Some(len)),
+					Err(_) => Err(StreamError::InvalidPrefix { record }),
+				};
+			}
+		}
+		Err(StreamError::InvalidPrefix { record })
+	}
+
+	/// Read the next record's bytes, or `None` at the end of the stream. The
+	/// bytes are overwritten by the next call.
+	pub fn next_record(&mut self) -> Result<Option<&[u8]>, StreamError> {
+		let record = self.record;
+		let Some(len) = self.read_len()? else {
+			return Ok(None);
+		};
+		if len > self.max_len as u64 {
+			return Err(StreamError::TooLarge {
+				record,
+				len,
+				max_len: self.max_len,
+			});
+		}
+		self.buf.clear();
+		let read = (&mut self.reader).take(len).read_to_end(&mut self.buf)?;
+		if (read as u64) < len {
+			return Err(StreamError::Truncated { record, len, read });
+		}
+		self.record += 1;
+		Ok(Some(&self.buf))
+	}
+
+	/// Read and decode the next message, or `None` at the end of the stream.
+	/// The message borrows from the reader until the next call.
+	pub fn next_message<'s, M: ProtoMessage<'s>>(&'s mut self) -> Result<Option<M>, StreamError> {
+		let record = self.record;
+		match self.next_record()? {
+			Some(data) => parse_message(data)
+				.map(Some)
+				.map_err(|source| StreamError::Decode { record, source }),
+			None => Ok(None),
+		}
+	}
+}
+
+/// Writes length-delimited records for `MessageReader` to read back.
+pub struct MessageWriter<W> {
+	writer: W,
+	buf: Vec<u8>,
+}
+
+impl<W: Write> MessageWriter<W> {
+	pub fn new(writer: W) -> Self {
+		MessageWriter {
+			writer,
+			buf: Vec::new(),
+		}
+	}
+
+	pub fn write_message<M: ProtoEncode>(&mut self, message: &M) -> io::Result<()> {
+		self.buf.clear();
+		encode_varint(message.encoded_len() as u64, &mut self.buf);
+		message.encode_to(&mut self.buf);
+		self.writer.write_all(&self.buf)
+	}
+
+	pub fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{FULL_PERSON, Person, PhoneNumber};
+
+	fn people() -> Vec<Person<'static>> {
+		vec![
+			parse_message(FULL_PERSON).unwrap(),
+			Person::default(),
+			Person {
+				name: "Evan".into(),
+				id: 22,
+				phone: vec![PhoneNumber {
+					number: "+1234-777-9090".into(),
+					type_: "home".into(),
+				}],
+			},
+		]
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let mut writer = MessageWriter::new(Vec::new());
+		for person in &people() {
+			writer.write_message(person).unwrap();
+		}
+		let bytes = writer.into_inner();
+		assert_eq!(bytes[0] as usize, FULL_PERSON.len());
+		assert_eq!(&bytes[1..=FULL_PERSON.len()], FULL_PERSON);
+
+		let mut reader = MessageReader::new(&bytes[..]);
+		for expected in people() {
+			assert_eq!(reader.next_message::<Person>().unwrap(), Some(expected));
+		}
+		assert_eq!(reader.next_message::<Person>().unwrap(), None);
+	}
+
+	#[test]
+	fn test_empty_stream() {
+		let mut reader = MessageReader::new(io::empty());
+		assert!(reader.next_record().unwrap().is_none());
+	}
+
+	#[test]
+	fn test_truncated_record() {
+		let mut bytes = vec![0x02, 0x10, 0x2a];
+		bytes.extend([0x10, 0x10, 0x2a]);
+		let mut reader = MessageReader::new(&bytes[..]);
+		assert_eq!(reader.next_record().unwrap(), Some(&[0x10, 0x2a][..]));
+		let err = reader.next_record().unwrap_err();
+		assert!(
+			matches!(
+				err,
+				StreamError::Truncated {
+					record: 1,
+					len: 16,
+					read: 2
+				}
+			),
+			"{err}"
+		);
+	}
+
+	#[test]
+	fn test_truncated_prefix() {
+		let mut reader = MessageReader::new(&[0x80][..]);
+		let err = reader.next_record().unwrap_err();
+		assert!(
+			matches!(err, StreamError::TruncatedPrefix { record: 0 }),
+			"{err}"
+		);
+	}
+
+	#[test]
+	fn test_too_large() {
+		let mut reader = MessageReader::new(&[0xe8, 0x07, 0x00][..]).with_max_len(100);
+		let err = reader.next_record().unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"record 0: length 1000 is over the limit of 100 bytes"
+		);
+	}
+
+	#[test]
+	fn test_decode_error() {
+		let mut reader = MessageReader::new(&[0x02, 0x10, 0x2a, 0x01, 0x17][..]);
+		assert!(reader.next_message::<Person>().unwrap().is_some());
+		let err = reader.next_message::<Person>().unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"record 1: field 2 at byte 0: invalid wire type 7"
+		);
+	}
+}
@@ -0,0 +1,560 @@
+//! The protobuf text format, as printed by `protoc --decode`:
+//!
+//! ```text
+//! name: "maxwell"
+//! phone {
+//!   number: "+1202-555-1212"
+//! }
+//! ```
+//!
+//! `parse_text` reads it back into wire bytes, so test fixtures can be
+//! written by field name rather than as hex.
+
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::descriptor::{DecodedField, Value, decode};
+use crate::field::{
+	bool_value, double_value, fixed32_value, fixed64_value, float_value, int32_value, int64_value,
+	sint32_value, sint64_value, uint32_value,
+};
+use crate::{DecodeError, Field, FieldDesc, FieldType, FieldValue, ProtoDescribe, ProtoEncode};
+
+/// Format `message` in text format, one field per line.
+pub fn to_text<M: ProtoEncode + ProtoDescribe>(message: &M) -> String {
+	format_text(&message.encode(), M::FIELDS).expect("a message decodes as its own fields")
+}
+
+/// Format the message in `data`, which has `fields`, in text format, one
+/// field per line. Fields that are not described are left out.
+pub fn format_text(data: &[u8], fields: &'static [FieldDesc]) -> Result<String, DecodeError> {
+	let mut out = String::new();
+	write_fields(&mut out, &decode(&[(0, data)], fields)?, 0);
+	Ok(out)
+}
+
+fn write_fields(out: &mut String, fields: &[DecodedField], depth: usize) {
+	let indent = "  ".repeat(depth);
+	for field in fields {
+		let name = field.desc.name;
+		for value in &field.values {
+			// Writing to a String cannot fail.
+			match value {
+				Value::Double(v) => {
+					writeln!(out, "{indent}{name}: {}", float_text(*v, v.to_string()))
+				}
+				Value::Float(v) => writeln!(
+					out,
+					"{indent}{name}: {}",
+					float_text((*v).into(), v.to_string())
+				),
+				Value::Int64(v) => writeln!(out, "{indent}{name}: {v}"),
+				Value::Uint64(v) => writeln!(out, "{indent}{name}: {v}"),
+				Value::Int32(v) => writeln!(out, "{indent}{name}: {v}"),
+				Value::Uint32(v) => writeln!(out, "{indent}{name}: {v}"),
+				Value::Bool(v) => writeln!(out, "{indent}{name}: {v}"),
+				Value::String(v) => writeln!(out, "{indent}{name}: \"{}\"", escape_str(v)),
+				Value::Bytes(v) => writeln!(out, "{indent}{name}: \"{}\"", v.escape_ascii()),
+				Value::Enum(v, names) => match names.iter().find(|(_, value)| value == v) {
+					Some((variant, _)) => writeln!(out, "{indent}{name}: {variant}"),
+					None => writeln!(out, "{indent}{name}: {v}"),
+				},
+				Value::Message(fields) => {
+					writeln!(out, "{indent}{name} {{").unwrap();
+					write_fields(out, fields, depth + 1);
+					writeln!(out, "{indent}}}")
+				}
+			}
+			.unwrap();
+		}
+	}
+}
+
+/// `text`, the shortest decimal form of `value`, unless `value` is one of
+/// the floats spelled out by name.
+fn float_text(value: f64, text: String) -> String {
+	if value.is_nan() {
+		"nan".to_string()
+	} else if value.is_infinite() {
+		if value > 0.0 { "inf" } else { "-inf" }.to_string()
+	} else {
+		text
+	}
+}
+
+/// Escape a string for a quoted literal, keeping printable non-ASCII
+/// characters as they are.
+fn escape_str(text: &str) -> String {
+	let mut out = String::new();
+	for c in text.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_ascii_control() => write!(out, "\\x{:02x}", c as u32).unwrap(),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+/// An error in text format input. Lines count from 1.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TextError {
+	#[error("line {line}: {message}")]
+	Syntax { line: usize, message: String },
+	#[error("line {line}: no field named `{name}`")]
+	UnknownField { line: usize, name: String },
+	#[error("line {line}: invalid value for `{field}`")]
+	InvalidValue { line: usize, field: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Ident(String),
+	/// A number as written, without its sign: `42`, `0x2a`, `1.5e3`, `2.5f`.
+	Number(String),
+	/// A quoted literal with its escapes resolved, which need not be UTF-8.
+	Str(Vec<u8>),
+	Symbol(char),
+}
+
+fn syntax(line: usize, message: &str) -> TextError {
+	TextError::Syntax {
+		line,
+		message: message.to_string(),
+	}
+}
+
+/// Split `source` into tokens, each with its line number.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, TextError> {
+	let mut tokens = Vec::new();
+	let mut line = 1;
+	let mut chars = source.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\n' => line += 1,
+			c if c.is_whitespace() => {}
+			'#' => while chars.next_if(|&c| c != '\n').is_some() {},
+			'"' | '\'' => {
+				let mut value = Vec::new();
+				loop {
+					match chars.next() {
+						Some(q) if q == c => break,
+						Some('\\') => {
+							let Some(escape) = chars.next() else {
+								return Err(syntax(line, "unterminated string"));
+							};
+							let byte = match escape {
+								'n' => b'\n',
+								'r' => b'\r',
+								't' => b'\t',
+								'a' => 0x07,
+								'b' => 0x08,
+								'f' => 0x0c,
+								'v' => 0x0b,
+								'x' | 'X' => {
+									let mut digits = String::new();
+									while digits.len() < 2
+										&& let Some(d) = chars.next_if(char::is_ascii_hexdigit)
+									{
+										digits.push(d);
+									}
+									u8::from_str_radix(&digits, 16)
+										.map_err(|_| syntax(line, "invalid `\\x` escape"))?
+								}
+								'0'..='7' => {
+									let mut digits = String::from(escape);
+									while digits.len() < 3
+										&& let Some(d) = chars.next_if(|c| matches!(c, '0'..='7'))
+									{
+										digits.push(d);
+									}
+									u8::from_str_radix(&digits, 8)
+										.map_err(|_| syntax(line, "invalid octal escape"))?
+								}
+								'\\' | '\'' | '"' | '?' => escape as u8,
+								_ => {
+									return Err(syntax(
+										line,
+										&format!("invalid escape `\\{escape}`"),
+									));
+								}
+							};
+							value.push(byte);
+						}
+						Some('\n') | None => return Err(syntax(line, "unterminated string")),
+						Some(c) => value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+					}
+				}
+				tokens.push((Token::Str(value), line));
+			}
+			c if c.is_ascii_digit() || c == '.' => {
+				let mut text = String::from(c);
+				while let Some(c) = chars.next_if(|&c| {
+					c.is_ascii_alphanumeric()
+						|| c == '.' || (matches!(c, '+' | '-')
+						&& !text.starts_with("0x")
+						&& text.ends_with(['e', 'E']))
+				}) {
+					text.push(c);
+				}
+				tokens.push((Token::Number(text), line));
+			}
+			c if c.is_ascii_alphabetic() || c == '_' => {
+				let mut text = String::from(c);
+				while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_') {
+					text.push(c);
+				}
+				tokens.push((Token::Ident(text), line));
+			}
+			c => tokens.push((Token::Symbol(c), line)),
+		}
+	}
+	Ok(tokens)
+}
+
+struct Parser {
+	tokens: Vec<(Token, usize)>,
+	pos: usize,
+}
+
+/// Parse a message with `fields` from text format into wire bytes, with
+/// fields in the order they are written.
+///
+/// Repeated fields may be written once per value or as a `[...]` list, and
+/// messages may be delimited by `{}` or `<>`. Repeated scalars are written
+/// unpacked.
+pub fn parse_text(source: &str, fields: &'static [FieldDesc]) -> Result<Vec<u8>, TextError> {
+	let mut parser = Parser {
+		tokens: tokenize(source)?,
+		pos: 0,
+	};
+	let mut buf = Vec::new();
+	parser.parse_fields(fields, None, &mut buf)?;
+	Ok(buf)
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos).map(|(token, _)| token)
+	}
+
+	/// The line of the next token, or of the last one at the end of input.
+	fn line(&self) -> usize {
+		self.tokens
+			.get(self.pos)
+			.or(self.tokens.last())
+			.map_or(1, |(_, line)| *line)
+	}
+
+	fn next(&mut self) -> Result<Token, TextError> {
+		let line = self.line();
+		let token = self
+			.peek()
+			.cloned()
+			.ok_or_else(|| syntax(line, "unexpected end of input"))?;
+		self.pos += 1;
+		Ok(token)
+	}
+
+	fn eat_symbol(&mut self, symbol: char) -> bool {
+		let found = self.peek() == Some(&Token::Symbol(symbol));
+		if found {
+			self.pos += 1;
+		}
+		found
+	}
+
+	fn expect_symbol(&mut self, symbol: char) -> Result<(), TextError> {
+		if self.eat_symbol(symbol) {
+			Ok(())
+		} else {
+			Err(syntax(self.line(), &format!("expected `{symbol}`")))
+		}
+	}
+
+	/// Parse fields up to `close`, or to the end of input if `None`.
+	fn parse_fields(
+		&mut self,
+		fields: &'static [FieldDesc],
+		close: Option<char>,
+		buf: &mut Vec<u8>,
+	) -> Result<(), TextError> {
+		loop {
+			match close {
+				Some(close) if self.eat_symbol(close) => return Ok(()),
+				None if self.peek().is_none() => return Ok(()),
+				_ => {}
+			}
+			let line = self.line();
+			let Token::Ident(name) = self.next()? else {
+				return Err(syntax(line, "expected a field name"));
+			};
+			let desc = fields
+				.iter()
+				.find(|f| f.name == name)
+				.ok_or(TextError::UnknownField { line, name })?;
+			let is_message = matches!(desc.field_type, FieldType::Message(_));
+			if !self.eat_symbol(':') && !is_message {
+				return Err(syntax(self.line(), "expected `:`"));
+			}
+			if desc.repeated && self.eat_symbol('[') {
+				if !self.eat_symbol(']') {
+					loop {
+						self.parse_value(desc, buf)?;
+						if self.eat_symbol(']') {
+							break;
+						}
+						self.expect_symbol(',')?;
+					}
+				}
+			} else {
+				self.parse_value(desc, buf)?;
+			}
+			if !self.eat_symbol(';') {
+				self.eat_symbol(',');
+			}
+		}
+	}
+
+	/// Parse one value of `desc` and append it to `buf` as a field.
+	fn parse_value(
+		&mut self,
+		desc: &'static FieldDesc,
+		buf: &mut Vec<u8>,
+	) -> Result<(), TextError> {
+		let line = self.line();
+		let invalid = || TextError::InvalidValue {
+			line,
+			field: desc.name,
+		};
+		let field_num = desc.number;
+		if let FieldType::Message(fields) = desc.field_type {
+			let close = if self.eat_symbol('<') {
+				'>'
+			} else {
+				self.expect_symbol('{')?;
+				'}'
+			};
+			let mut nested = Vec::new();
+			self.parse_fields(fields, Some(close), &mut nested)?;
+			Field {
+				field_num,
+				value: FieldValue::Len(&nested),
+			}
+			.encode_to(buf);
+			return Ok(());
+		}
+		if let FieldType::String | FieldType::Bytes = desc.field_type {
+			// Adjacent literals are concatenated, as in C.
+			let Token::Str(mut bytes) = self.next()? else {
+				return Err(syntax(line, "expected a string"));
+			};
+			while let Some((Token::Str(literal), _)) = self.tokens.get(self.pos) {
+				bytes.extend_from_slice(literal);
+				self.pos += 1;
+			}
+			if let FieldType::String = desc.field_type
+				&& std::str::from_utf8(&bytes).is_err()
+			{
+				return Err(invalid());
+			}
+			Field {
+				field_num,
+				value: FieldValue::Len(&bytes),
+			}
+			.encode_to(buf);
+			return Ok(());
+		}
+
+		let negative = self.eat_symbol('-');
+		let token = self.next()?;
+		let value = match (desc.field_type, &token) {
+			(FieldType::Double | FieldType::Float, Token::Number(text) | Token::Ident(text)) => {
+				let v = parse_float(text).ok_or_else(invalid)?;
+				let v = if negative { -v } else { v };
+				if let FieldType::Double = desc.field_type {
+					double_value(v)
+				} else {
+					float_value(v as f32)
+				}
+			}
+			(FieldType::Bool, Token::Ident(text)) if !negative => match text.as_str() {
+				"true" | "True" | "t" => bool_value(true),
+				"false" | "False" | "f" => bool_value(false),
+				_ => return Err(invalid()),
+			},
+			(FieldType::Enum(names), Token::Ident(text)) if !negative => {
+				let (_, v) = names
+					.iter()
+					.find(|(name, _)| name == text)
+					.ok_or_else(invalid)?;
+				int32_value(*v)
+			}
+			(field_type, Token::Number(text)) => {
+				let magnitude = parse_int(text).ok_or_else(invalid)?;
+				let v = if negative {
+					0i128 - magnitude as i128
+				} else {
+					magnitude as i128
+				};
+				int_value(field_type, v).ok_or_else(invalid)?
+			}
+			_ => return Err(invalid()),
+		};
+		Field { field_num, value }.encode_to(buf);
+		Ok(())
+	}
+}
+
+/// Parse an unsigned integer in decimal, hex (`0x`) or octal (leading `0`).
+fn parse_int(text: &str) -> Option<u64> {
+	if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+		u64::from_str_radix(hex, 16).ok()
+	} else if text.len() > 1
+		&& let Some(octal) = text.strip_prefix('0')
+	{
+		u64::from_str_radix(octal, 8).ok()
+	} else {
+		text.parse().ok()
+	}
+}
+
+/// Parse a float without its sign, allowing an `f` suffix and the names
+/// `inf`, `infinity` and `nan` in any case.
+fn parse_float(text: &str) -> Option<f64> {
+	let lower = text.to_ascii_lowercase();
+	match lower.as_str() {
+		"inf" | "infinity" => return Some(f64::INFINITY),
+		"nan" => return Some(f64::NAN),
+		_ => {}
+	}
+	let digits = lower.strip_suffix('f').unwrap_or(&lower);
+	if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+		return None;
+	}
+	digits.parse().ok()
+}
+
+/// The wire value of integer `v` as `field_type`, if it is in range.
+fn int_value(field_type: FieldType, v: i128) -> Option<FieldValue<'static>> {
+	Some(match field_type {
+		FieldType::Int64 => int64_value(v.try_into().ok()?),
+		FieldType::Uint64 => FieldValue::Varint(v.try_into().ok()?),
+		FieldType::Int32 => int32_value(v.try_into().ok()?),
+		FieldType::Uint32 => uint32_value(v.try_into().ok()?),
+		FieldType::Sint64 => sint64_value(v.try_into().ok()?),
+		FieldType::Sint32 => sint32_value(v.try_into().ok()?),
+		FieldType::Fixed64 => fixed64_value(v.try_into().ok()?),
+		FieldType::Fixed32 => fixed32_value(v.try_into().ok()?),
+		FieldType::Sfixed64 => FieldValue::I64(v.try_into().ok()?),
+		FieldType::Sfixed32 => FieldValue::I32(v.try_into().ok()?),
+		FieldType::Bool => bool_value(match v {
+			0 => false,
+			1 => true,
+			_ => return None,
+		}),
+		FieldType::Enum(_) => int32_value(v.try_into().ok()?),
+		FieldType::Double | FieldType::Float => {
+			unreachable!("floats are parsed by `parse_float`")
+		}
+		FieldType::String | FieldType::Bytes | FieldType::Message(_) => return None,
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{FULL_PERSON, Person, parse_message};
+
+	const FULL_PERSON_TEXT: &str = r#"name: "maxwell"
+id: 42
+phone {
+  number: "+1202-555-1212"
+  type: "home"
+}
+phone {
+  number: "+1800-867-5308"
+  type: "mobile"
+}
+"#;
+
+	#[test]
+	fn test_person_text() {
+		let person: Person = parse_message(FULL_PERSON).unwrap();
+		assert_eq!(to_text(&person), FULL_PERSON_TEXT);
+		assert_eq!(to_text(&Person::default()), "");
+	}
+
+	#[test]
+	fn test_parse_person() {
+		assert_eq!(
+			parse_text(FULL_PERSON_TEXT, Person::FIELDS).unwrap(),
+			FULL_PERSON
+		);
+		// The same message in the other accepted spellings.
+		let data = parse_text(
+			r#"
+			# Fields may come in any order.
+			id: 0x2a; name: "max" 'well'
+			phone: [<number: "+1202-555-1212" type: "home">,
+			        {number: "+1800-867-5308", type: "mobile"}]
+			"#,
+			Person::FIELDS,
+		)
+		.unwrap();
+		let person: Person = parse_message(&data).unwrap();
+		assert_eq!(person, parse_message(FULL_PERSON).unwrap());
+	}
+
+	#[test]
+	fn test_parse_escapes() {
+		let data = parse_text(r#"name: "a\n\"\x41\101\\""#, Person::FIELDS).unwrap();
+		let person: Person = parse_message(&data).unwrap();
+		assert_eq!(person.name, "a\n\"AA\\");
+		assert_eq!(to_text(&person), "name: \"a\\n\\\"AA\\\\\"\n");
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		assert_eq!(
+			parse_text("name: \"x\"\nemail: \"y\"", Person::FIELDS),
+			Err(TextError::UnknownField {
+				line: 2,
+				name: "email".to_string()
+			})
+		);
+		assert_eq!(
+			parse_text("id: -1", Person::FIELDS),
+			Err(TextError::InvalidValue {
+				line: 1,
+				field: "id"
+			})
+		);
+		assert_eq!(
+			parse_text("name: \"\\xff\"", Person::FIELDS),
+			Err(TextError::InvalidValue {
+				line: 1,
+				field: "name"
+			})
+		);
+		assert_eq!(
+			parse_text("phone {\n  number: \"1\"\n", Person::FIELDS),
+			Err(TextError::Syntax {
+				line: 2,
+				message: "unexpected end of input".to_string()
+			})
+		);
+		assert_eq!(
+			parse_text("id 42", Person::FIELDS),
+			Err(TextError::Syntax {
+				line: 1,
+				message: "expected `:`".to_string()
+			})
+		);
+	}
+}
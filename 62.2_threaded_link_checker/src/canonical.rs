@@ -0,0 +1,196 @@
+use reqwest::Url;
+
+/// Query parameters that only track where a visitor came from.
+const TRACKING_PARAMS: &[&str] = &[
+	"fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "_ga",
+];
+
+/// Rewrites URLs into a canonical form, so that links that lead to the same
+/// resource are only checked once.
+///
+/// Fragments are always dropped, as they are not sent to the server, and
+/// percent-escapes are normalized. Parsing already lowercases the scheme and
+/// host and drops default ports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Canonicalizer {
+	/// Sort query parameters by name.
+	pub sort_query: bool,
+	/// Lowercase paths, for sites that ignore their case.
+	pub ignore_case: bool,
+	/// Drop a trailing slash from paths other than `/`.
+	pub strip_slash: bool,
+	/// Drop `utm_*` and other tracking parameters.
+	pub strip_tracking: bool,
+}
+
+impl Default for Canonicalizer {
+	fn default() -> Self {
+		Canonicalizer {
+			sort_query: true,
+			ignore_case: false,
+			strip_slash: false,
+			strip_tracking: false,
+		}
+	}
+}
+
+impl Canonicalizer {
+	pub fn canonicalize(&self, url: &Url) -> Url {
+		let mut url = url.clone();
+		url.set_fragment(None);
+		if url.cannot_be_a_base() {
+			return url;
+		}
+
+		let mut path = normalize_escapes(url.path(), self.ignore_case);
+		if self.strip_slash && path.len() > 1 && path.ends_with('/') {
+			path.pop();
+		}
+		url.set_path(&path);
+
+		let Some(query) = url.query() else {
+			return url;
+		};
+		let mut params: Vec<String> = query
+			.split('&')
+			.filter(|param| !param.is_empty())
+			.map(|param| normalize_escapes(param, false))
+			.filter(|param| !(self.strip_tracking && is_tracking(param)))
+			.collect();
+		if self.sort_query {
+			// stable, so that repeated parameters keep their order
+			params.sort_by(|a, b| param_name(a).cmp(param_name(b)));
+		}
+		if params.is_empty() {
+			url.set_query(None);
+		} else {
+			url.set_query(Some(&params.join("&")));
+		}
+		url
+	}
+}
+
+fn param_name(param: &str) -> &str {
+	param.split('=').next().unwrap()
+}
+
+fn is_tracking(param: &str) -> bool {
+	let name = param_name(param);
+	name.starts_with("utm_") || TRACKING_PARAMS.contains(&name)
+}
+
+/// Decode the escapes of unreserved characters, which mean the same either
+/// way, and uppercase the hex digits of the others. With `lowercase`, other
+/// characters are lowercased too.
+fn normalize_escapes(s: &str, lowercase: bool) -> String {
+	let bytes = s.as_bytes();
+	let mut out = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		// `from_str_radix` alone would take a sign, as in `%+1`
+		let hex = bytes
+			.get(i + 1..i + 3)
+			.filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+			.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+		let (byte, escaped) = match (bytes[i], hex) {
+			(b'%', Some(byte)) => {
+				i += 3;
+				(byte, true)
+			}
+			(byte, _) => {
+				i += 1;
+				(byte, false)
+			}
+		};
+		let byte = if lowercase {
+			byte.to_ascii_lowercase()
+		} else {
+			byte
+		};
+		if escaped && !is_unreserved(byte) {
+			out.extend(format!("%{byte:02X}").bytes());
+		} else {
+			out.push(byte);
+		}
+	}
+	// only whole escapes are decoded, and only to ASCII
+	String::from_utf8(out).unwrap()
+}
+
+fn is_unreserved(byte: u8) -> bool {
+	byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn canonical(canonicalizer: &Canonicalizer, url: &str) -> String {
+		canonicalizer
+			.canonicalize(&Url::parse(url).unwrap())
+			.to_string()
+	}
+
+	#[test]
+	fn test_default() {
+		let canonicalizer = Canonicalizer::default();
+		for (url, expected) in [
+			("https://example.com/page#a", "https://example.com/page"),
+			("HTTPS://Example.COM:443/page", "https://example.com/page"),
+			("http://example.com:80/", "http://example.com/"),
+			("http://example.com:8080/", "http://example.com:8080/"),
+			(
+				"https://example.com/%7euser/a%2fb%c3%a9",
+				"https://example.com/~user/a%2Fb%C3%A9",
+			),
+			(
+				"https://example.com/?b=2&a=1&b=1",
+				"https://example.com/?a=1&b=2&b=1",
+			),
+			(
+				"https://example.com/?q=%41%26&",
+				"https://example.com/?q=A%26",
+			),
+			("https://example.com/?", "https://example.com/"),
+			("https://example.com/a%+1", "https://example.com/a%+1"),
+			(
+				"https://example.com/Page/?utm_source=x",
+				"https://example.com/Page/?utm_source=x",
+			),
+			("mailto:someone@example.com", "mailto:someone@example.com"),
+		] {
+			assert_eq!(canonical(&canonicalizer, url), expected, "{url}");
+		}
+	}
+
+	#[test]
+	fn test_options() {
+		let canonicalizer = Canonicalizer {
+			sort_query: false,
+			ignore_case: true,
+			strip_slash: true,
+			strip_tracking: true,
+		};
+		for (url, expected) in [
+			(
+				"https://example.com/Docs/Page/",
+				"https://example.com/docs/page",
+			),
+			("https://example.com/", "https://example.com/"),
+			(
+				"https://example.com/%C3%A9%4A",
+				"https://example.com/%C3%A9j",
+			),
+			(
+				"https://example.com/?utm_source=x&b=2&fbclid=y&a=1",
+				"https://example.com/?b=2&a=1",
+			),
+			(
+				"https://example.com/?utm_medium=email",
+				"https://example.com/",
+			),
+		] {
+			assert_eq!(canonical(&canonicalizer, url), expected, "{url}");
+		}
+	}
+}
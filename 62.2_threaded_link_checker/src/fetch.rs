@@ -0,0 +1,209 @@
+use std::time::{Duration, SystemTime};
+
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{StatusCode, Url};
+
+use crate::Error;
+use crate::retry::parse_retry_after;
+
+/// The product token robots.txt groups are matched against.
+pub const AGENT: &str = env!("CARGO_PKG_NAME");
+
+/// What fetching a URL returned.
+#[derive(Debug)]
+pub struct Response {
+	/// The URL the response came from, after following redirects.
+	pub url: Url,
+	pub status: StatusCode,
+	/// Only read when asked for.
+	pub body: Option<String>,
+	/// How long the server asked to wait before trying again.
+	pub retry_after: Option<Duration>,
+}
+
+/// How workers fetch URLs, so that crawls can run without a network.
+pub trait Fetcher: Send + Sync {
+	/// Fetch `url`, reading the body only if `read_body` is set.
+	fn fetch(&self, url: &Url, read_body: bool) -> Result<Response, Error>;
+
+	/// Ask for `url`'s status only, which over HTTP is a HEAD request.
+	fn head(&self, url: &Url) -> Result<Response, Error> {
+		self.fetch(url, false)
+	}
+}
+
+/// Fetches over HTTP. The client is shared by all workers.
+pub struct ReqwestFetcher {
+	client: Client,
+}
+
+impl ReqwestFetcher {
+	/// Give up on connecting after `connect_timeout`, and on a request,
+	/// including reading the body, after `timeout` in total.
+	pub fn new(connect_timeout: Duration, timeout: Duration) -> Self {
+		let user_agent = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+		let client = Client::builder()
+			.user_agent(user_agent)
+			.connect_timeout(connect_timeout)
+			.timeout(timeout)
+			.build()
+			.unwrap();
+		Self::with_client(client)
+	}
+
+	pub fn with_client(client: Client) -> Self {
+		ReqwestFetcher { client }
+	}
+
+	fn send(request: RequestBuilder, read_body: bool) -> Result<Response, Error> {
+		let response = request.send()?;
+		let url = response.url().to_owned();
+		let status = response.status();
+		let retry_after = response
+			.headers()
+			.get(RETRY_AFTER)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| parse_retry_after(value, SystemTime::now()));
+		let body = if read_body {
+			Some(response.text()?)
+		} else {
+			None
+		};
+		Ok(Response {
+			url,
+			status,
+			body,
+			retry_after,
+		})
+	}
+}
+
+impl Fetcher for ReqwestFetcher {
+	fn fetch(&self, url: &Url, read_body: bool) -> Result<Response, Error> {
+		Self::send(self.client.get(url.clone()), read_body)
+	}
+
+	fn head(&self, url: &Url) -> Result<Response, Error> {
+		Self::send(self.client.head(url.clone()), false)
+	}
+}
+
+#[cfg(test)]
+pub use fake::FakeFetcher;
+
+#[cfg(test)]
+mod fake {
+	use std::collections::HashMap;
+	use std::path::Path;
+	use std::sync::Mutex;
+
+	use super::*;
+
+	/// A failed response's status and `Retry-After`.
+	type Failure = (StatusCode, Option<Duration>);
+
+	/// Serves pages from memory. URLs without a page are `404 Not Found`.
+	#[derive(Default)]
+	pub struct FakeFetcher {
+		pages: HashMap<Url, (StatusCode, String)>,
+		/// Responses served before the page.
+		failures: Mutex<HashMap<Url, Vec<Failure>>>,
+		/// Each fetch, with whether the body was read.
+		pub fetched: Mutex<Vec<(Url, bool)>>,
+	}
+
+	impl FakeFetcher {
+		pub fn page(self, url: &str, body: &str) -> Self {
+			self.response(url, StatusCode::OK, body)
+		}
+
+		pub fn response(mut self, url: &str, status: StatusCode, body: &str) -> Self {
+			let url = Url::parse(url).unwrap();
+			self.pages.insert(url, (status, body.to_string()));
+			self
+		}
+
+		/// Respond to the first `times` fetches of `url` with `status`.
+		pub fn failing(
+			self,
+			url: &str,
+			status: StatusCode,
+			retry_after: Option<Duration>,
+			times: usize,
+		) -> Self {
+			let url = Url::parse(url).unwrap();
+			let failures = vec![(status, retry_after); times];
+			self.failures.lock().unwrap().insert(url, failures);
+			self
+		}
+
+		/// Serve the files under `dir`, at their path relative to `base`.
+		pub fn from_fixture(base: &Url, dir: &Path) -> Self {
+			let mut fetcher = FakeFetcher::default();
+			fetcher.add_dir(base, dir, dir);
+			fetcher
+		}
+
+		fn add_dir(&mut self, base: &Url, root: &Path, dir: &Path) {
+			for entry in std::fs::read_dir(dir).unwrap() {
+				let path = entry.unwrap().path();
+				if path.is_dir() {
+					self.add_dir(base, root, &path);
+					continue;
+				}
+				let relative = path.strip_prefix(root).unwrap().to_str().unwrap();
+				let body = std::fs::read_to_string(&path).unwrap();
+				self.pages
+					.insert(base.join(relative).unwrap(), (StatusCode::OK, body));
+			}
+		}
+
+		/// The URLs fetched, sorted, as the workers fetch in any order.
+		pub fn fetched_urls(&self) -> Vec<String> {
+			let mut urls: Vec<String> = self
+				.fetched
+				.lock()
+				.unwrap()
+				.iter()
+				.map(|(url, _)| url.to_string())
+				.collect();
+			urls.sort();
+			urls
+		}
+	}
+
+	impl Fetcher for FakeFetcher {
+		fn fetch(&self, url: &Url, read_body: bool) -> Result<Response, Error> {
+			self.fetched.lock().unwrap().push((url.clone(), read_body));
+			// as over HTTP, the fragment is not part of the request
+			let mut page_url = url.clone();
+			page_url.set_fragment(None);
+			let failure = self
+				.failures
+				.lock()
+				.unwrap()
+				.get_mut(&page_url)
+				.and_then(|failures| failures.pop());
+			if let Some((status, retry_after)) = failure {
+				return Ok(Response {
+					url: url.clone(),
+					status,
+					body: read_body.then(String::new),
+					retry_after,
+				});
+			}
+			let (status, body) = self
+				.pages
+				.get(&page_url)
+				.cloned()
+				.unwrap_or((StatusCode::NOT_FOUND, String::new()));
+			Ok(Response {
+				url: url.clone(),
+				status,
+				body: read_body.then_some(body),
+				retry_after: None,
+			})
+		}
+	}
+}
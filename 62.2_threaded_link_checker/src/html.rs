@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+
+use crate::Link;
+
+/// The attributes holding links, for each element that has them.
+const LINK_ATTRIBUTES: &[(&str, &str)] = &[
+	("a", "href"),
+	("area", "href"),
+	("link", "href"),
+	("img", "src"),
+	("img", "srcset"),
+	("source", "src"),
+	("source", "srcset"),
+	("script", "src"),
+	("iframe", "src"),
+];
+
+/// `<link>` relations whose `href` is only a hint, not a resource to load.
+const HINT_RELS: &[&str] = &["dns-prefetch", "preconnect"];
+
+/// The schemes of links that are fetched; others, such as `mailto:`, are
+/// skipped.
+const FETCHED_SCHEMES: &[&str] = &["http", "https"];
+
+/// Every HTTP link in `document`, in document order, resolved against
+/// `base_url`.
+pub fn extract_links(document: &Html, base_url: &Url) -> Vec<Link> {
+	let selector = LINK_ATTRIBUTES
+		.iter()
+		.map(|(element, attribute)| format!("{element}[{attribute}]"))
+		.collect::<Vec<_>>()
+		.join(", ");
+	let selector = Selector::parse(&selector).unwrap();
+	let mut links = Vec::new();
+	for element in document.select(&selector) {
+		let name = element.value().name();
+		let rel = element.value().attr("rel").unwrap_or_default();
+		if name == "link" && rel.split_whitespace().any(|rel| HINT_RELS.contains(&rel)) {
+			continue;
+		}
+		let attributes = LINK_ATTRIBUTES
+			.iter()
+			.filter(|(element, _)| *element == name);
+		for (_, attribute) in attributes {
+			let Some(value) = element.value().attr(attribute) else {
+				continue;
+			};
+			let described = match (name, *attribute) {
+				("link", _) if rel.eq_ignore_ascii_case("canonical") => {
+					"link[rel=canonical]".to_string()
+				}
+				_ => format!("{name}[{attribute}]"),
+			};
+			let urls = if *attribute == "srcset" {
+				srcset_urls(value)
+			} else {
+				vec![value.trim()]
+			};
+			for href in urls {
+				match base_url.join(href) {
+					Ok(url) if !FETCHED_SCHEMES.contains(&url.scheme()) => {}
+					Ok(url) => links.push(Link {
+						url,
+						referrer: Some(base_url.clone()),
+						element: described.clone(),
+						text: element_text(element),
+					}),
+					Err(err) => {
+						println!("On {base_url:#}: ignored unparsable {href:?}: {err}");
+					}
+				}
+			}
+		}
+	}
+	links
+}
+
+/// The element's text, with whitespace collapsed, or for images their `alt`.
+fn element_text(element: ElementRef) -> String {
+	let text: Vec<&str> = element.text().flat_map(str::split_whitespace).collect();
+	if text.is_empty() {
+		let alt = element.value().attr("alt").unwrap_or_default();
+		return alt.split_whitespace().collect::<Vec<_>>().join(" ");
+	}
+	text.join(" ")
+}
+
+/// The URLs of a `srcset`'s candidates, each a URL and an optional width or
+/// density descriptor.
+fn srcset_urls(srcset: &str) -> Vec<&str> {
+	srcset
+		.split(',')
+		.filter_map(|candidate| candidate.split_whitespace().next())
+		.collect()
+}
+
+/// The fragments that `document` has a target for: the `id` of any element,
+/// and the `name` of `<a>` elements.
+pub fn anchors(document: &Html) -> HashSet<String> {
+	let selector = Selector::parse("[id], a[name]").unwrap();
+	let mut anchors = HashSet::new();
+	for element in document.select(&selector) {
+		let element = element.value();
+		anchors.extend(element.id().map(str::to_string));
+		if element.name() == "a" {
+			anchors.extend(element.attr("name").map(str::to_string));
+		}
+	}
+	anchors
+}
+
+/// Whether `fragment`, as found in a URL, has a target among `anchors`.
+/// Browsers scroll to the top for an empty fragment and for `top`.
+pub fn has_anchor(anchors: &HashSet<String>, fragment: &str) -> bool {
+	let fragment = percent_decode(fragment);
+	fragment.is_empty() || fragment.eq_ignore_ascii_case("top") || anchors.contains(&fragment)
+}
+
+fn percent_decode(s: &str) -> String {
+	let bytes = s.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		// two hex digits, not e.g. `+1`, which `from_str_radix` parses
+		let hex = bytes
+			.get(i + 1..i + 3)
+			.filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+			.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+		match (bytes[i], hex) {
+			(b'%', Some(byte)) => {
+				decoded.push(byte);
+				i += 3;
+			}
+			(byte, _) => {
+				decoded.push(byte);
+				i += 1;
+			}
+		}
+	}
+	String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_extract_links() {
+		let document = Html::parse_document(
+			r#"<html><head>
+			<link rel="stylesheet" href="style.css">
+			<link rel="preconnect" href="https://fonts.example.com">
+			<link rel="canonical" href="https://example.com/page">
+			<script src="/app.js"></script>
+			</head><body>
+			<a href="next.html">Next <b>page</b></a>
+			<img src="logo.png" srcset="logo-2x.png 2x, logo-3x.png 3x" alt="Logo">
+			<picture><source srcset="photo.webp 800w"></picture>
+			<map><area href="region.html" alt="Region"></map>
+			<iframe src="embed.html"></iframe>
+			<a href="mailto:someone@example.com">Mail</a> <a href="javascript:void(0)">Script</a>
+			<a href="tel:+15555550100">Call</a> <img src="data:image/gif;base64,R0lGOD">
+			<script>let notALink = "a.html";</script>
+			</body></html>"#,
+		);
+		let base_url = Url::parse("https://example.com/docs/").unwrap();
+		let links: Vec<(String, String, String)> = extract_links(&document, &base_url)
+			.into_iter()
+			.map(|link| {
+				assert_eq!(link.referrer.as_ref(), Some(&base_url));
+				(link.url.to_string(), link.element, link.text)
+			})
+			.collect();
+		let expected = [
+			("docs/style.css", "link[href]", ""),
+			("page", "link[rel=canonical]", ""),
+			("app.js", "script[src]", ""),
+			("docs/next.html", "a[href]", "Next page"),
+			("docs/logo.png", "img[src]", "Logo"),
+			("docs/logo-2x.png", "img[srcset]", "Logo"),
+			("docs/logo-3x.png", "img[srcset]", "Logo"),
+			("docs/photo.webp", "source[srcset]", ""),
+			("docs/region.html", "area[href]", "Region"),
+			("docs/embed.html", "iframe[src]", ""),
+		]
+		.map(|(path, element, text)| {
+			let url = format!("https://example.com/{path}");
+			(url, element.to_string(), text.to_string())
+		});
+		assert_eq!(links, expected);
+	}
+
+	#[test]
+	fn test_anchors() {
+		let document = Html::parse_document(
+			r#"<h1 id="intro">Intro</h1>
+			<a name="legacy"></a>
+			<input name="query">
+			<section id="caf&eacute;"></section>
+			<p id="%+1"></p>"#,
+		);
+		let anchors = anchors(&document);
+		assert!(has_anchor(&anchors, "intro"));
+		assert!(has_anchor(&anchors, "legacy"));
+		assert!(has_anchor(&anchors, "caf%C3%A9"));
+		assert!(has_anchor(&anchors, "%+1"));
+		assert!(has_anchor(&anchors, "top"));
+		assert!(has_anchor(&anchors, ""));
+		assert!(!has_anchor(&anchors, "query"));
+		assert!(!has_anchor(&anchors, "Intro"));
+	}
+}
@@ -16,145 +16,551 @@ Put an upper limit of 100 pages or so so that you don’t end up being blocked b
 */
 
 use std::{
-	collections::HashSet,
+	collections::{HashMap, HashSet},
 	fs::OpenOptions,
 	io::Write,
 	sync::{Arc, Mutex, mpsc},
+	time::{Duration, Instant},
 };
 
-use reqwest::Url;
-use reqwest::blocking::Client;
-use scraper::{Html, Selector};
+use reqwest::{StatusCode, Url};
+use scraper::Html;
 use std::thread;
 use thiserror::Error;
 
+mod canonical;
+mod fetch;
+mod html;
+mod options;
+mod rate_limit;
+mod report;
+mod retry;
+mod robots;
+mod state;
+
+use canonical::Canonicalizer;
+use fetch::{AGENT, Fetcher, ReqwestFetcher, Response};
+use options::{Options, USAGE};
+use rate_limit::RateLimiter;
+use retry::Retry;
+use robots::{Robots, RobotsCache};
+
 #[derive(Error, Debug)]
 enum Error {
 	#[error("request error: {0}")]
 	ReqwestError(#[from] reqwest::Error),
 	#[error("bad http response: {0}")]
-	BadResponse(String),
+	BadResponse(StatusCode),
 }
 
-#[derive(Debug)]
+/// What kind of failure made a URL broken, for machine-readable reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorKind {
+	Status,
+	Timeout,
+	Connect,
+	Redirect,
+	Body,
+	Request,
+	/// The page has no element for the URL's fragment.
+	Fragment,
+}
+
+impl ErrorKind {
+	fn as_str(self) -> &'static str {
+		match self {
+			ErrorKind::Status => "http_status",
+			ErrorKind::Timeout => "timeout",
+			ErrorKind::Connect => "connect",
+			ErrorKind::Redirect => "redirect",
+			ErrorKind::Body => "body",
+			ErrorKind::Request => "request",
+			ErrorKind::Fragment => "fragment",
+		}
+	}
+
+	fn parse(s: &str) -> Option<Self> {
+		use ErrorKind::*;
+		[Status, Timeout, Connect, Redirect, Body, Request, Fragment]
+			.into_iter()
+			.find(|kind| kind.as_str() == s)
+	}
+}
+
+impl Error {
+	fn kind(&self) -> ErrorKind {
+		match self {
+			Error::BadResponse(_) => ErrorKind::Status,
+			Error::ReqwestError(err) if err.is_timeout() => ErrorKind::Timeout,
+			Error::ReqwestError(err) if err.is_connect() => ErrorKind::Connect,
+			Error::ReqwestError(err) if err.is_redirect() => ErrorKind::Redirect,
+			Error::ReqwestError(err) if err.is_body() || err.is_decode() => ErrorKind::Body,
+			Error::ReqwestError(_) => ErrorKind::Request,
+		}
+	}
+
+	/// Whether the request may succeed when tried again: the connection
+	/// dropped, rather than e.g. being refused.
+	fn is_transient(&self) -> bool {
+		let Error::ReqwestError(err) = self else {
+			return false;
+		};
+		let mut source = std::error::Error::source(err);
+		while let Some(err) = source {
+			if let Some(err) = err.downcast_ref::<std::io::Error>()
+				&& matches!(
+					err.kind(),
+					std::io::ErrorKind::ConnectionReset
+						| std::io::ErrorKind::ConnectionAborted
+						| std::io::ErrorKind::BrokenPipe
+						| std::io::ErrorKind::UnexpectedEof
+				) {
+				return true;
+			}
+			source = err.source();
+		}
+		false
+	}
+
+	/// The response's status, if there was a response.
+	fn status(&self) -> Option<StatusCode> {
+		match self {
+			Error::BadResponse(status) => Some(*status),
+			Error::ReqwestError(err) => err.status(),
+		}
+	}
+}
+
+/// A link to `url`, and where it was found.
+#[derive(Debug, Clone, PartialEq)]
+struct Link {
+	url: Url,
+	/// The page containing the link; `None` for start URLs.
+	referrer: Option<Url>,
+	/// The element and attribute holding the link, as a selector.
+	element: String,
+	/// The element's text, with whitespace collapsed.
+	text: String,
+}
+
+impl Link {
+	fn start(url: &Url) -> Self {
+		Link {
+			url: url.clone(),
+			referrer: None,
+			element: String::new(),
+			text: String::new(),
+		}
+	}
+
+	/// Whether the link leads to a page that may have links of its own,
+	/// rather than to e.g. an image or stylesheet.
+	fn is_page(&self) -> bool {
+		self.referrer.is_none()
+			|| matches!(
+				self.element.as_str(),
+				"a[href]" | "area[href]" | "iframe[src]" | "link[rel=canonical]"
+			)
+	}
+}
+
+#[derive(Debug, Clone)]
 struct CrawlCommand {
+	/// The first link found to `url`.
+	link: Link,
+	/// The link's canonical URL, which the crawl state is keyed by.
 	url: Url,
 	extract_links: bool,
+	/// Only the page's fragment targets are wanted, as the page was checked
+	/// already without reading its body.
+	anchors_only: bool,
+	/// How many links were followed from a start URL to reach the link.
+	depth: usize,
 }
 
-// check a specific url
-fn visit_page(client: &Client, command: &CrawlCommand) -> Result<Vec<Url>, Error> {
-	println!("{:#}", command.url);
-	let response = client.get(command.url.clone()).send()?;
-	if !response.status().is_success() {
-		return Err(Error::BadResponse(response.status().to_string()));
+impl CrawlCommand {
+	/// The URL to fetch: the link's own, as the server may not treat its
+	/// canonical form the same, without the fragment, which is never sent.
+	fn fetch_url(&self) -> Url {
+		let mut url = self.link.url.clone();
+		url.set_fragment(None);
+		url
 	}
+}
 
-	let mut link_urls = Vec::new();
-	if !command.extract_links {
-		return Ok(link_urls);
+/// A page that loaded, with the links found on it.
+#[derive(Debug)]
+struct Page {
+	status: StatusCode,
+	links: Vec<Link>,
+	/// The fragments the page has targets for, if its body was read.
+	anchors: Option<HashSet<String>>,
+}
+
+/// What the workers share.
+struct WorkerContext {
+	fetcher: Arc<dyn Fetcher>,
+	/// `None` when robots.txt is ignored.
+	robots: Option<RobotsCache>,
+	rate_limiter: RateLimiter,
+	retry: Retry,
+}
+
+impl WorkerContext {
+	fn new(options: &Options, fetcher: Arc<dyn Fetcher>) -> Self {
+		WorkerContext {
+			fetcher,
+			robots: options.robots.then(RobotsCache::default),
+			rate_limiter: RateLimiter::new(options.rate, options.rate.ceil() as u32),
+			retry: options.retry.clone(),
+		}
 	}
 
-	let base_url = response.url().to_owned();
-	let body_text = response.text()?;
-	let document = Html::parse_document(&body_text);
+	/// Fetch and parse a robots.txt. A missing or failing one allows
+	/// everything.
+	fn load_robots(&self, robots_url: &Url) -> Robots {
+		let host = robots_url.host_str().unwrap_or_default();
+		self.rate_limiter.wait(host);
+		let robots = match self.fetcher.fetch(robots_url, true) {
+			Ok(Response {
+				status,
+				body: Some(body),
+				..
+			}) if status.is_success() => Robots::parse(&body, AGENT),
+			_ => Robots::default(),
+		};
+		if let Some(delay) = robots.crawl_delay {
+			self.rate_limiter.slow_down(host, delay);
+		}
+		robots
+	}
 
-	let selector = Selector::parse("a").unwrap();
-	let href_values = document
-		.select(&selector)
-		.filter_map(|element| element.value().attr("href"));
-	for href in href_values {
-		match base_url.join(href) {
-			Ok(link_url) => {
-				link_urls.push(link_url);
+	/// Fetch `url`, retrying with backoff while it fails in a way that may
+	/// pass. Without `read_body`, a HEAD request is tried first, and GET only
+	/// if the server does not support HEAD or the request fails.
+	fn fetch(&self, url: &Url, read_body: bool) -> Result<Response, Error> {
+		if !read_body {
+			let outcome = self.retrying(url, || self.fetcher.head(url));
+			if let Ok(response) = &outcome
+				&& !matches!(
+					response.status,
+					StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+				) {
+				return outcome;
 			}
-			Err(err) => {
-				println!("On {base_url:#}: ignored unparsable {href:?}: {err}");
+		}
+		self.retrying(url, || self.fetcher.fetch(url, read_body))
+	}
+
+	/// Send a request to `url`, again while it fails in a way that may pass.
+	/// Each attempt waits for the rate limiter.
+	fn retrying(
+		&self,
+		url: &Url,
+		send: impl Fn() -> Result<Response, Error>,
+	) -> Result<Response, Error> {
+		let host = url.host_str().unwrap_or_default();
+		let mut attempt = 0;
+		loop {
+			self.rate_limiter.wait(host);
+			let outcome = send();
+			let retry_after = match &outcome {
+				Ok(response) if retry::is_transient(response.status) => response.retry_after,
+				Err(err) if err.is_transient() => None,
+				_ => return outcome,
+			};
+			if attempt == self.retry.retries {
+				return outcome;
 			}
+			let delay = self.retry.delay(attempt, retry_after);
+			println!("retrying {url:#} in {delay:?}");
+			thread::sleep(delay);
+			attempt += 1;
 		}
 	}
-	Ok(link_urls)
 }
-// from solution
-type CrawlResult = Result<Vec<Url>, (Url, Error)>;
+
+// check a specific url; `None` if robots.txt disallows it
+fn visit_page(context: &WorkerContext, command: &CrawlCommand) -> Result<Option<Page>, Error> {
+	let url = &command.fetch_url();
+	if let Some(robots) = &context.robots
+		&& !robots
+			.get(url, |robots_url| context.load_robots(robots_url))
+			.is_allowed(url)
+	{
+		return Ok(None);
+	}
+	println!("{:#}", url);
+	// the body is also needed to check the fragment's target exists
+	let read_body =
+		command.extract_links || command.anchors_only || command.link.url.fragment().is_some();
+	let response = context.fetch(url, read_body)?;
+	if !response.status.is_success() {
+		return Err(Error::BadResponse(response.status));
+	}
+
+	let mut page = Page {
+		status: response.status,
+		links: Vec::new(),
+		anchors: None,
+	};
+	let Some(body_text) = response.body else {
+		return Ok(Some(page));
+	};
+
+	let document = Html::parse_document(&body_text);
+	page.anchors = Some(html::anchors(&document));
+	if command.extract_links {
+		page.links = html::extract_links(&document, &response.url);
+	}
+	Ok(Some(page))
+}
+
+/// What a worker found for a `CrawlCommand`.
+struct CrawlResult {
+	command: CrawlCommand,
+	elapsed: Duration,
+	outcome: Result<Option<Page>, Error>,
+}
 
 // mpsc: CrawlCommand
 fn main() {
-	let start_url = reqwest::Url::parse("https://www.google.org").unwrap();
-	let args: Vec<String> = std::env::args().collect();
-	let save_file = args.get(1).map(|s| s.as_str());
-	if let Some(file) = save_file {
-		eprintln!("{file} argument provided!")
+	let options = match Options::parse(std::env::args().skip(1)) {
+		Ok(Some(options)) => options,
+		Ok(None) => {
+			print!("{USAGE}");
+			return;
+		}
+		Err(err) => {
+			eprintln!("error: {err}\n\n{USAGE}");
+			std::process::exit(2);
+		}
+	};
+	let fetcher = Arc::new(ReqwestFetcher::new(
+		options.connect_timeout,
+		options.timeout,
+	));
+	let crawl_state = match (&options.state, options.resume) {
+		(Some(path), true) => match CrawlState::load(path, &options) {
+			Ok(crawl_state) => crawl_from(&options, crawl_state, fetcher),
+			Err(err) => {
+				eprintln!("error: resuming from {path}: {err}");
+				std::process::exit(2);
+			}
+		},
+		_ => crawl(&options, fetcher),
+	};
+	let broken = crawl_state.bad_urls().next().is_some();
+	if broken && let Err(err) = report::write_broken_links(&crawl_state, &mut std::io::stderr()) {
+		eprintln!("error: writing broken links: {err}");
+		std::process::exit(2);
+	}
+	if let Err(err) = crawl_state.filedump(options.output.as_deref()) {
+		eprintln!("error: writing visited URLs: {err}");
+		std::process::exit(2);
+	}
+	if let Err(err) = report::write_reports(&options, &crawl_state) {
+		eprintln!("error: writing reports: {err}");
+		std::process::exit(2);
+	}
+	// lets CI fail the build on broken links
+	if broken {
+		std::process::exit(1);
 	}
-	check_sites(start_url, save_file);
 }
 
 ///
 /// runs the loop until no more endpoints remaining
 fn worker_crawl_thread(
+	context: Arc<WorkerContext>,
 	command_receiver: Arc<Mutex<std::sync::mpsc::Receiver<CrawlCommand>>>,
 	result_sender: mpsc::Sender<CrawlResult>,
 ) {
-	let client = Client::new();
 	loop {
 		// check endpoints, send result on channel
 		let crawl_command = match command_receiver.lock().unwrap().recv() {
 			Ok(crawlcommand) => crawlcommand,
 			Err(_) => break,
 		};
-		let crawl_result = match visit_page(
-			&client,
+		let start = Instant::now();
+		let outcome = visit_page(
+			&context,
 			&crawl_command, /* from command_receiver after recv() */
-		) {
-			Ok(links) => Ok(links),
-			Err(err) => Err((crawl_command.url, err)),
+		);
+		let crawl_result = CrawlResult {
+			command: crawl_command,
+			elapsed: start.elapsed(),
+			outcome,
 		};
 		result_sender.send(crawl_result).unwrap();
 	}
 }
 fn spawn_workers(
+	num_workers: usize,
+	context: Arc<WorkerContext>,
 	command_receiver: mpsc::Receiver<CrawlCommand>,
 	result_sender: mpsc::Sender<CrawlResult>,
 ) {
 	// wrap command_receiver in mutex
 	let command_receiver_guarded = Arc::new(Mutex::new(command_receiver));
-	for _ in 0..NUM_THREADS {
+	for _ in 0..num_workers {
 		let command_receiver_guard = command_receiver_guarded.clone();
 		let result_sender = result_sender.clone();
+		let context = context.clone();
 		thread::spawn(move || {
-			worker_crawl_thread(command_receiver_guard, result_sender);
+			worker_crawl_thread(context, command_receiver_guard, result_sender);
 		});
 	}
 }
 
+/// How fetching a URL went.
+#[derive(Debug)]
+struct UrlResult {
+	url: Url,
+	/// `None` when no response arrived.
+	status: Option<StatusCode>,
+	/// Set when the URL is broken.
+	error: Option<UrlError>,
+	elapsed: Duration,
+}
+
+#[derive(Debug)]
+struct UrlError {
+	kind: ErrorKind,
+	message: String,
+}
+
 struct CrawlState {
-	domain: String,
+	domains: HashSet<String>,
+	canonicalizer: Canonicalizer,
+	/// The canonical URLs queued so far.
 	visited_sites: std::collections::HashSet<String>,
+	/// Every link found to each URL, keyed like `visited_sites`, or by
+	/// canonical URL and fragment for fragments with no target.
+	links: HashMap<String, Vec<Link>>,
+	/// One per URL checked, in the order they finished, then one per
+	/// fragment with no target.
+	results: Vec<UrlResult>,
+	/// The fragment targets of pages whose body was read, keyed like
+	/// `visited_sites`.
+	anchors: HashMap<String, HashSet<String>>,
+	/// The commands sent to workers that have no result yet, keyed by URL.
+	pending: HashMap<String, CrawlCommand>,
 }
 
 impl CrawlState {
-	fn new(start_url: &Url) -> Self {
-		let mut new = CrawlState {
+	/// Pages on the start URLs' domains are crawled; the start URLs are
+	/// marked visited as they are queued.
+	fn new(options: &Options) -> Self {
+		CrawlState {
 			visited_sites: HashSet::new(),
-			domain: start_url.domain().unwrap().to_string(),
-		};
-		new.visited_sites.insert(start_url.to_string());
-		new
+			canonicalizer: options.canonical.clone(),
+			links: HashMap::new(),
+			results: Vec::new(),
+			anchors: HashMap::new(),
+			pending: HashMap::new(),
+			domains: options
+				.start_urls
+				.iter()
+				.map(|url| url.domain().unwrap().to_string())
+				.collect(),
+		}
 	}
 	///
 	/// is domain, has host, not just IP
 	fn should_descend_endpoints(&self, url: &Url) -> bool {
 		if let Some(url_endpoint) = url.domain() {
-			url_endpoint == self.domain
+			self.domains.contains(url_endpoint)
 		} else {
 			false
 		}
 	}
 	///
-	/// not previously encountered
+	/// not previously encountered; `url` is canonical
 	fn mark_visited(&mut self, url: &Url) -> bool {
 		self.visited_sites.insert(url.to_string())
 	}
+	///
+	/// send a command to the workers, remembering it until its result
+	/// arrives
+	fn dispatch(&mut self, command: CrawlCommand, command_sender: &mpsc::Sender<CrawlCommand>) {
+		self.pending
+			.insert(command.url.to_string(), command.clone());
+		command_sender.send(command).unwrap();
+	}
+	///
+	/// remember where a link to the canonical `url` was found, for
+	/// reporting, unless the page has the same link already
+	fn record_link(&mut self, url: &Url, link: &Link) {
+		let links = self.links.entry(url.to_string()).or_default();
+		if !links.contains(link) {
+			links.push(link.clone());
+		}
+	}
+	///
+	/// commands to read the pages that loaded without their body being
+	/// read, but that links with a fragment lead to, found after the page
+	/// was queued
+	fn anchor_commands(&self) -> Vec<CrawlCommand> {
+		let unread = self.results.iter().filter(|result| {
+			result.error.is_none() && !self.anchors.contains_key(result.url.as_str())
+		});
+		unread
+			.filter_map(|result| {
+				let links = &self.links[result.url.as_str()];
+				let link = links.iter().find(|link| link.url.fragment().is_some())?;
+				Some(CrawlCommand {
+					link: link.clone(),
+					url: result.url.clone(),
+					extract_links: false,
+					anchors_only: true,
+					depth: 0,
+				})
+			})
+			.collect()
+	}
+	///
+	/// move the links whose fragment has no target on their page to a
+	/// broken URL of their own, one per fragment
+	fn check_fragments(&mut self) {
+		let mut broken = Vec::new();
+		for result in &self.results {
+			let Some(anchors) = self.anchors.get(result.url.as_str()) else {
+				continue;
+			};
+			let links = self.links.get_mut(result.url.as_str()).unwrap();
+			let (missing, found): (Vec<Link>, Vec<Link>) = links.drain(..).partition(|link| {
+				link.url
+					.fragment()
+					.is_some_and(|fragment| !html::has_anchor(anchors, fragment))
+			});
+			*links = found;
+			for link in missing {
+				let fragment = link.url.fragment().unwrap();
+				let mut url = result.url.clone();
+				url.set_fragment(Some(fragment));
+				let fragment_links = self.links.entry(url.to_string()).or_default();
+				if fragment_links.is_empty() {
+					broken.push(UrlResult {
+						status: result.status,
+						error: Some(UrlError {
+							kind: ErrorKind::Fragment,
+							message: format!("no element with id or name {fragment:?}"),
+						}),
+						elapsed: result.elapsed,
+						url,
+					});
+				}
+				fragment_links.push(link);
+			}
+		}
+		self.results.extend(broken);
+	}
+	///
+	/// the URLs that failed to load
+	fn bad_urls(&self) -> impl Iterator<Item = &UrlResult> {
+		self.results.iter().filter(|result| result.error.is_some())
+	}
 	/// write visited sites to file
 	fn filedump(&self, save_file: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
 		if let Some(filename) = save_file {
@@ -171,66 +577,732 @@ impl CrawlState {
 	}
 }
 
-const NUM_THREADS: usize = 16;
+/// How often the crawl state is saved, when there is a state file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
 ///
-/// stores crawlstate, updates visited & bad urls
+/// stores crawlstate, updates visited & bad urls; a resumed `crawl_state`
+/// has its pending commands sent again
 fn monitor_workers(
-	start_url: Url,
+	options: &Options,
+	mut crawl_state: CrawlState,
 	command_sender: mpsc::Sender<CrawlCommand>,
 	result_receiver: mpsc::Receiver<CrawlResult>,
-	save_file: Option<&str>,
-) -> Result<(), Box<(dyn std::error::Error + 'static)>> {
-	// initialize crawlstate
-	let mut crawl_state = CrawlState::new(&start_url);
-	let initial_crawl_command = CrawlCommand {
-		url: start_url,
-		extract_links: true,
-	};
-	command_sender.send(initial_crawl_command).unwrap();
-	let mut sites_remaining = 1;
-	let mut bad_urls: Vec<Url> = vec![];
+) -> CrawlState {
+	// links are extracted from pages less than max_depth links away
+	let within_depth = |depth: usize| options.max_depth.is_none_or(|max| depth < max);
+	for command in crawl_state.pending.values() {
+		command_sender.send(command.clone()).unwrap();
+	}
+	for url in &options.start_urls {
+		if crawl_state.visited_sites.len() >= options.max_pages {
+			break;
+		}
+		let link = Link::start(url);
+		let url = crawl_state.canonicalizer.canonicalize(url);
+		crawl_state.record_link(&url, &link);
+		if crawl_state.mark_visited(&url) {
+			let initial_crawl_command = CrawlCommand {
+				link,
+				url,
+				extract_links: within_depth(0),
+				anchors_only: false,
+				depth: 0,
+			};
+			crawl_state.dispatch(initial_crawl_command, &command_sender);
+		}
+	}
 
-	while sites_remaining > 0 {
+	let mut saved = Instant::now();
+	// pages whose body is read for their fragment targets only, at most once
+	let mut anchors_requested = HashSet::new();
+	loop {
+		if crawl_state.pending.is_empty() {
+			let commands: Vec<CrawlCommand> = crawl_state
+				.anchor_commands()
+				.into_iter()
+				.filter(|command| anchors_requested.insert(command.url.to_string()))
+				.collect();
+			if commands.is_empty() {
+				break;
+			}
+			for command in commands {
+				crawl_state.dispatch(command, &command_sender);
+			}
+		}
+		// save progress, so that a crash loses little
+		if let Some(path) = &options.state
+			&& saved.elapsed() >= SAVE_INTERVAL
+		{
+			if let Err(err) = crawl_state.save(path) {
+				eprintln!("error: saving crawl state to {path}: {err}");
+			}
+			saved = Instant::now();
+		}
 		// receive results
 		let crawl_result = result_receiver.recv().unwrap();
-		sites_remaining -= 1;
+		let CrawlResult {
+			command,
+			elapsed,
+			outcome,
+		} = crawl_result;
+		crawl_state.pending.remove(command.url.as_str());
+		if command.anchors_only {
+			if let Ok(Some(Page {
+				anchors: Some(anchors),
+				..
+			})) = outcome
+			{
+				crawl_state.anchors.insert(command.url.to_string(), anchors);
+			}
+			continue;
+		}
+		let depth = command.depth;
 		// match, append and redispatch or error out
-		match crawl_result {
-			Ok(urls) => {
-				for url in urls {
+		match outcome {
+			Ok(None) => {
+				println!("skipped {:#}: disallowed by robots.txt", command.url);
+			}
+			Ok(Some(page)) => {
+				if let Some(anchors) = page.anchors {
+					crawl_state.anchors.insert(command.url.to_string(), anchors);
+				}
+				crawl_state.results.push(UrlResult {
+					url: command.url,
+					status: Some(page.status),
+					error: None,
+					elapsed,
+				});
+				for link in page.links {
+					// stop queueing once the page limit is reached
+					if crawl_state.visited_sites.len() >= options.max_pages {
+						break;
+					}
+					let url = crawl_state.canonicalizer.canonicalize(&link.url);
+					crawl_state.record_link(&url, &link);
 					// check if visited, otherwise mark as visited
 					if crawl_state.mark_visited(&url) {
 						// determine if we should extract links
-						let extract_links = crawl_state.should_descend_endpoints(&url);
+						let extract_links = link.is_page()
+							&& crawl_state.should_descend_endpoints(&url)
+							&& within_depth(depth + 1);
 						// set up CrawlCommand and send
-						command_sender
-							.send(CrawlCommand {
-								extract_links,
-								url: url.clone(),
-							})
-							.unwrap();
-						sites_remaining += 1;
+						let command = CrawlCommand {
+							extract_links,
+							anchors_only: false,
+							link,
+							url,
+							depth: depth + 1,
+						};
+						crawl_state.dispatch(command, &command_sender);
 					}
 				}
 			}
-			Err((url, err)) => {
-				bad_urls.push(url);
+			Err(err) => {
 				eprintln!("crawling error: {:#}", err);
+				crawl_state.results.push(UrlResult {
+					url: command.url,
+					status: err.status(),
+					error: Some(UrlError {
+						kind: err.kind(),
+						message: err.to_string(),
+					}),
+					elapsed,
+				});
 				continue;
 			}
 		}
 	}
-	if !bad_urls.is_empty() {
-		eprintln!("Bad URLs: {:#?}", bad_urls);
+	if let Some(path) = &options.state
+		&& let Err(err) = crawl_state.save(path)
+	{
+		eprintln!("error: saving crawl state to {path}: {err}");
 	}
-	crawl_state.filedump(save_file)
+	crawl_state.check_fragments();
+	crawl_state
 }
 
 // sets up infrastructure for supervising/monitoring as well as dispatching workers
-fn check_sites(start_url: Url, save_file: Option<&str>) {
+fn crawl(options: &Options, fetcher: Arc<dyn Fetcher>) -> CrawlState {
+	crawl_from(options, CrawlState::new(options), fetcher)
+}
+
+/// Continue the crawl that `crawl_state` has made so far.
+fn crawl_from(options: &Options, crawl_state: CrawlState, fetcher: Arc<dyn Fetcher>) -> CrawlState {
 	// from solution: use command_sender, command_receiver, result_sender, result_receiver)
 	let (command_sender, command_receiver) = mpsc::channel::<CrawlCommand>();
 	let (result_sender, result_receiver) = mpsc::channel::<CrawlResult>();
-	spawn_workers(command_receiver, result_sender);
-	monitor_workers(start_url, command_sender, result_receiver, save_file).unwrap();
+	let context = Arc::new(WorkerContext::new(options, fetcher));
+	spawn_workers(options.workers, context, command_receiver, result_sender);
+	monitor_workers(options, crawl_state, command_sender, result_receiver)
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{BufRead, BufReader};
+	use std::net::TcpListener;
+	use std::path::{Path, PathBuf};
+
+	use reqwest::StatusCode;
+	use reqwest::blocking::Client;
+
+	use super::*;
+	use crate::fetch::FakeFetcher;
+
+	fn fixture_dir() -> PathBuf {
+		Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/site")
+	}
+
+	/// Options for crawling from `start_url`, ignoring robots.txt so that
+	/// only the pages themselves are fetched, and retrying quickly.
+	fn options(start_url: &Url) -> Options {
+		Options {
+			start_urls: vec![start_url.clone()],
+			workers: 4,
+			robots: false,
+			retry: Retry {
+				retries: 2,
+				delay: Duration::from_millis(10),
+			},
+			..Options::default()
+		}
+	}
+
+	fn sorted(urls: impl IntoIterator<Item = impl ToString>) -> Vec<String> {
+		let mut urls: Vec<String> = urls.into_iter().map(|url| url.to_string()).collect();
+		urls.sort();
+		urls
+	}
+
+	/// The URLs linked to on the fixture site, relative to `base`, without
+	/// fragments.
+	fn fixture_urls(base: &Url) -> Vec<String> {
+		sorted(
+			[
+				"index.html",
+				"about.html",
+				"missing.html",
+				"docs/guide.html",
+				"docs/deep.html",
+				"docs/nowhere.html",
+				"docs/media.html",
+				"docs/style.css",
+				"docs/app.js",
+				"docs/logo.png",
+				"docs/logo-2x.png",
+				"docs/photo.webp",
+			]
+			.map(|path| base.join(path).unwrap()),
+		)
+	}
+
+	/// The broken URLs on the fixture site, with the fragments that have no
+	/// target.
+	fn fixture_broken_urls(base: &Url) -> Vec<String> {
+		sorted(
+			[
+				"missing.html",
+				"docs/nowhere.html",
+				"docs/logo-2x.png",
+				"docs/photo.webp",
+				"docs/guide.html#uninstall",
+			]
+			.map(|path| base.join(path).unwrap()),
+		)
+	}
+
+	/// Serve `fixture_dir()` over HTTP on a free local port, one connection
+	/// at a time, and return the URL of its index page, with the request
+	/// lines received. Without `allow_head`, HEAD requests get `405
+	/// Method Not Allowed`.
+	fn serve_fixture_site(allow_head: bool) -> (Url, Arc<Mutex<Vec<String>>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let requests = Arc::new(Mutex::new(Vec::new()));
+		let received = requests.clone();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = stream.unwrap();
+				let mut request_line = String::new();
+				let mut reader = BufReader::new(&stream);
+				reader.read_line(&mut request_line).unwrap();
+				// skip the headers
+				let mut line = String::new();
+				while reader.read_line(&mut line).unwrap() > 2 {
+					line.clear();
+				}
+				let mut parts = request_line.split(' ');
+				let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+				received.lock().unwrap().push(format!("{method} {path}"));
+				let (status, mut body) =
+					match std::fs::read_to_string(fixture_dir().join(&path[1..])) {
+						_ if method == "HEAD" && !allow_head => {
+							("405 Method Not Allowed", String::new())
+						}
+						Ok(body) => ("200 OK", body),
+						Err(_) => ("404 Not Found", String::new()),
+					};
+				let length = body.len();
+				if method == "HEAD" {
+					body.clear();
+				}
+				let _ = write!(
+					stream,
+					"HTTP/1.1 {status}\r\nContent-Type: text/html\r\n\
+					Content-Length: {length}\r\nConnection: close\r\n\r\n{body}"
+				);
+			}
+		});
+		let start_url = Url::parse(&format!("http://localhost:{port}/index.html")).unwrap();
+		(start_url, requests)
+	}
+
+	#[test]
+	fn test_crawl_fixture_site() {
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fetcher = Arc::new(FakeFetcher::from_fixture(&start_url, &fixture_dir()));
+		let crawl_state = crawl(&options(&start_url), fetcher.clone());
+		assert_eq!(sorted(&crawl_state.visited_sites), fixture_urls(&start_url));
+		assert_eq!(fetcher.fetched_urls(), fixture_urls(&start_url));
+		assert_eq!(
+			sorted(crawl_state.bad_urls().map(|bad_url| &bad_url.url)),
+			fixture_broken_urls(&start_url)
+		);
+		// only pages are read, but not images, scripts and stylesheets, and
+		// each only once
+		let fetched = fetcher.fetched.lock().unwrap();
+		let read: Vec<String> = sorted(
+			fetched
+				.iter()
+				.filter(|(_, read_body)| *read_body)
+				.map(|(url, _)| url.path()),
+		);
+		assert_eq!(
+			read,
+			[
+				"/about.html",
+				"/docs/deep.html",
+				"/docs/guide.html",
+				"/docs/media.html",
+				"/docs/nowhere.html",
+				"/index.html",
+				"/missing.html",
+			]
+		);
+	}
+
+	#[test]
+	fn test_fragments() {
+		let crawl_state = crawl(
+			&options(&Url::parse("https://example.com/").unwrap()),
+			Arc::new(FakeFetcher::default().page(
+				"https://example.com/",
+				r##"<a href="#top">Top</a> <a href="#main">Main</a> <a href="#">Here</a>
+				<a href="#nowhere">Nowhere</a> <a href="/other#x">Elsewhere</a> <a name="main"></a>"##,
+			)),
+		);
+		let results = sorted(crawl_state.results.iter().map(|result| {
+			let kind = result.error.as_ref().map(|error| error.kind);
+			format!("{} {kind:?}", result.url)
+		}));
+		assert_eq!(
+			results,
+			[
+				"https://example.com/ None",
+				"https://example.com/#nowhere Some(Fragment)",
+				"https://example.com/other Some(Status)",
+			]
+		);
+		// the page's other links are still there, and reported under their
+		// own URL
+		let links: Vec<&str> = crawl_state.links["https://example.com/"]
+			.iter()
+			.map(|link| link.url.as_str())
+			.collect();
+		assert_eq!(
+			links,
+			[
+				"https://example.com/",
+				"https://example.com/#top",
+				"https://example.com/#main",
+				"https://example.com/#"
+			]
+		);
+	}
+
+	#[test]
+	fn test_canonical_urls() {
+		let fetcher = Arc::new(FakeFetcher::default().page(
+			"https://example.com/",
+			r#"<a href="/docs/Page/?b=2&a=1">a</a> <a href="/docs/page#intro">b</a>
+			<a href="HTTPS://EXAMPLE.COM:443/docs/page?a=1&b=2&utm_source=x">c</a>
+			<a href="/docs/%70age/">d</a> <a href="/docs/page?a=1&b=2">e</a>"#,
+		));
+		let start_url = Url::parse("https://example.com/").unwrap();
+		let options = Options {
+			canonical: Canonicalizer {
+				ignore_case: true,
+				strip_slash: true,
+				strip_tracking: true,
+				..Canonicalizer::default()
+			},
+			..options(&start_url)
+		};
+		let crawl_state = crawl(&options, fetcher.clone());
+		// the first link to each canonical URL is fetched as written, less
+		// its fragment
+		assert_eq!(
+			fetcher.fetched_urls(),
+			[
+				"https://example.com/",
+				"https://example.com/docs/Page/?b=2&a=1",
+				"https://example.com/docs/page",
+			]
+		);
+		// reports show the links as they were written
+		let texts = |url: &str| -> Vec<(String, String)> {
+			crawl_state.links[url]
+				.iter()
+				.map(|link| (link.url.to_string(), link.text.clone()))
+				.collect()
+		};
+		assert_eq!(
+			texts("https://example.com/docs/page"),
+			[
+				(
+					"https://example.com/docs/page#intro".to_string(),
+					"b".to_string()
+				),
+				(
+					"https://example.com/docs/%70age/".to_string(),
+					"d".to_string()
+				),
+			]
+		);
+		assert_eq!(texts("https://example.com/docs/page?a=1&b=2").len(), 3);
+	}
+
+	#[test]
+	fn test_fragment_after_plain_link() {
+		// the page is checked for the first link, without reading its body
+		let fetcher = FakeFetcher::default()
+			.page(
+				"https://example.com/",
+				r#"<a href="https://example.org/leaf">Leaf</a>
+				<a href="https://example.org/leaf#missing">Missing</a>
+				<a href="https://example.org/leaf#here">Here</a>"#,
+			)
+			.page("https://example.org/leaf", r#"<p id="here"></p>"#);
+		let fetcher = Arc::new(fetcher);
+		let start_url = Url::parse("https://example.com/").unwrap();
+		let crawl_state = crawl(&options(&start_url), fetcher.clone());
+		let results = sorted(crawl_state.results.iter().map(|result| {
+			let kind = result.error.as_ref().map(|error| error.kind);
+			format!("{} {kind:?}", result.url)
+		}));
+		assert_eq!(
+			results,
+			[
+				"https://example.com/ None",
+				"https://example.org/leaf None",
+				"https://example.org/leaf#missing Some(Fragment)",
+			]
+		);
+		let leaf = Url::parse("https://example.org/leaf").unwrap();
+		let fetched = fetcher.fetched.lock().unwrap();
+		let leaf_fetches: Vec<bool> = fetched
+			.iter()
+			.filter(|(url, _)| *url == leaf)
+			.map(|(_, read_body)| *read_body)
+			.collect();
+		assert_eq!(leaf_fetches, [false, true]);
+	}
+
+	#[test]
+	fn test_other_domains_not_crawled() {
+		let fetcher = Arc::new(
+			FakeFetcher::default()
+				.page(
+					"https://example.com/",
+					r#"<a href="https://example.org/">elsewhere</a>"#,
+				)
+				.page("https://example.org/", r#"<a href="/private">private</a>"#),
+		);
+		let start_url = Url::parse("https://example.com/").unwrap();
+		let crawl_state = crawl(&options(&start_url), fetcher.clone());
+		assert_eq!(crawl_state.bad_urls().count(), 0);
+		let fetched = fetcher.fetched.lock().unwrap();
+		assert_eq!(
+			*fetched,
+			[
+				(start_url, true),
+				(Url::parse("https://example.org/").unwrap(), false)
+			]
+		);
+	}
+
+	#[test]
+	fn test_max_depth() {
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fetcher = Arc::new(FakeFetcher::from_fixture(&start_url, &fixture_dir()));
+		let options = Options {
+			max_depth: Some(1),
+			..options(&start_url)
+		};
+		crawl(&options, fetcher.clone());
+		// links on pages one link away are not followed
+		assert_eq!(
+			fetcher.fetched_urls(),
+			[
+				"https://example.com/about.html",
+				"https://example.com/docs/guide.html",
+				"https://example.com/index.html",
+				"https://example.com/missing.html",
+			]
+		);
+		assert!(
+			fetcher
+				.fetched
+				.lock()
+				.unwrap()
+				.iter()
+				.all(|(url, read_body)| *read_body == (*url == start_url))
+		);
+	}
+
+	#[test]
+	fn test_max_pages() {
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fetcher = Arc::new(FakeFetcher::from_fixture(&start_url, &fixture_dir()));
+		let options = Options {
+			max_pages: 2,
+			..options(&start_url)
+		};
+		let crawl_state = crawl(&options, fetcher.clone());
+		assert_eq!(
+			fetcher.fetched_urls(),
+			[
+				"https://example.com/about.html",
+				"https://example.com/index.html"
+			]
+		);
+		assert_eq!(crawl_state.visited_sites.len(), 2);
+	}
+
+	#[test]
+	fn test_robots_txt() {
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fetcher = FakeFetcher::from_fixture(&start_url, &fixture_dir()).page(
+			"https://example.com/robots.txt",
+			"User-agent: *\nDisallow: /docs/\nAllow: /docs/deep.html\n",
+		);
+		let fetcher = Arc::new(fetcher);
+		let options = Options {
+			robots: true,
+			..options(&start_url)
+		};
+		let crawl_state = crawl(&options, fetcher.clone());
+		assert_eq!(
+			fetcher.fetched_urls(),
+			[
+				"https://example.com/about.html",
+				"https://example.com/index.html",
+				"https://example.com/missing.html",
+				"https://example.com/robots.txt",
+			]
+		);
+		// disallowed URLs are neither checked nor broken
+		assert_eq!(
+			sorted(crawl_state.results.iter().map(|result| &result.url)),
+			[
+				"https://example.com/about.html",
+				"https://example.com/index.html",
+				"https://example.com/missing.html",
+			]
+		);
+		assert_eq!(crawl_state.bad_urls().count(), 1);
+	}
+
+	#[test]
+	fn test_robots_txt_missing() {
+		// about.html has a mailto: link, which has no robots.txt to check
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fetcher = Arc::new(FakeFetcher::from_fixture(&start_url, &fixture_dir()));
+		let options = Options {
+			robots: true,
+			..options(&start_url)
+		};
+		let crawl_state = crawl(&options, fetcher);
+		assert_eq!(sorted(&crawl_state.visited_sites), fixture_urls(&start_url));
+		assert_eq!(
+			sorted(crawl_state.bad_urls().map(|bad_url| &bad_url.url)),
+			fixture_broken_urls(&start_url)
+		);
+	}
+
+	#[test]
+	fn test_crawl_delay() {
+		let fetcher = FakeFetcher::default()
+			.page(
+				"https://example.com/",
+				r#"<a href="/a">a</a> <a href="/b">b</a> <a href="https://example.org/">c</a>"#,
+			)
+			.page(
+				"https://example.com/robots.txt",
+				"User-agent: *\nCrawl-delay: 0.1\n",
+			)
+			.page(
+				"https://example.org/robots.txt",
+				"User-agent: *\nCrawl-delay: 10\n",
+			);
+		let start_url = Url::parse("https://example.com/").unwrap();
+		let options = Options {
+			robots: true,
+			..options(&start_url)
+		};
+		let start = Instant::now();
+		crawl(&options, Arc::new(fetcher));
+		// after robots.txt and the start page, the two links on example.com
+		// wait 100ms each; example.org's delay only applies after its first
+		// request
+		let elapsed = start.elapsed();
+		assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+		assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+	}
+
+	#[test]
+	fn test_bad_status() {
+		let start_url = Url::parse("https://example.com/").unwrap();
+		let fetcher = Arc::new(FakeFetcher::default().response(
+			start_url.as_str(),
+			StatusCode::INTERNAL_SERVER_ERROR,
+			"",
+		));
+		let crawl_state = crawl(&options(&start_url), fetcher);
+		let [result] = &crawl_state.results[..] else {
+			panic!("{:?}", crawl_state.results);
+		};
+		assert_eq!(result.url, start_url);
+		assert_eq!(result.status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+		let error = result.error.as_ref().unwrap();
+		assert_eq!(error.kind, ErrorKind::Status);
+		assert_eq!(
+			error.message,
+			"bad http response: 500 Internal Server Error"
+		);
+	}
+
+	#[test]
+	fn test_retries() {
+		let fetcher = FakeFetcher::default()
+			.page(
+				"https://example.com/",
+				r#"<a href="/busy">a</a> <a href="/limited">b</a> <a href="/down">c</a>"#,
+			)
+			.page("https://example.com/busy", "")
+			.page("https://example.com/limited", "")
+			.failing(
+				"https://example.com/busy",
+				StatusCode::SERVICE_UNAVAILABLE,
+				None,
+				2,
+			)
+			.failing(
+				"https://example.com/limited",
+				StatusCode::TOO_MANY_REQUESTS,
+				Some(Duration::from_millis(300)),
+				1,
+			)
+			.failing("https://example.com/down", StatusCode::BAD_GATEWAY, None, 5);
+		let fetcher = Arc::new(fetcher);
+		let start_url = Url::parse("https://example.com/").unwrap();
+		let start = Instant::now();
+		let crawl_state = crawl(&options(&start_url), fetcher.clone());
+		// Retry-After replaces the 10ms backoff
+		assert!(start.elapsed() >= Duration::from_millis(300));
+		assert_eq!(
+			fetcher.fetched_urls(),
+			[
+				"https://example.com/",
+				"https://example.com/busy",
+				"https://example.com/busy",
+				"https://example.com/busy",
+				"https://example.com/down",
+				"https://example.com/down",
+				"https://example.com/down",
+				"https://example.com/limited",
+				"https://example.com/limited",
+			]
+		);
+		let bad_urls: Vec<_> = crawl_state
+			.bad_urls()
+			.map(|result| (result.url.as_str(), result.status))
+			.collect();
+		assert_eq!(
+			bad_urls,
+			[("https://example.com/down", Some(StatusCode::BAD_GATEWAY))]
+		);
+	}
+
+	#[test]
+	fn test_connection_error() {
+		// nothing listens on the port once the listener is dropped
+		let port = TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port();
+		let start_url = Url::parse(&format!("http://localhost:{port}/")).unwrap();
+		let fetcher = ReqwestFetcher::with_client(Client::builder().no_proxy().build().unwrap());
+		let crawl_state = crawl(&options(&start_url), Arc::new(fetcher));
+		let error = crawl_state.results[0].error.as_ref().unwrap();
+		assert_eq!(crawl_state.results[0].status, None);
+		assert_eq!(error.kind, ErrorKind::Connect);
+	}
+
+	fn crawl_local_server(allow_head: bool) -> Vec<String> {
+		let (start_url, requests) = serve_fixture_site(allow_head);
+		let fetcher = ReqwestFetcher::with_client(Client::builder().no_proxy().build().unwrap());
+		let crawl_state = crawl(&options(&start_url), Arc::new(fetcher));
+		assert_eq!(sorted(&crawl_state.visited_sites), fixture_urls(&start_url));
+		assert_eq!(
+			sorted(crawl_state.bad_urls().map(|bad_url| &bad_url.url)),
+			fixture_broken_urls(&start_url)
+		);
+		sorted(requests.lock().unwrap().iter())
+	}
+
+	#[test]
+	fn test_local_server() {
+		// pages are read, and the rest only asked for their status
+		assert_eq!(
+			crawl_local_server(true),
+			[
+				"GET /about.html",
+				"GET /docs/deep.html",
+				"GET /docs/guide.html",
+				"GET /docs/media.html",
+				"GET /docs/nowhere.html",
+				"GET /index.html",
+				"GET /missing.html",
+				"HEAD /docs/app.js",
+				"HEAD /docs/logo-2x.png",
+				"HEAD /docs/logo.png",
+				"HEAD /docs/photo.webp",
+				"HEAD /docs/style.css",
+			]
+		);
+	}
+
+	#[test]
+	fn test_head_not_allowed() {
+		let requests = crawl_local_server(false);
+		let leaves = requests
+			.iter()
+			.filter(|request| request.contains("/docs/logo.png"));
+		assert_eq!(
+			leaves.collect::<Vec<_>>(),
+			["GET /docs/logo.png", "HEAD /docs/logo.png"]
+		);
+	}
 }
@@ -0,0 +1,355 @@
+use std::time::Duration;
+
+use reqwest::Url;
+use thiserror::Error;
+
+use crate::canonical::Canonicalizer;
+use crate::retry::Retry;
+
+pub const USAGE: &str = "\
+Usage: threaded_link_checker [OPTIONS] [URL]...
+
+Check the links on URL, and on the pages it links to on the same domain.
+With several URLs, pages on any of their domains are crawled.
+
+Options:
+  -j, --workers <N>            number of worker threads [default: 16]
+  -n, --max-pages <N>          stop queueing URLs once N have been queued [default: 100]
+  -d, --max-depth <N>          do not follow links more than N hops from a start URL
+      --rate <N>               send at most N requests per second to each host [default: 10]
+      --ignore-robots          fetch URLs that robots.txt disallows
+      --connect-timeout <SECS> give up connecting after SECS [default: 10]
+      --timeout <SECS>         give up on a request after SECS in total [default: 30]
+      --retries <N>            retry server errors, 429s and dropped connections
+                               N times [default: 2]
+      --retry-delay <SECS>     wait SECS before the first retry, doubling for
+                               each one after it, unless the server sends
+                               Retry-After [default: 1]
+      --ignore-case            treat URL paths that differ only in case as the same
+      --strip-slash            treat URL paths with and without a trailing slash as the same
+      --strip-tracking         drop utm_* and other tracking parameters from URLs
+      --keep-query-order       treat URLs with query parameters in another order as different
+  -o, --output <FILE>          write the visited URLs to FILE
+      --state <FILE>           save the crawl's progress to FILE as it goes
+      --resume                 continue the crawl saved in the --state FILE,
+                               also checking any URLs given
+      --json <FILE>            write a JSON report of every URL checked to FILE
+      --junit <FILE>           write a JUnit XML report to FILE
+      --sarif <FILE>           write the broken links as a SARIF log to FILE
+  -h, --help                   print this help
+
+Exits with status 1 if any link is broken.
+";
+
+const DEFAULT_START_URL: &str = "https://www.google.org";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum UsageError {
+	#[error("unknown option {0}")]
+	UnknownOption(String),
+	#[error("missing value for {0}")]
+	MissingValue(String),
+	#[error("invalid value {value:?} for {option}")]
+	InvalidValue { option: String, value: String },
+	#[error("invalid start URL {url:?}: {reason}")]
+	InvalidUrl { url: String, reason: String },
+	#[error("{option} needs {needs}")]
+	Requires { option: String, needs: String },
+}
+
+/// Crawl parameters, as given on the command line.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+	/// Empty when resuming without URLs: the saved crawl has its own.
+	pub start_urls: Vec<Url>,
+	pub workers: usize,
+	pub max_pages: usize,
+	/// `None` follows links at any depth.
+	pub max_depth: Option<usize>,
+	/// Requests per second per host; a robots.txt `Crawl-delay` can lower it.
+	pub rate: f64,
+	/// Whether to honor robots.txt.
+	pub robots: bool,
+	pub connect_timeout: Duration,
+	/// How long a whole request may take, reading the body included.
+	pub timeout: Duration,
+	pub retry: Retry,
+	/// How URLs are rewritten to tell whether they were checked already.
+	pub canonical: Canonicalizer,
+	pub output: Option<String>,
+	/// Where to save the crawl state.
+	pub state: Option<String>,
+	/// Whether to continue from the saved crawl state.
+	pub resume: bool,
+	pub json: Option<String>,
+	pub junit: Option<String>,
+	pub sarif: Option<String>,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Options {
+			start_urls: vec![Url::parse(DEFAULT_START_URL).unwrap()],
+			workers: 16,
+			max_pages: 100,
+			max_depth: None,
+			rate: 10.0,
+			robots: true,
+			connect_timeout: Duration::from_secs(10),
+			timeout: Duration::from_secs(30),
+			retry: Retry {
+				retries: 2,
+				delay: Duration::from_secs(1),
+			},
+			canonical: Canonicalizer::default(),
+			output: None,
+			state: None,
+			resume: false,
+			json: None,
+			junit: None,
+			sarif: None,
+		}
+	}
+}
+
+impl Options {
+	/// Parse the arguments following the program name. Returns `None` when
+	/// help was asked for.
+	pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, UsageError> {
+		let mut options = Options::default();
+		let mut start_urls = Vec::new();
+		let mut args = args.into_iter();
+		while let Some(arg) = args.next() {
+			if !arg.starts_with('-') || arg == "-" {
+				start_urls.push(parse_start_url(&arg)?);
+				continue;
+			}
+			// Both `--option value` and `--option=value` are accepted.
+			let (option, inline_value) = match arg.split_once('=') {
+				Some((option, value)) if arg.starts_with("--") => {
+					(option.to_string(), Some(value.to_string()))
+				}
+				_ => (arg, None),
+			};
+			let mut value = || {
+				inline_value
+					.clone()
+					.or_else(|| args.next())
+					.ok_or_else(|| UsageError::MissingValue(option.clone()))
+			};
+			match option.as_str() {
+				"-h" | "--help" => return Ok(None),
+				"-j" | "--workers" => options.workers = parse_count(&option, value()?)?,
+				"-n" | "--max-pages" => options.max_pages = parse_count(&option, value()?)?,
+				"-d" | "--max-depth" => {
+					options.max_depth = Some(parse_number(&option, value()?)?);
+				}
+				"--rate" => options.rate = parse_positive(&option, value()?)?,
+				"--ignore-robots" => options.robots = false,
+				"--connect-timeout" => {
+					options.connect_timeout = parse_seconds(&option, value()?)?;
+				}
+				"--timeout" => options.timeout = parse_seconds(&option, value()?)?,
+				"--retries" => {
+					let retries = parse_number(&option, value()?)?;
+					options.retry.retries = retries.try_into().unwrap_or(u32::MAX);
+				}
+				"--retry-delay" => options.retry.delay = parse_seconds(&option, value()?)?,
+				"--ignore-case" => options.canonical.ignore_case = true,
+				"--strip-slash" => options.canonical.strip_slash = true,
+				"--strip-tracking" => options.canonical.strip_tracking = true,
+				"--keep-query-order" => options.canonical.sort_query = false,
+				"-o" | "--output" => options.output = Some(value()?),
+				"--state" => options.state = Some(value()?),
+				"--resume" => options.resume = true,
+				"--json" => options.json = Some(value()?),
+				"--junit" => options.junit = Some(value()?),
+				"--sarif" => options.sarif = Some(value()?),
+				_ => return Err(UsageError::UnknownOption(option)),
+			}
+		}
+		if options.resume && options.state.is_none() {
+			return Err(UsageError::Requires {
+				option: "--resume".to_string(),
+				needs: "--state".to_string(),
+			});
+		}
+		if !start_urls.is_empty() || options.resume {
+			options.start_urls = start_urls;
+		}
+		Ok(Some(options))
+	}
+}
+
+fn parse_number(option: &str, value: String) -> Result<usize, UsageError> {
+	value.parse().map_err(|_| UsageError::InvalidValue {
+		option: option.to_string(),
+		value,
+	})
+}
+
+/// Like `parse_number`, for options where zero would do nothing.
+fn parse_count(option: &str, value: String) -> Result<usize, UsageError> {
+	match parse_number(option, value)? {
+		0 => Err(UsageError::InvalidValue {
+			option: option.to_string(),
+			value: "0".to_string(),
+		}),
+		count => Ok(count),
+	}
+}
+
+/// A positive number, which may have a fraction.
+fn parse_positive(option: &str, value: String) -> Result<f64, UsageError> {
+	match value.parse::<f64>() {
+		Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+		_ => Err(UsageError::InvalidValue {
+			option: option.to_string(),
+			value,
+		}),
+	}
+}
+
+fn parse_seconds(option: &str, value: String) -> Result<Duration, UsageError> {
+	let seconds = parse_positive(option, value)?;
+	Duration::try_from_secs_f64(seconds).map_err(|_| UsageError::InvalidValue {
+		option: option.to_string(),
+		value: seconds.to_string(),
+	})
+}
+
+/// Start URLs need a domain, as it decides which pages get crawled.
+fn parse_start_url(arg: &str) -> Result<Url, UsageError> {
+	let invalid = |reason: String| UsageError::InvalidUrl {
+		url: arg.to_string(),
+		reason,
+	};
+	let url = Url::parse(arg).map_err(|err| invalid(err.to_string()))?;
+	if url.domain().is_none() {
+		return Err(invalid("no domain name".to_string()));
+	}
+	Ok(url)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn parse(args: &[&str]) -> Result<Option<Options>, UsageError> {
+		Options::parse(args.iter().map(|arg| arg.to_string()))
+	}
+
+	#[test]
+	fn test_defaults() {
+		assert_eq!(parse(&[]), Ok(Some(Options::default())));
+		assert_eq!(parse(&["-n", "5", "--help"]), Ok(None));
+		let resumed = parse(&["--state", "crawl.state", "--resume"]);
+		assert_eq!(resumed.unwrap().unwrap().start_urls, []);
+	}
+
+	#[test]
+	fn test_options() {
+		let options = parse(&[
+			"https://example.com/docs/",
+			"-j",
+			"4",
+			"--max-pages=500",
+			"--max-depth",
+			"2",
+			"--rate=0.5",
+			"--ignore-robots",
+			"--timeout=2.5",
+			"--retries",
+			"0",
+			"--strip-tracking",
+			"-o",
+			"visited.txt",
+			"--state=crawl.state",
+			"--resume",
+			"--json=report.json",
+			"--junit",
+			"junit.xml",
+			"https://example.org",
+		])
+		.unwrap()
+		.unwrap();
+		assert_eq!(
+			options,
+			Options {
+				start_urls: vec![
+					Url::parse("https://example.com/docs/").unwrap(),
+					Url::parse("https://example.org").unwrap(),
+				],
+				workers: 4,
+				max_pages: 500,
+				max_depth: Some(2),
+				rate: 0.5,
+				robots: false,
+				connect_timeout: Duration::from_secs(10),
+				timeout: Duration::from_millis(2500),
+				retry: Retry {
+					retries: 0,
+					..Options::default().retry
+				},
+				canonical: Canonicalizer {
+					strip_tracking: true,
+					..Canonicalizer::default()
+				},
+				output: Some("visited.txt".to_string()),
+				state: Some("crawl.state".to_string()),
+				resume: true,
+				json: Some("report.json".to_string()),
+				junit: Some("junit.xml".to_string()),
+				sarif: None,
+			}
+		);
+	}
+
+	#[test]
+	fn test_errors() {
+		assert_eq!(
+			parse(&["--verbose"]),
+			Err(UsageError::UnknownOption("--verbose".to_string()))
+		);
+		assert_eq!(
+			parse(&["-o"]),
+			Err(UsageError::MissingValue("-o".to_string()))
+		);
+		assert_eq!(
+			parse(&["--workers", "0"]),
+			Err(UsageError::InvalidValue {
+				option: "--workers".to_string(),
+				value: "0".to_string()
+			})
+		);
+		assert_eq!(
+			parse(&["-d", "-1"]),
+			Err(UsageError::InvalidValue {
+				option: "-d".to_string(),
+				value: "-1".to_string()
+			})
+		);
+		assert_eq!(
+			parse(&["--rate", "0"]),
+			Err(UsageError::InvalidValue {
+				option: "--rate".to_string(),
+				value: "0".to_string()
+			})
+		);
+		assert_eq!(
+			parse(&["--resume"]),
+			Err(UsageError::Requires {
+				option: "--resume".to_string(),
+				needs: "--state".to_string()
+			})
+		);
+		assert!(matches!(
+			parse(&["http://127.0.0.1/"]),
+			Err(UsageError::InvalidUrl { .. })
+		));
+		assert!(matches!(
+			parse(&["example.com"]),
+			Err(UsageError::InvalidUrl { .. })
+		));
+	}
+}
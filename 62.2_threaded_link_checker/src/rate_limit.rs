@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits the requests to each host with a token bucket, shared by all
+/// workers.
+pub struct RateLimiter {
+	/// Requests per second, for hosts without a slower crawl delay.
+	rate: f64,
+	burst: f64,
+	hosts: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+	/// Negative when requests are waiting for tokens.
+	tokens: f64,
+	updated: Instant,
+	rate: f64,
+	burst: f64,
+}
+
+impl RateLimiter {
+	/// Allow `rate` requests per second to each host, after a burst of up to
+	/// `burst`.
+	pub fn new(rate: f64, burst: u32) -> Self {
+		RateLimiter {
+			rate,
+			burst: burst.max(1) as f64,
+			hosts: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Block until a request to `host` is allowed.
+	pub fn wait(&self, host: &str) {
+		let delay = self.reserve(host, Instant::now());
+		if !delay.is_zero() {
+			thread::sleep(delay);
+		}
+	}
+
+	/// Take a token for `host`, returning how long to wait for it. Waiting
+	/// happens outside the lock, so workers bound for other hosts carry on.
+	fn reserve(&self, host: &str, now: Instant) -> Duration {
+		let mut hosts = self.hosts.lock().unwrap();
+		let bucket = self.bucket(&mut hosts, host, now);
+		bucket.refill(now);
+		bucket.tokens -= 1.0;
+		if bucket.tokens >= 0.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+		}
+	}
+
+	/// Allow one request per `interval` to `host`, without bursts, as for a
+	/// robots.txt `Crawl-delay`. A shorter interval than the configured rate
+	/// allows does not speed requests up.
+	pub fn slow_down(&self, host: &str, interval: Duration) {
+		let now = Instant::now();
+		let mut hosts = self.hosts.lock().unwrap();
+		let bucket = self.bucket(&mut hosts, host, now);
+		bucket.refill(now);
+		bucket.rate = bucket.rate.min(1.0 / interval.as_secs_f64());
+		bucket.burst = 1.0;
+		bucket.tokens = bucket.tokens.min(1.0);
+	}
+
+	fn bucket<'h>(
+		&self,
+		hosts: &'h mut HashMap<String, Bucket>,
+		host: &str,
+		now: Instant,
+	) -> &'h mut Bucket {
+		hosts.entry(host.to_string()).or_insert_with(|| Bucket {
+			tokens: self.burst,
+			updated: now,
+			rate: self.rate,
+			burst: self.burst,
+		})
+	}
+}
+
+impl Bucket {
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+		self.updated = self.updated.max(now);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn millis(millis: u64) -> Duration {
+		Duration::from_millis(millis)
+	}
+
+	#[test]
+	fn test_burst_then_rate() {
+		let limiter = RateLimiter::new(10.0, 2);
+		let start = Instant::now();
+		assert_eq!(limiter.reserve("a.example", start), Duration::ZERO);
+		assert_eq!(limiter.reserve("a.example", start), Duration::ZERO);
+		// later requests queue up 100ms apart
+		assert_eq!(limiter.reserve("a.example", start), millis(100));
+		assert_eq!(limiter.reserve("a.example", start), millis(200));
+		// other hosts have their own bucket
+		assert_eq!(limiter.reserve("b.example", start), Duration::ZERO);
+		// after a second the bucket is full again, but no fuller
+		let later = start + millis(1300);
+		assert_eq!(limiter.reserve("a.example", later), Duration::ZERO);
+		assert_eq!(limiter.reserve("a.example", later), Duration::ZERO);
+		assert_eq!(limiter.reserve("a.example", later), millis(100));
+	}
+
+	#[test]
+	fn test_slow_down() {
+		let limiter = RateLimiter::new(10.0, 5);
+		limiter.slow_down("a.example", Duration::from_secs(2));
+		// a faster crawl delay does not speed things up
+		limiter.slow_down("a.example", millis(10));
+		let start = Instant::now();
+		let first = limiter.reserve("a.example", start);
+		assert!(first.is_zero());
+		let second = limiter.reserve("a.example", start);
+		assert!(
+			second > millis(1900) && second <= millis(2000),
+			"{second:?}"
+		);
+	}
+}
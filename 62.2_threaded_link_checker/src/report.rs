@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+
+use reqwest::Url;
+
+use crate::options::Options;
+use crate::{CrawlState, Link, UrlResult};
+
+/// A link, with how loading its URL went.
+#[derive(Debug)]
+pub struct CheckedLink<'a> {
+	pub link: &'a Link,
+	pub result: &'a UrlResult,
+}
+
+impl CheckedLink<'_> {
+	fn error(&self) -> &str {
+		self.result
+			.error
+			.as_ref()
+			.map_or("", |error| &error.message)
+	}
+}
+
+/// The links to the URLs of `results`, grouped by the page containing them.
+/// Start URLs are under `None`, which sorts first.
+fn links_by_page<'a>(
+	state: &'a CrawlState,
+	results: impl Iterator<Item = &'a UrlResult>,
+) -> BTreeMap<Option<&'a Url>, Vec<CheckedLink<'a>>> {
+	let mut pages: BTreeMap<Option<&Url>, Vec<CheckedLink>> = BTreeMap::new();
+	for result in results {
+		for link in &state.links[result.url.as_str()] {
+			let page = pages.entry(link.referrer.as_ref()).or_default();
+			page.push(CheckedLink { link, result });
+		}
+	}
+	// workers finish in any order
+	for links in pages.values_mut() {
+		links.sort_by(|a, b| (&a.link.url, &a.link.text).cmp(&(&b.link.url, &b.link.text)));
+	}
+	pages
+}
+
+/// The broken links, grouped by the page containing them.
+pub fn broken_links_by_page(state: &CrawlState) -> BTreeMap<Option<&Url>, Vec<CheckedLink<'_>>> {
+	links_by_page(state, state.bad_urls())
+}
+
+/// Write the broken links, grouped by page, for people to read.
+pub fn write_broken_links(state: &CrawlState, out: &mut dyn Write) -> io::Result<()> {
+	for (page, links) in broken_links_by_page(state) {
+		match page {
+			Some(page) => writeln!(out, "Broken links on {page}:")?,
+			None => writeln!(out, "Broken start URLs:")?,
+		}
+		for checked in links {
+			let link = checked.link;
+			if link.referrer.is_some() {
+				writeln!(
+					out,
+					"  {} ({} {:?}): {}",
+					link.url,
+					link.element,
+					link.text,
+					checked.error()
+				)?;
+			} else {
+				writeln!(out, "  {}: {}", link.url, checked.error())?;
+			}
+		}
+	}
+	Ok(())
+}
+
+type ReportWriter = fn(&CrawlState, &mut dyn Write) -> io::Result<()>;
+
+/// Write each report that `options` asks for to its file.
+pub fn write_reports(options: &Options, state: &CrawlState) -> io::Result<()> {
+	let reports: [(&Option<String>, ReportWriter); 3] = [
+		(&options.json, write_json),
+		(&options.junit, write_junit),
+		(&options.sarif, write_sarif),
+	];
+	for (path, write_report) in reports {
+		if let Some(path) = path {
+			let with_path = |err: io::Error| io::Error::new(err.kind(), format!("{path}: {err}"));
+			let mut file = BufWriter::new(File::create(path).map_err(with_path)?);
+			write_report(state, &mut file)
+				.and_then(|()| file.flush())
+				.map_err(with_path)?;
+		}
+	}
+	Ok(())
+}
+
+/// Every URL checked, sorted by URL.
+fn sorted_results(state: &CrawlState) -> Vec<&UrlResult> {
+	let mut results: Vec<&UrlResult> = state.results.iter().collect();
+	results.sort_by(|a, b| a.url.cmp(&b.url));
+	results
+}
+
+fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+fn json_or_null(value: Option<impl ToString>) -> String {
+	value.map_or("null".to_string(), |value| json_string(&value.to_string()))
+}
+
+/// Write every URL checked, with its status, error, timing and the links to
+/// it, as JSON.
+pub fn write_json(state: &CrawlState, out: &mut dyn Write) -> io::Result<()> {
+	let results = sorted_results(state);
+	writeln!(out, "{{")?;
+	writeln!(out, "  \"checked\": {},", results.len())?;
+	writeln!(out, "  \"broken\": {},", state.bad_urls().count())?;
+	write!(out, "  \"results\": [")?;
+	for (i, result) in results.iter().enumerate() {
+		let separator = if i == 0 { "" } else { "," };
+		let status = result.status.map(|status| status.as_u16());
+		let error = match &result.error {
+			Some(error) => format!(
+				"{{\"kind\": {}, \"message\": {}}}",
+				json_string(error.kind.as_str()),
+				json_string(&error.message)
+			),
+			None => "null".to_string(),
+		};
+		writeln!(out, "{separator}\n    {{")?;
+		writeln!(out, "      \"url\": {},", json_string(result.url.as_str()))?;
+		writeln!(
+			out,
+			"      \"status\": {},",
+			status.map_or("null".to_string(), |status| status.to_string())
+		)?;
+		writeln!(out, "      \"error\": {error},")?;
+		writeln!(out, "      \"elapsed_ms\": {},", result.elapsed.as_millis())?;
+		write!(out, "      \"referrers\": [")?;
+		for (j, link) in state.links[result.url.as_str()].iter().enumerate() {
+			let separator = if j == 0 { "" } else { "," };
+			write!(
+				out,
+				"{separator}\n        {{\"page\": {}, \"element\": {}, \"text\": {}}}",
+				json_or_null(link.referrer.as_ref()),
+				json_string(&link.element),
+				json_string(&link.text)
+			)?;
+		}
+		writeln!(out, "\n      ]")?;
+		write!(out, "    }}")?;
+	}
+	writeln!(out, "\n  ]")?;
+	writeln!(out, "}}")
+}
+
+fn xml_escape(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&apos;"),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+fn seconds(elapsed: Duration) -> String {
+	format!("{:.3}", elapsed.as_secs_f64())
+}
+
+/// Write a JUnit XML report: a test suite per page, with a test case per link
+/// on it, failing if the link is broken.
+pub fn write_junit(state: &CrawlState, out: &mut dyn Write) -> io::Result<()> {
+	let pages = links_by_page(state, state.results.iter());
+	let all_links = pages.values().flatten();
+	let tests = all_links.clone().count();
+	let failures = all_links.clone().filter(|link| link.result.error.is_some());
+	let time = all_links.map(|link| link.result.elapsed).sum();
+	writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+	writeln!(
+		out,
+		r#"<testsuites name="link check" tests="{tests}" failures="{}" time="{}">"#,
+		failures.count(),
+		seconds(time)
+	)?;
+	for (page, links) in &pages {
+		let name = page.map_or("start URLs".to_string(), |page| page.to_string());
+		let failures = links.iter().filter(|link| link.result.error.is_some());
+		let time = links.iter().map(|link| link.result.elapsed).sum();
+		writeln!(
+			out,
+			r#"  <testsuite name="{}" tests="{}" failures="{}" time="{}">"#,
+			xml_escape(&name),
+			links.len(),
+			failures.count(),
+			seconds(time)
+		)?;
+		for checked in links {
+			write!(
+				out,
+				r#"    <testcase name="{}" classname="{}" time="{}""#,
+				xml_escape(checked.link.url.as_str()),
+				xml_escape(&name),
+				seconds(checked.result.elapsed)
+			)?;
+			let Some(error) = &checked.result.error else {
+				writeln!(out, "/>")?;
+				continue;
+			};
+			writeln!(out, ">")?;
+			writeln!(
+				out,
+				r#"      <failure type="{}" message="{}">{} {}</failure>"#,
+				error.kind.as_str(),
+				xml_escape(&error.message),
+				xml_escape(&checked.link.element),
+				xml_escape(&format!("{:?}", checked.link.text))
+			)?;
+			writeln!(out, "    </testcase>")?;
+		}
+		writeln!(out, "  </testsuite>")?;
+	}
+	writeln!(out, "</testsuites>")
+}
+
+/// Write the broken links as a SARIF 2.1.0 log, located at the page
+/// containing them.
+pub fn write_sarif(state: &CrawlState, out: &mut dyn Write) -> io::Result<()> {
+	writeln!(out, "{{")?;
+	writeln!(
+		out,
+		"  \"$schema\": \"https://json.schemastore.org/sarif-2.1.0.json\","
+	)?;
+	writeln!(out, "  \"version\": \"2.1.0\",")?;
+	writeln!(out, "  \"runs\": [{{")?;
+	writeln!(out, "    \"tool\": {{\"driver\": {{")?;
+	writeln!(out, "      \"name\": \"threaded_link_checker\",")?;
+	writeln!(
+		out,
+		"      \"rules\": [{{\"id\": \"broken-link\", \
+		\"shortDescription\": {{\"text\": \"Link to a URL that fails to load\"}}}}]"
+	)?;
+	writeln!(out, "    }}}},")?;
+	write!(out, "    \"results\": [")?;
+	let links = broken_links_by_page(state).into_values().flatten();
+	for (i, checked) in links.enumerate() {
+		let separator = if i == 0 { "" } else { "," };
+		let link = checked.link;
+		let message = match &link.referrer {
+			Some(_) => format!(
+				"{} {:?} links to {}: {}",
+				link.element,
+				link.text,
+				link.url,
+				checked.error()
+			),
+			None => format!("start URL {}: {}", link.url, checked.error()),
+		};
+		let location = link.referrer.as_ref().unwrap_or(&link.url);
+		write!(
+			out,
+			"{separator}\n      {{\"ruleId\": \"broken-link\", \"level\": \"error\", \
+			\"message\": {{\"text\": {}}}, \
+			\"locations\": [{{\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": {}}}}}}}]}}",
+			json_string(&message),
+			json_string(location.as_str())
+		)?;
+	}
+	writeln!(out, "\n    ]")?;
+	writeln!(out, "  }}]")?;
+	writeln!(out, "}}")
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::Path;
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::crawl;
+	use crate::fetch::FakeFetcher;
+
+	#[test]
+	fn test_broken_links_by_page() {
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fixture_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/site");
+		let options = Options {
+			start_urls: vec![start_url.clone(), start_url.join("/gone/").unwrap()],
+			..Options::default()
+		};
+		let fetcher = FakeFetcher::from_fixture(&start_url, &fixture_dir);
+		let crawl_state = crawl(&options, Arc::new(fetcher));
+		let pages = broken_links_by_page(&crawl_state);
+		let page_urls: Vec<Option<&str>> = pages.keys().map(|page| page.map(Url::as_str)).collect();
+		assert_eq!(
+			page_urls,
+			[
+				None,
+				Some("https://example.com/about.html"),
+				Some("https://example.com/docs/deep.html"),
+				Some("https://example.com/docs/media.html"),
+				Some("https://example.com/index.html"),
+			]
+		);
+
+		let mut out = Vec::new();
+		write_broken_links(&crawl_state, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"\
+Broken start URLs:
+  https://example.com/gone/: bad http response: 404 Not Found
+Broken links on https://example.com/about.html:
+  https://example.com/missing.html (a[href] \"Old page\"): bad http response: 404 Not Found
+Broken links on https://example.com/docs/deep.html:
+  https://example.com/docs/nowhere.html (a[href] \"Nowhere\"): bad http response: 404 Not Found
+Broken links on https://example.com/docs/media.html:
+  https://example.com/docs/guide.html#uninstall (a[href] \"Uninstalling\"): no element with id or name \"uninstall\"
+  https://example.com/docs/logo-2x.png (img[srcset] \"Logo\"): bad http response: 404 Not Found
+  https://example.com/docs/photo.webp (source[srcset] \"\"): bad http response: 404 Not Found
+Broken links on https://example.com/index.html:
+  https://example.com/missing.html (a[href] \"Missing page\"): bad http response: 404 Not Found
+"
+		);
+	}
+
+	/// A crawl of a page with one working and one broken link, with timings
+	/// zeroed so that reports are reproducible.
+	fn small_crawl() -> CrawlState {
+		let fetcher = FakeFetcher::default()
+			.page(
+				"https://example.com/",
+				r#"<a href="/ok">Fine</a> <a href="/gone">"Gone" &amp; lost</a>"#,
+			)
+			.page("https://example.com/ok", "");
+		let options = Options {
+			start_urls: vec![Url::parse("https://example.com/").unwrap()],
+			..Options::default()
+		};
+		let mut crawl_state = crawl(&options, Arc::new(fetcher));
+		for result in &mut crawl_state.results {
+			result.elapsed = Duration::ZERO;
+		}
+		crawl_state
+	}
+
+	fn report(write_report: ReportWriter) -> String {
+		let mut out = Vec::new();
+		write_report(&small_crawl(), &mut out).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+
+	#[test]
+	fn test_json() {
+		assert_eq!(
+			report(write_json),
+			r#"{
+  "checked": 3,
+  "broken": 1,
+  "results": [
+    {
+      "url": "https://example.com/",
+      "status": 200,
+      "error": null,
+      "elapsed_ms": 0,
+      "referrers": [
+        {"page": null, "element": "", "text": ""}
+      ]
+    },
+    {
+      "url": "https://example.com/gone",
+      "status": 404,
+      "error": {"kind": "http_status", "message": "bad http response: 404 Not Found"},
+      "elapsed_ms": 0,
+      "referrers": [
+        {"page": "https://example.com/", "element": "a[href]", "text": "\"Gone\" & lost"}
+      ]
+    },
+    {
+      "url": "https://example.com/ok",
+      "status": 200,
+      "error": null,
+      "elapsed_ms": 0,
+      "referrers": [
+        {"page": "https://example.com/", "element": "a[href]", "text": "Fine"}
+      ]
+    }
+  ]
+}
+"#
+		);
+	}
+
+	#[test]
+	fn test_junit() {
+		assert_eq!(
+			report(write_junit),
+			r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="link check" tests="3" failures="1" time="0.000">
+  <testsuite name="start URLs" tests="1" failures="0" time="0.000">
+    <testcase name="https://example.com/" classname="start URLs" time="0.000"/>
+  </testsuite>
+  <testsuite name="https://example.com/" tests="2" failures="1" time="0.000">
+    <testcase name="https://example.com/gone" classname="https://example.com/" time="0.000">
+      <failure type="http_status" message="bad http response: 404 Not Found">a[href] &quot;\&quot;Gone\&quot; &amp; lost&quot;</failure>
+    </testcase>
+    <testcase name="https://example.com/ok" classname="https://example.com/" time="0.000"/>
+  </testsuite>
+</testsuites>
+"#
+		);
+	}
+
+	#[test]
+	fn test_sarif() {
+		assert_eq!(
+			report(write_sarif),
+			r#"{
+  "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+  "version": "2.1.0",
+  "runs": [{
+    "tool": {"driver": {
+      "name": "threaded_link_checker",
+      "rules": [{"id": "broken-link", "shortDescription": {"text": "Link to a URL that fails to load"}}]
+    }},
+    "results": [
+      {"ruleId": "broken-link", "level": "error", "message": {"text": "a[href] \"\\\"Gone\\\" & lost\" links to https://example.com/gone: bad http response: 404 Not Found"}, "locations": [{"physicalLocation": {"artifactLocation": {"uri": "https://example.com/"}}}]}
+    ]
+  }]
+}
+"#
+		);
+	}
+}
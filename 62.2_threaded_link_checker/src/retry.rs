@@ -0,0 +1,140 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::StatusCode;
+
+/// The longest a server's `Retry-After` is waited for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// When to retry requests that failed for reasons that may pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retry {
+	/// Retries after the first attempt.
+	pub retries: u32,
+	/// The wait before the first retry, doubling for each one after it.
+	pub delay: Duration,
+}
+
+impl Retry {
+	/// The wait before retry number `attempt`, counting from 0. A server's
+	/// `Retry-After` replaces the backoff, up to a minute.
+	pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+		match retry_after {
+			Some(retry_after) => retry_after.min(MAX_RETRY_AFTER),
+			None => self.delay.saturating_mul(1 << attempt.min(16)),
+		}
+	}
+}
+
+/// Whether a response with `status` may succeed when tried again.
+pub fn is_transient(status: StatusCode) -> bool {
+	status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Parse a `Retry-After` header, either seconds or an HTTP date, into how
+/// long to wait from `now`.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+	let value = value.trim();
+	if let Ok(seconds) = value.parse::<u64>() {
+		return Some(Duration::from_secs(seconds));
+	}
+	let date = parse_http_date(value)?;
+	// a date in the past means now
+	Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Parse the `Sun, 06 Nov 1994 08:49:37 GMT` form of HTTP dates, the only
+/// one servers may send.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+	const MONTHS: [&str; 12] = [
+		"Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+	];
+	let parts: [&str; 6] = value.split(' ').collect::<Vec<_>>().try_into().ok()?;
+	let [_weekday, day, month, year, time, "GMT"] = parts else {
+		return None;
+	};
+	let day: u64 = day.parse().ok()?;
+	let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+	let year: u64 = year.parse().ok()?;
+	let [hours, minutes, seconds] = time
+		.split(':')
+		.map(|part| part.parse::<u64>().ok())
+		.collect::<Option<Vec<_>>>()?
+		.try_into()
+		.ok()?;
+	// HTTP dates have four-digit years
+	if !(1..=31).contains(&day)
+		|| !(1970..=9999).contains(&year)
+		|| hours > 23
+		|| minutes > 59
+		|| seconds > 60
+	{
+		return None;
+	}
+	let days = days_since_epoch(year, month, day);
+	let seconds = days
+		.checked_mul(24)?
+		.checked_add(hours)?
+		.checked_mul(60)?
+		.checked_add(minutes)?
+		.checked_mul(60)?
+		.checked_add(seconds)?;
+	UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+	// count years from March, so that leap days end them
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year / 400;
+	let year_of_era = year % 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	// 719468 days from 0000-03-01 to 1970-01-01
+	era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_delay() {
+		let retry = Retry {
+			retries: 3,
+			delay: Duration::from_millis(100),
+		};
+		let delays = (0..3).map(|attempt| retry.delay(attempt, None).as_millis());
+		assert_eq!(delays.collect::<Vec<_>>(), [100, 200, 400]);
+		let retry_after = Some(Duration::from_secs(5));
+		assert_eq!(retry.delay(2, retry_after), Duration::from_secs(5));
+		let retry_after = Some(Duration::from_secs(3600));
+		assert_eq!(retry.delay(0, retry_after), MAX_RETRY_AFTER);
+	}
+
+	#[test]
+	fn test_parse_retry_after() {
+		let now = UNIX_EPOCH + Duration::from_secs(784111777);
+		let parse = |value| parse_retry_after(value, now);
+		assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+		// 1994-11-06 08:49:37 is `now`
+		assert_eq!(
+			parse("Sun, 06 Nov 1994 08:50:07 GMT"),
+			Some(Duration::from_secs(30))
+		);
+		assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+		assert_eq!(parse("Thu, 01 Jan 1970 00:00:00 GMT"), Some(Duration::ZERO));
+		assert_eq!(
+			parse_http_date("Tue, 29 Feb 2028 12:00:00 GMT"),
+			Some(UNIX_EPOCH + Duration::from_secs(1835438400))
+		);
+		assert_eq!(
+			parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT"),
+			Some(UNIX_EPOCH + Duration::from_secs(253402300799))
+		);
+		assert_eq!(parse("Sun, 06 Nov 300000000000 08:49:37 GMT"), None);
+		assert_eq!(parse("Sun, 06 Nov 10000 08:49:37 GMT"), None);
+		assert_eq!(parse("-1"), None);
+		assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+		assert_eq!(parse("soon"), None);
+	}
+}
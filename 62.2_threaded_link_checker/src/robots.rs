@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use reqwest::Url;
+
+/// The longest `Crawl-delay` honored, so that a site cannot stall a crawl.
+const MAX_CRAWL_DELAY: Duration = Duration::from_secs(60);
+
+/// The rules a site's robots.txt sets for this crawler.
+#[derive(Debug, Default, PartialEq)]
+pub struct Robots {
+	/// `(allow, pattern)` pairs.
+	rules: Vec<(bool, String)>,
+	pub crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+	/// Parse a robots.txt, keeping the groups for `agent`, or else the groups
+	/// for `*`. Several groups for the same agent are merged.
+	pub fn parse(text: &str, agent: &str) -> Robots {
+		let mut own = Robots::default();
+		let mut any = Robots::default();
+		let mut found_own = false;
+		// the agents of the current group, and whether its rules have started
+		let (mut is_own, mut is_any, mut in_rules) = (false, false, false);
+		for line in text.lines() {
+			let line = line.split('#').next().unwrap();
+			let Some((key, value)) = line.split_once(':') else {
+				continue;
+			};
+			let value = value.trim();
+			let key = key.trim().to_ascii_lowercase();
+			if key == "user-agent" {
+				if in_rules {
+					(is_own, is_any, in_rules) = (false, false, false);
+				}
+				let product = value.split('/').next().unwrap();
+				is_own |= product.eq_ignore_ascii_case(agent);
+				is_any |= value == "*";
+				found_own |= is_own;
+				continue;
+			}
+			in_rules = true;
+			let groups = [(is_own, &mut own), (is_any, &mut any)];
+			let applying = groups
+				.into_iter()
+				.filter_map(|(applies, robots)| applies.then_some(robots));
+			for robots in applying {
+				match key.as_str() {
+					// an empty Disallow allows everything
+					"disallow" if !value.is_empty() => {
+						robots.rules.push((false, value.to_string()))
+					}
+					"allow" if !value.is_empty() => robots.rules.push((true, value.to_string())),
+					"crawl-delay" => {
+						// a huge or infinite delay is capped, not an overflow
+						if let Ok(seconds) = value.parse::<f64>()
+							&& seconds >= 0.0
+						{
+							let delay =
+								Duration::try_from_secs_f64(seconds).unwrap_or(MAX_CRAWL_DELAY);
+							robots.crawl_delay = Some(delay.min(MAX_CRAWL_DELAY));
+						}
+					}
+					_ => {}
+				}
+			}
+		}
+		if found_own { own } else { any }
+	}
+
+	/// Whether `url` may be fetched. The longest matching pattern decides,
+	/// and `Allow` wins a tie.
+	pub fn is_allowed(&self, url: &Url) -> bool {
+		let mut path = url.path().to_string();
+		if let Some(query) = url.query() {
+			path = format!("{path}?{query}");
+		}
+		let best = self
+			.rules
+			.iter()
+			.filter(|(_, pattern)| matches(pattern, &path))
+			.max_by_key(|(allow, pattern)| (pattern.len(), *allow));
+		best.is_none_or(|(allow, _)| *allow)
+	}
+}
+
+/// Match a robots.txt path pattern, where `*` matches any characters and a
+/// trailing `$` anchors the end.
+fn matches(pattern: &str, path: &str) -> bool {
+	let (pattern, anchored) = match pattern.strip_suffix('$') {
+		Some(pattern) => (pattern, true),
+		None => (pattern, false),
+	};
+	let mut parts = pattern.split('*');
+	let Some(mut rest) = path.strip_prefix(parts.next().unwrap()) else {
+		return false;
+	};
+	let parts: Vec<&str> = parts.collect();
+	let Some((last, middle)) = parts.split_last() else {
+		return !anchored || rest.is_empty();
+	};
+	for part in middle {
+		match rest.find(part) {
+			Some(i) => rest = &rest[i + part.len()..],
+			None => return false,
+		}
+	}
+	if anchored {
+		rest.ends_with(last)
+	} else {
+		rest.contains(last)
+	}
+}
+
+/// The robots.txt rules of each site, shared by all workers. Each site's
+/// robots.txt is loaded once, by the first worker needing it.
+#[derive(Default)]
+pub struct RobotsCache {
+	sites: Mutex<HashMap<String, Arc<OnceLock<Arc<Robots>>>>>,
+}
+
+impl RobotsCache {
+	/// The rules for `url`'s site, calling `load` with the robots.txt URL
+	/// if they are not known yet. URLs without a site, such as `mailto:`
+	/// ones, have no robots.txt and are allowed.
+	pub fn get(&self, url: &Url, load: impl FnOnce(&Url) -> Robots) -> Arc<Robots> {
+		let Ok(robots_url) = url.join("/robots.txt") else {
+			return Arc::default();
+		};
+		let site = url.origin().ascii_serialization();
+		// only hold the lock for the lookup, not while loading
+		let cell = self.sites.lock().unwrap().entry(site).or_default().clone();
+		cell.get_or_init(|| Arc::new(load(&robots_url))).clone()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn allowed(robots: &Robots, path: &str) -> bool {
+		robots.is_allowed(
+			&Url::parse("https://example.com")
+				.unwrap()
+				.join(path)
+				.unwrap(),
+		)
+	}
+
+	#[test]
+	fn test_groups() {
+		let text = "\
+# comment
+User-agent: *
+Disallow: /private/
+Crawl-delay: 2
+
+User-agent: OtherBot
+User-agent: threaded_link_checker/0.1
+Disallow: /  # everything
+Allow: /public/
+Crawl-delay: 0.5
+
+user-agent: threaded_link_checker
+disallow: /public/secret
+";
+		let own = Robots::parse(text, "threaded_link_checker");
+		assert_eq!(own.crawl_delay, Some(Duration::from_millis(500)));
+		assert!(!allowed(&own, "/"));
+		assert!(!allowed(&own, "/private/a.html"));
+		assert!(allowed(&own, "/public/index.html"));
+		assert!(!allowed(&own, "/public/secret.html"));
+
+		let any = Robots::parse(text, "SomeBot");
+		assert_eq!(any.crawl_delay, Some(Duration::from_secs(2)));
+		assert!(allowed(&any, "/"));
+		assert!(allowed(&any, "/private"));
+		assert!(!allowed(&any, "/private/a.html"));
+
+		assert_eq!(
+			Robots::parse("", "threaded_link_checker"),
+			Robots::default()
+		);
+		assert!(allowed(&Robots::default(), "/anything"));
+	}
+
+	#[test]
+	fn test_crawl_delay() {
+		let delay = |value: &str| {
+			let text = format!("User-agent: *\nCrawl-delay: {value}\n");
+			Robots::parse(&text, "threaded_link_checker").crawl_delay
+		};
+		assert_eq!(delay("1.5"), Some(Duration::from_millis(1500)));
+		assert_eq!(delay("1e20"), Some(MAX_CRAWL_DELAY));
+		assert_eq!(delay("inf"), Some(MAX_CRAWL_DELAY));
+		assert_eq!(delay("3600"), Some(MAX_CRAWL_DELAY));
+		assert_eq!(delay("NaN"), None);
+		assert_eq!(delay("-1"), None);
+	}
+
+	#[test]
+	fn test_patterns() {
+		let robots = Robots::parse(
+			"\
+User-agent: *
+Disallow: /*.pdf$
+Disallow: /search?*q=
+Disallow: /tmp
+Allow: /tmp$
+Allow: /*.pdf?
+Disallow:
+",
+			"threaded_link_checker",
+		);
+		assert!(!allowed(&robots, "/docs/a.pdf"));
+		assert!(allowed(&robots, "/docs/a.pdf.html"));
+		assert!(allowed(&robots, "/docs/a.pdf?download=1"));
+		assert!(!allowed(&robots, "/search?lang=en&q=rust"));
+		assert!(allowed(&robots, "/search?lang=en"));
+		assert!(allowed(&robots, "/tmp"));
+		assert!(!allowed(&robots, "/tmp/"));
+		assert!(!allowed(&robots, "/tmpfile"));
+	}
+
+	#[test]
+	fn test_cache_loads_once() {
+		let cache = RobotsCache::default();
+		let mut loaded = Vec::new();
+		for url in [
+			"https://example.com/a",
+			"https://example.com/b",
+			"https://example.org/",
+		] {
+			cache.get(&Url::parse(url).unwrap(), |robots_url| {
+				loaded.push(robots_url.to_string());
+				Robots::default()
+			});
+		}
+		let mailto = Url::parse("mailto:someone@example.com").unwrap();
+		let robots = cache.get(&mailto, |_| unreachable!());
+		assert!(robots.is_allowed(&mailto));
+		assert_eq!(
+			loaded,
+			[
+				"https://example.com/robots.txt",
+				"https://example.org/robots.txt"
+			]
+		);
+	}
+}
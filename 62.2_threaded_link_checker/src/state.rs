@@ -0,0 +1,421 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use reqwest::{StatusCode, Url};
+use thiserror::Error;
+
+use crate::options::Options;
+use crate::{CrawlCommand, CrawlState, ErrorKind, Link, UrlError, UrlResult};
+
+/// The first line of a state file, naming its format.
+///
+/// Each line after it is a record, a kind followed by fields, separated by
+/// tabs:
+///
+/// - `domain`, a domain being crawled;
+/// - `visited`, a canonical URL queued;
+/// - `pending`, a canonical URL, depth, whether to extract links and
+///   whether only fragment targets are wanted (`1` or `0`), then the link
+///   fields, for a URL queued but not checked;
+/// - `link`, a canonical URL, then the link fields: URL, referrer (empty
+///   for start URLs), element and text;
+/// - `result`, a URL, status (empty without a response), milliseconds
+///   taken, error kind and message (empty unless broken);
+/// - `anchors`, a canonical URL, then the page's fragment targets.
+const HEADER: &str = "threaded_link_checker crawl state 1";
+
+#[derive(Error, Debug)]
+pub enum StateError {
+	#[error(transparent)]
+	Io(#[from] io::Error),
+	#[error("line {line}: {reason}")]
+	Invalid { line: usize, reason: String },
+}
+
+impl CrawlState {
+	/// Save what is needed to continue the crawl to `path`. The file is only
+	/// replaced once complete, so a crash while saving keeps the last one.
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		let temp_path = format!("{path}.tmp");
+		let mut out = BufWriter::new(File::create(&temp_path)?);
+		writeln!(out, "{HEADER}")?;
+		for domain in &self.domains {
+			write_record(&mut out, &["domain", domain])?;
+		}
+		for url in &self.visited_sites {
+			write_record(&mut out, &["visited", url])?;
+		}
+		for command in self.pending.values() {
+			let depth = command.depth.to_string();
+			let flag = |set: bool| if set { "1" } else { "0" };
+			let mut fields = vec![
+				"pending",
+				command.url.as_str(),
+				&depth,
+				flag(command.extract_links),
+				flag(command.anchors_only),
+			];
+			fields.extend(link_fields(&command.link));
+			write_record(&mut out, &fields)?;
+		}
+		for (url, links) in &self.links {
+			for link in links {
+				let mut fields = vec!["link", url.as_str()];
+				fields.extend(link_fields(link));
+				write_record(&mut out, &fields)?;
+			}
+		}
+		for result in &self.results {
+			let status = result
+				.status
+				.map(|status| status.as_str().to_string())
+				.unwrap_or_default();
+			let elapsed = result.elapsed.as_millis().to_string();
+			let (kind, message) = match &result.error {
+				Some(error) => (error.kind.as_str(), error.message.as_str()),
+				None => ("", ""),
+			};
+			let fields = [
+				"result",
+				result.url.as_str(),
+				&status,
+				&elapsed,
+				kind,
+				message,
+			];
+			write_record(&mut out, &fields)?;
+		}
+		for (url, anchors) in &self.anchors {
+			let mut fields = vec!["anchors", url.as_str()];
+			fields.extend(anchors.iter().map(String::as_str));
+			write_record(&mut out, &fields)?;
+		}
+		out.into_inner()
+			.map_err(io::IntoInnerError::into_error)?
+			.sync_all()?;
+		fs::rename(temp_path, path)
+	}
+
+	/// Load a state saved by `save`, to continue the crawl with `options`.
+	/// Only the saved domains are crawled, whatever the start URLs.
+	pub fn load(path: &str, options: &Options) -> Result<CrawlState, StateError> {
+		let text = fs::read_to_string(path)?;
+		let mut lines = text.lines().zip(1..);
+		if lines.next().map(|(line, _)| line) != Some(HEADER) {
+			return Err(StateError::Invalid {
+				line: 1,
+				reason: "not a crawl state file".to_string(),
+			});
+		}
+		let mut state = CrawlState {
+			domains: HashSet::new(),
+			..CrawlState::new(options)
+		};
+		for (line, number) in lines {
+			let fields: Vec<String> = line.split('\t').map(unescape).collect();
+			let mut fields = Fields {
+				line: number,
+				fields: fields.into_iter(),
+			};
+			match fields.next()?.as_str() {
+				"domain" => {
+					state.domains.insert(fields.next()?);
+				}
+				"visited" => {
+					state.visited_sites.insert(fields.next()?);
+				}
+				"pending" => {
+					let command = CrawlCommand {
+						url: fields.url()?,
+						depth: fields.parse()?,
+						extract_links: fields.next()? == "1",
+						anchors_only: fields.next()? == "1",
+						link: fields.link()?,
+					};
+					state.pending.insert(command.url.to_string(), command);
+				}
+				"link" => {
+					let url = fields.next()?;
+					let link = fields.link()?;
+					state.links.entry(url).or_default().push(link);
+				}
+				"result" => {
+					let url = fields.url()?;
+					let status = match fields.next()?.as_str() {
+						"" => None,
+						status => {
+							Some(StatusCode::from_str(status).map_err(|err| fields.invalid(err))?)
+						}
+					};
+					let elapsed = Duration::from_millis(fields.parse()?);
+					let error = match fields.next()?.as_str() {
+						"" => None,
+						kind => Some(UrlError {
+							kind: ErrorKind::parse(kind).ok_or_else(|| {
+								fields.invalid(format!("unknown error kind {kind:?}"))
+							})?,
+							message: fields.next()?,
+						}),
+					};
+					// reports look up the links to each URL checked
+					state.links.entry(url.to_string()).or_default();
+					state.results.push(UrlResult {
+						url,
+						status,
+						error,
+						elapsed,
+					});
+				}
+				"anchors" => {
+					let url = fields.next()?;
+					state.anchors.insert(url, fields.fields.collect());
+				}
+				kind => return Err(fields.invalid(format!("unknown record {kind:?}"))),
+			}
+		}
+		Ok(state)
+	}
+}
+
+fn link_fields(link: &Link) -> [&str; 4] {
+	[
+		link.url.as_str(),
+		link.referrer.as_ref().map_or("", Url::as_str),
+		&link.element,
+		&link.text,
+	]
+}
+
+fn write_record(out: &mut impl Write, fields: &[&str]) -> io::Result<()> {
+	let fields: Vec<String> = fields.iter().map(|field| escape(field)).collect();
+	writeln!(out, "{}", fields.join("\t"))
+}
+
+/// Escape the characters that separate fields and records.
+fn escape(field: &str) -> String {
+	let mut out = String::with_capacity(field.len());
+	for c in field.chars() {
+		match c {
+			'\\' => out.push_str("\\\\"),
+			'\t' => out.push_str("\\t"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+fn unescape(field: &str) -> String {
+	let mut out = String::with_capacity(field.len());
+	let mut chars = field.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			out.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('t') => out.push('\t'),
+			Some('n') => out.push('\n'),
+			Some('r') => out.push('\r'),
+			Some(c) => out.push(c),
+			None => out.push('\\'),
+		}
+	}
+	out
+}
+
+/// The fields of a record, taken in order.
+struct Fields {
+	line: usize,
+	fields: std::vec::IntoIter<String>,
+}
+
+impl Fields {
+	fn invalid(&self, reason: impl ToString) -> StateError {
+		StateError::Invalid {
+			line: self.line,
+			reason: reason.to_string(),
+		}
+	}
+
+	fn next(&mut self) -> Result<String, StateError> {
+		self.fields
+			.next()
+			.ok_or_else(|| self.invalid("missing field"))
+	}
+
+	fn parse<T: FromStr>(&mut self) -> Result<T, StateError> {
+		let field = self.next()?;
+		field
+			.parse()
+			.map_err(|_| self.invalid(format!("invalid number {field:?}")))
+	}
+
+	fn url(&mut self) -> Result<Url, StateError> {
+		let field = self.next()?;
+		Url::parse(&field).map_err(|err| self.invalid(format!("invalid URL {field:?}: {err}")))
+	}
+
+	fn link(&mut self) -> Result<Link, StateError> {
+		let url = self.url()?;
+		let referrer = match self.next()?.as_str() {
+			"" => None,
+			referrer => Some(Url::parse(referrer).map_err(|err| self.invalid(err))?),
+		};
+		Ok(Link {
+			url,
+			referrer,
+			element: self.next()?,
+			text: self.next()?,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::{Path, PathBuf};
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::fetch::FakeFetcher;
+	use crate::{crawl, crawl_from};
+
+	fn temp_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!(
+			"threaded_link_checker-{}-{name}",
+			std::process::id()
+		))
+	}
+
+	fn results(state: &CrawlState) -> Vec<String> {
+		let mut results: Vec<String> = state
+			.results
+			.iter()
+			.map(|result| {
+				let error = result
+					.error
+					.as_ref()
+					.map(|error| (error.kind, &error.message));
+				format!("{} {:?} {error:?}", result.url, result.status)
+			})
+			.collect();
+		results.sort();
+		results
+	}
+
+	#[test]
+	fn test_save_and_load() {
+		let start_url = Url::parse("https://example.com/index.html").unwrap();
+		let fixture_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/site");
+		let path = temp_path("saved.state");
+		let options = Options {
+			start_urls: vec![start_url.clone()],
+			robots: false,
+			state: Some(path.to_str().unwrap().to_string()),
+			..Options::default()
+		};
+		let fetcher = FakeFetcher::from_fixture(&start_url, &fixture_dir);
+		let crawl_state = crawl(&options, Arc::new(fetcher));
+		let mut loaded = CrawlState::load(options.state.as_ref().unwrap(), &options).unwrap();
+		fs::remove_file(&path).unwrap();
+		// the state is saved before fragments are checked
+		loaded.check_fragments();
+		assert_eq!(loaded.domains, crawl_state.domains);
+		assert_eq!(loaded.visited_sites, crawl_state.visited_sites);
+		assert_eq!(loaded.links, crawl_state.links);
+		assert_eq!(loaded.anchors, crawl_state.anchors);
+		assert_eq!(results(&loaded), results(&crawl_state));
+		assert!(loaded.pending.is_empty());
+	}
+
+	#[test]
+	fn test_resume() {
+		let path = temp_path("resume.state");
+		let state = "\
+threaded_link_checker crawl state 1
+domain\texample.com
+visited\thttps://example.com/
+visited\thttps://example.com/next
+visited\thttps://example.com/gone
+pending\thttps://example.com/next\t1\t1\t0\thttps://example.com/next#top\thttps://example.com/\ta[href]\tNext\\tpage
+link\thttps://example.com/\thttps://example.com/\t\t\t
+link\thttps://example.com/next\thttps://example.com/next#top\thttps://example.com/\ta[href]\tNext\\tpage
+link\thttps://example.com/gone\thttps://example.com/gone\thttps://example.com/\ta[href]\tGone
+result\thttps://example.com/\t200\t12\t\t
+result\thttps://example.com/gone\t404\t3\thttp_status\tbad http response: 404 Not Found
+anchors\thttps://example.com/\tmain
+";
+		fs::write(&path, state).unwrap();
+		// without start URLs, rather than the default one
+		let args = [
+			"--ignore-robots",
+			"--state",
+			path.to_str().unwrap(),
+			"--resume",
+		];
+		let options = Options::parse(args.map(String::from)).unwrap().unwrap();
+		let loaded = CrawlState::load(options.state.as_ref().unwrap(), &options).unwrap();
+		assert_eq!(loaded.domains, HashSet::from(["example.com".to_string()]));
+		let fetcher = Arc::new(
+			FakeFetcher::default()
+				.page("https://example.com/next", r#"<a href="/last">Last</a>"#)
+				.page("https://example.com/last", ""),
+		);
+		let crawl_state = crawl_from(&options, loaded, fetcher.clone());
+		fs::remove_file(&path).unwrap();
+		// only the pending URL and what it links to are fetched
+		assert_eq!(
+			fetcher.fetched_urls(),
+			["https://example.com/last", "https://example.com/next"]
+		);
+		assert_eq!(
+			results(&crawl_state),
+			[
+				"https://example.com/ Some(200) None",
+				"https://example.com/gone Some(404) Some((Status, \"bad http response: 404 Not Found\"))",
+				"https://example.com/last Some(200) None",
+				"https://example.com/next Some(200) None",
+			]
+		);
+		let texts: Vec<&str> = crawl_state.links["https://example.com/next"]
+			.iter()
+			.map(|link| link.text.as_str())
+			.collect();
+		assert_eq!(texts, ["Next\tpage"]);
+	}
+
+	#[test]
+	fn test_invalid() {
+		let path = temp_path("invalid.state");
+		let options = Options::default();
+		let load = |text: &str| {
+			fs::write(&path, text).unwrap();
+			CrawlState::load(path.to_str().unwrap(), &options).map(|_| ())
+		};
+		let error = |text: &str| load(text).unwrap_err().to_string();
+		assert_eq!(
+			error("visited\thttps://example.com/\n"),
+			"line 1: not a crawl state file"
+		);
+		assert_eq!(
+			error(&format!(
+				"{HEADER}\nvisited\thttps://example.com/\nresult\tnowhere\n"
+			)),
+			"line 3: invalid URL \"nowhere\": relative URL without a base"
+		);
+		assert_eq!(
+			error(&format!("{HEADER}\nresult\thttps://example.com/\t200\n")),
+			"line 2: missing field"
+		);
+		assert_eq!(
+			error(&format!("{HEADER}\nqueued\n")),
+			"line 2: unknown record \"queued\""
+		);
+		assert!(load(&format!("{HEADER}\n")).is_ok());
+		fs::remove_file(&path).unwrap();
+	}
+}